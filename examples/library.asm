@@ -0,0 +1,13 @@
+; routine: CUBE
+; DUP SQUARE *
+[0] CALL DUP             ; DUP
+[1] CALL SQUARE          ; SQUARE
+[2] MUL                  ; *
+[3] RETURN
+
+; routine: SQUARE
+; DUP *
+[0] CALL DUP             ; DUP
+[1] MUL                  ; *
+[2] RETURN
+
@@ -0,0 +1,59 @@
+//! Annotated listing for --output-asm.
+//!
+//! Unlike --disassemble, every instruction is shown next to the command it
+//! was compiled from. Each routine starts with a `; routine: NAME` line and
+//! its source, instructions are `[INDEX] INSTRUCTION` followed by a comment
+//! with the command when they are the first one compiled from it. Anything
+//! after a `;` is a comment.
+
+use std::fmt::Write;
+
+use crate::{compile_routine, CauchemarAST, CauchemarProgram, CauchemarVMInstruction};
+
+/// Where the comments start, past most instructions.
+const COMMENT_COLUMN: usize = 24;
+
+pub fn asm(program: &CauchemarProgram) -> String {
+    let mut routines: Vec<_> = program.routines.iter().collect();
+    routines.sort_by_key(|(name, _)| **name);
+
+    let mut out = String::new();
+    for (name, commands) in routines {
+        let commands: Vec<&CauchemarAST> =
+            commands.iter().filter(|command| !matches!(command, CauchemarAST::Comment { .. })).collect();
+        let source: Vec<String> = commands.iter().map(|command| single_line(command)).collect();
+        writeln!(out, "; routine: {}", name).unwrap();
+        writeln!(out, "; {}", source.join(" ")).unwrap();
+
+        // Compiled one command at a time so that what each one gave is known,
+        // jump targets still count from the start of the routine.
+        let mut instructions = Vec::new();
+        for (command, source) in commands.iter().zip(&source) {
+            let start = instructions.len();
+            compile_routine(&mut instructions, vec![(*command).clone()], &program.constants, &program.macros);
+            for (i, instruction) in instructions.iter().enumerate().skip(start) {
+                let comment = (i == start).then_some(source.as_str());
+                line(&mut out, i, instruction, comment);
+            }
+        }
+        line(&mut out, instructions.len(), &CauchemarVMInstruction::Return, None);
+        writeln!(out).unwrap();
+    }
+    out
+}
+
+fn line(out: &mut String, index: usize, instruction: &CauchemarVMInstruction, comment: Option<&str>) {
+    let instruction = match instruction {
+        CauchemarVMInstruction::Push(value) => format!("[{}] PUSH {}", index, value.repr()),
+        instruction => format!("[{}] {}", index, instruction),
+    };
+    match comment {
+        Some(comment) => writeln!(out, "{:<width$} ; {}", instruction, comment, width = COMMENT_COLUMN).unwrap(),
+        None => writeln!(out, "{}", instruction).unwrap(),
+    }
+}
+
+/// The command as written, comments inside of it can span several lines.
+fn single_line(command: &CauchemarAST) -> String {
+    command.to_string().lines().map(str::trim).collect::<Vec<_>>().join(" ")
+}
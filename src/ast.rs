@@ -0,0 +1,383 @@
+//! What the parser makes of a source file, before it is compiled.
+
+use std::{
+    borrow::Cow,
+    collections::{BTreeMap, HashMap},
+    fmt,
+};
+
+use num_bigint::BigInt;
+use serde::Serialize;
+
+#[derive(Debug, Clone)]
+pub(crate) enum CauchemarAST<'a> {
+    /// The literal as written comes second, such as `#\A` for 65.
+    Number(i32, &'a str),
+    /// The literal as written comes second, as for numbers.
+    BigInt(BigInt, &'a str),
+    Bool(bool),
+    /// Escape sequences are already decoded, the literal as written comes
+    /// second as for numbers.
+    String(Cow<'a, str>, &'a str),
+    Identifier(&'a str),
+    /// Only found first in a routine body.
+    Params(Vec<&'a str>),
+    /// A parameter of the routine it is in.
+    Param(&'a str),
+    /// Only found first in a routine body, after any parameters.
+    Returns(usize),
+    ReturnWith,
+    If(Vec<CauchemarAST<'a>>, Vec<CauchemarAST<'a>>),
+    /// The value, each case's value and body, then the default body.
+    Switch(Vec<CauchemarAST<'a>>, Vec<(Vec<CauchemarAST<'a>>, Vec<CauchemarAST<'a>>)>, Vec<CauchemarAST<'a>>),
+    While(Vec<CauchemarAST<'a>>),
+    Thunk(Vec<CauchemarAST<'a>>),
+    Add,
+    Sub,
+    Mul,
+    Div,
+    /// Ignored by the compiler, `own_line` is whether nothing but whitespace
+    /// precedes it on its line.
+    Comment { text: &'a str, own_line: bool },
+}
+
+impl fmt::Display for CauchemarAST<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CauchemarAST::Number(n, _) => write!(f, "{}", n),
+            CauchemarAST::BigInt(n, _) => write!(f, "{}BI", n),
+            CauchemarAST::Bool(true) => write!(f, "TRUE"),
+            CauchemarAST::Bool(false) => write!(f, "FALSE"),
+            CauchemarAST::String(s, _) => write!(f, "\"{}\"", escape_string(s)),
+            CauchemarAST::Identifier(s) => write!(f, "{}", s),
+            CauchemarAST::Params(names) => write!(f, "PARAMS ({})", names.join(" ")),
+            CauchemarAST::Param(name) => write!(f, "{}", name),
+            CauchemarAST::Returns(count) => write!(f, "RETURNS {}", count),
+            CauchemarAST::ReturnWith => write!(f, "RETURN-WITH"),
+            CauchemarAST::If(then, otherwise) => {
+                write!(f, "IF ")?;
+                for c in then {
+                    write!(f, "{} ", c)?;
+                }
+                if !otherwise.is_empty() {
+                    write!(f, "ELSE ")?;
+                    for o in otherwise {
+                        write!(f, "{} ", o)?;
+                    }
+                }
+                write!(f, "THEN")
+            },
+            CauchemarAST::Switch(value, cases, default) => {
+                write!(f, "SWITCH ")?;
+                for v in value {
+                    write!(f, "{} ", v)?;
+                }
+                for (case_value, body) in cases {
+                    write!(f, "CASE ")?;
+                    for v in case_value {
+                        write!(f, "{} ", v)?;
+                    }
+                    write!(f, "DO ")?;
+                    for b in body {
+                        write!(f, "{} ", b)?;
+                    }
+                }
+                if !default.is_empty() {
+                    write!(f, "DEFAULT ")?;
+                    for d in default {
+                        write!(f, "{} ", d)?;
+                    }
+                }
+                write!(f, "END")
+            },
+            CauchemarAST::While(body) => {
+                write!(f, "DO ")?;
+                for b in body {
+                    write!(f, "{} ", b)?;
+                }
+                write!(f, "WHILE")
+            },
+            CauchemarAST::Thunk(body) => {
+                write!(f, "{{ ")?;
+                for b in body {
+                    write!(f, "{} ", b)?;
+                }
+                write!(f, "}}")
+            },
+            CauchemarAST::Add => write!(f, "+"),
+            CauchemarAST::Sub => write!(f, "-"),
+            CauchemarAST::Mul => write!(f, "*"),
+            CauchemarAST::Div => write!(f, "/"),
+            CauchemarAST::Comment { text, .. } => write!(f, "{}", text),
+        }
+    }
+}
+
+/// Commands as objects tagged by "type", the field names are relied upon by
+/// tools reading `--dump-ast --format json`, see examples/library.ast.json.
+impl Serialize for CauchemarAST<'_> {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        use serde::ser::SerializeMap;
+
+        let mut map = serializer.serialize_map(None)?;
+        match self {
+            CauchemarAST::Number(n, _) => {
+                map.serialize_entry("type", "number")?;
+                map.serialize_entry("value", n)?;
+            }
+            CauchemarAST::BigInt(n, _) => {
+                // As a string, JSON readers tend to lose precision otherwise.
+                map.serialize_entry("type", "bigint")?;
+                map.serialize_entry("value", &n.to_string())?;
+            }
+            CauchemarAST::Bool(b) => {
+                map.serialize_entry("type", "bool")?;
+                map.serialize_entry("value", b)?;
+            }
+            CauchemarAST::String(s, _) => {
+                map.serialize_entry("type", "string")?;
+                map.serialize_entry("value", s)?;
+            }
+            CauchemarAST::Identifier(name) => {
+                map.serialize_entry("type", "identifier")?;
+                map.serialize_entry("name", name)?;
+            }
+            CauchemarAST::Params(names) => {
+                map.serialize_entry("type", "params")?;
+                map.serialize_entry("names", names)?;
+            }
+            CauchemarAST::Param(name) => {
+                map.serialize_entry("type", "param")?;
+                map.serialize_entry("name", name)?;
+            }
+            CauchemarAST::Returns(count) => {
+                map.serialize_entry("type", "returns")?;
+                map.serialize_entry("count", count)?;
+            }
+            CauchemarAST::ReturnWith => map.serialize_entry("type", "return_with")?,
+            CauchemarAST::If(then, otherwise) => {
+                map.serialize_entry("type", "if")?;
+                map.serialize_entry("then", then)?;
+                map.serialize_entry("else", otherwise)?;
+            }
+            CauchemarAST::Switch(value, cases, default) => {
+                let cases: Vec<BTreeMap<&str, &Vec<CauchemarAST>>> = cases
+                    .iter()
+                    .map(|(case_value, body)| BTreeMap::from([("value", case_value), ("body", body)]))
+                    .collect();
+                map.serialize_entry("type", "switch")?;
+                map.serialize_entry("value", value)?;
+                map.serialize_entry("cases", &cases)?;
+                map.serialize_entry("default", default)?;
+            }
+            CauchemarAST::While(body) => {
+                map.serialize_entry("type", "while")?;
+                map.serialize_entry("body", body)?;
+            }
+            CauchemarAST::Thunk(body) => {
+                map.serialize_entry("type", "thunk")?;
+                map.serialize_entry("body", body)?;
+            }
+            CauchemarAST::Add => map.serialize_entry("type", "add")?,
+            CauchemarAST::Sub => map.serialize_entry("type", "sub")?,
+            CauchemarAST::Mul => map.serialize_entry("type", "mul")?,
+            CauchemarAST::Div => map.serialize_entry("type", "div")?,
+            CauchemarAST::Comment { text, .. } => {
+                map.serialize_entry("type", "comment")?;
+                map.serialize_entry("text", text)?;
+            }
+        }
+        map.end()
+    }
+}
+
+/// Top-level items of a file in source order.
+#[derive(Debug, Clone)]
+pub(crate) enum CauchemarItem<'a> {
+    Routine(&'a str),
+    Define(&'a str),
+    Macro(&'a str),
+    Comment { text: &'a str, own_line: bool },
+}
+
+/// The inverse of `unescape_string`, used to print literals back as source.
+pub(crate) fn escape_string(s: &str) -> String {
+    let mut escaped = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '"' => escaped.push_str("\\\""),
+            '\\' => escaped.push_str("\\\\"),
+            '\n' => escaped.push_str("\\n"),
+            '\t' => escaped.push_str("\\t"),
+            '\r' => escaped.push_str("\\r"),
+            c if c.is_control() => escaped.push_str(&format!("\\u{:04X}", c as u32)),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/// Where a command is written, `start..end` being its bytes in the source.
+/// Line 0 is for instructions that weren't written anywhere.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub(crate) struct CauchemarSpan {
+    pub(crate) line: usize,
+    pub(crate) start: usize,
+    pub(crate) end: usize,
+}
+
+#[derive(Debug, Clone)]
+pub(crate) struct CauchemarProgram<'a> {
+    pub(crate) routines: HashMap<&'a str, Vec<CauchemarAST<'a>>>,
+    /// Values given by DEFINE, inlined wherever their name is used.
+    pub(crate) constants: HashMap<&'a str, CauchemarAST<'a>>,
+    /// Bodies given by MACRO, spliced in wherever their name is used.
+    pub(crate) macros: HashMap<&'a str, Vec<CauchemarAST<'a>>>,
+    /// Everything needed on top of `routines` to reprint the file.
+    pub(crate) layout: Vec<CauchemarItem<'a>>,
+    /// Span of every command of each routine in the order they are compiled,
+    /// those inside of thunks aside. Used by --coverage, `cauchemar dap` and
+    /// to point at the command a runtime error happened in.
+    pub(crate) spans: HashMap<&'a str, Vec<CauchemarSpan>>,
+}
+
+/// Routines, constants and macros keyed by name in sorted order, the layout
+/// only matters to the formatter and is left out.
+impl Serialize for CauchemarProgram<'_> {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        use serde::ser::SerializeStruct;
+
+        let mut program = serializer.serialize_struct("CauchemarProgram", 3)?;
+        program.serialize_field("routines", &self.routines.iter().collect::<BTreeMap<_, _>>())?;
+        program.serialize_field("constants", &self.constants.iter().collect::<BTreeMap<_, _>>())?;
+        program.serialize_field("macros", &self.macros.iter().collect::<BTreeMap<_, _>>())?;
+        program.end()
+    }
+}
+
+/// A command as an S-expression, compound commands being lists headed by
+/// their lowercase keyword with a list for each body, such as
+/// `(if (PRINT) (DROP))`.
+fn ast_to_sexp(ast: &CauchemarAST) -> String {
+    match ast {
+        CauchemarAST::Params(names) => format!("(params {})", names.join(" ")),
+        CauchemarAST::Param(name) => format!("(param {})", name),
+        CauchemarAST::Returns(count) => format!("(returns {})", count),
+        CauchemarAST::ReturnWith => "(return-with)".to_string(),
+        CauchemarAST::If(then, otherwise) => format!("(if {} {})", sexp_list(then), sexp_list(otherwise)),
+        CauchemarAST::Switch(value, cases, default) => {
+            let mut sexp = format!("(switch {}", sexp_list(value));
+            for (case_value, body) in cases {
+                sexp.push_str(&format!(" (case {} {})", sexp_list(case_value), sexp_list(body)));
+            }
+            sexp.push_str(&format!(" (default {}))", sexp_list(default)));
+            sexp
+        }
+        CauchemarAST::While(body) => format!("(while {})", sexp_list(body)),
+        CauchemarAST::Thunk(body) => format!("(thunk {})", sexp_list(body)),
+        CauchemarAST::Comment { text, .. } => format!("(comment \"{}\")", escape_string(text)),
+        // Literals, names and arithmetic read the same as in the source.
+        ast => ast.to_string(),
+    }
+}
+
+fn sexp_list(commands: &[CauchemarAST]) -> String {
+    let commands: Vec<String> = commands.iter().map(ast_to_sexp).collect();
+    format!("({})", commands.join(" "))
+}
+
+impl CauchemarProgram<'_> {
+    /// The program as printed by --print-ast, one `(define NAME VALUE)`,
+    /// `(macro NAME (BODY))` or `(routine NAME (BODY))` per line, each kind
+    /// sorted by name.
+    pub(crate) fn sexp(&self) -> String {
+        let mut out = String::new();
+
+        let mut constants: Vec<_> = self.constants.iter().collect();
+        constants.sort_by_key(|(name, _)| *name);
+        for (name, value) in constants {
+            out.push_str(&format!("(define {} {})\n", name, ast_to_sexp(value)));
+        }
+
+        let mut macros: Vec<_> = self.macros.iter().collect();
+        macros.sort_by_key(|(name, _)| *name);
+        for (name, body) in macros {
+            out.push_str(&format!("(macro {} {})\n", name, sexp_list(body)));
+        }
+
+        let mut routines: Vec<_> = self.routines.iter().collect();
+        routines.sort_by_key(|(name, _)| *name);
+        for (name, body) in routines {
+            out.push_str(&format!("(routine {} {})\n", name, sexp_list(body)));
+        }
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    /// Tools read --dump-ast --format json, renaming any of these breaks them.
+    #[test]
+    fn json_schema_is_stable() {
+        let body = vec![
+            CauchemarAST::Params(vec!["A", "B"]),
+            CauchemarAST::Returns(1),
+            CauchemarAST::Number(1, "1"),
+            CauchemarAST::BigInt(BigInt::from(2), "2BI"),
+            CauchemarAST::Bool(true),
+            CauchemarAST::String("s".into(), "\"s\""),
+            CauchemarAST::Identifier("DUP"),
+            CauchemarAST::Param("A"),
+            CauchemarAST::ReturnWith,
+            CauchemarAST::If(vec![CauchemarAST::Add], vec![CauchemarAST::Sub]),
+            CauchemarAST::Switch(
+                vec![CauchemarAST::Param("B")],
+                vec![(vec![CauchemarAST::Number(0, "0")], vec![CauchemarAST::Mul])],
+                vec![CauchemarAST::Div],
+            ),
+            CauchemarAST::While(vec![CauchemarAST::Bool(false)]),
+            CauchemarAST::Thunk(vec![CauchemarAST::Identifier("DROP")]),
+            CauchemarAST::Comment { text: "note", own_line: true },
+        ];
+        let program = CauchemarProgram {
+            routines: HashMap::from([("PROGRAM", body)]),
+            constants: HashMap::from([("ANSWER", CauchemarAST::Number(42, "42"))]),
+            macros: HashMap::from([("TWICE", vec![CauchemarAST::Identifier("DUP"), CauchemarAST::Add])]),
+            layout: Vec::new(),
+            spans: HashMap::new(),
+        };
+
+        let expected = json!({
+            "routines": {
+                "PROGRAM": [
+                    {"type": "params", "names": ["A", "B"]},
+                    {"type": "returns", "count": 1},
+                    {"type": "number", "value": 1},
+                    {"type": "bigint", "value": "2"},
+                    {"type": "bool", "value": true},
+                    {"type": "string", "value": "s"},
+                    {"type": "identifier", "name": "DUP"},
+                    {"type": "param", "name": "A"},
+                    {"type": "return_with"},
+                    {"type": "if", "then": [{"type": "add"}], "else": [{"type": "sub"}]},
+                    {
+                        "type": "switch",
+                        "value": [{"type": "param", "name": "B"}],
+                        "cases": [{"value": [{"type": "number", "value": 0}], "body": [{"type": "mul"}]}],
+                        "default": [{"type": "div"}]
+                    },
+                    {"type": "while", "body": [{"type": "bool", "value": false}]},
+                    {"type": "thunk", "body": [{"type": "identifier", "name": "DROP"}]},
+                    {"type": "comment", "text": "note"}
+                ]
+            },
+            "constants": {"ANSWER": {"type": "number", "value": 42}},
+            "macros": {"TWICE": [{"type": "identifier", "name": "DUP"}, {"type": "add"}]}
+        });
+        assert_eq!(serde_json::to_value(&program).unwrap(), expected);
+    }
+}
@@ -0,0 +1,5 @@
+//! Same as `cauchemar lsp`, for editors expecting a server of its own.
+
+fn main() {
+    cauchemar::lsp::serve();
+}
@@ -0,0 +1,187 @@
+//! C backend for --output-c.
+//!
+//! Every user routine becomes a function switching on the index of its next
+//! instruction, so that jumps are a matter of setting it. The stack and the
+//! natives that can be written in C are in `cauchemar_runtime.h`, which is
+//! written next to the C file. Numbers, booleans and strings are supported,
+//! anything else fails when reached.
+
+use std::{collections::HashMap, fmt::Write, sync::Arc};
+
+use crate::{CauchemarVMInstruction, CauchemarVMRoutine, CauchemarVMValue};
+
+pub const RUNTIME_NAME: &str = "cauchemar_runtime.h";
+pub const RUNTIME: &str = include_str!("cauchemar_runtime.h");
+
+/// Compile the user routines to C with a `main` calling `entry`.
+pub fn c(routines: &HashMap<Arc<str>, CauchemarVMRoutine>, entry: &str) -> String {
+    let mut user: Vec<_> = routines
+        .iter()
+        .filter_map(|(name, routine)| match routine {
+            CauchemarVMRoutine::User(instructions) => Some((name.clone(), instructions)),
+            CauchemarVMRoutine::Native(_) | CauchemarVMRoutine::Host(_) => None,
+        })
+        .collect();
+    user.sort_by(|(a, _), (b, _)| a.cmp(b));
+    // Routine names aren't valid C identifiers, they are numbered instead.
+    let functions: HashMap<Arc<str>, String> =
+        user.iter().enumerate().map(|(i, (name, _))| (name.clone(), format!("cm_routine_{}", i))).collect();
+
+    let mut out = String::new();
+    writeln!(out, "#include \"{}\"", RUNTIME_NAME).unwrap();
+    writeln!(out).unwrap();
+    for (name, _) in &user {
+        writeln!(out, "void {}(void); /* {} */", functions[name], comment(name)).unwrap();
+    }
+
+    for (name, instructions) in &user {
+        writeln!(out).unwrap();
+        routine(&mut out, name, instructions, &functions);
+    }
+
+    writeln!(out).unwrap();
+    writeln!(out, "int main(void) {{").unwrap();
+    if let Some(function) = functions.get(entry) {
+        writeln!(out, "    {}();", function).unwrap();
+    }
+    writeln!(out, "    cm_finish();").unwrap();
+    writeln!(out, "    return 0;").unwrap();
+    writeln!(out, "}}").unwrap();
+    out
+}
+
+fn routine(
+    out: &mut String,
+    name: &str,
+    instructions: &[CauchemarVMInstruction],
+    functions: &HashMap<Arc<str>, String>,
+) {
+    let mut locals: Vec<&str> = Vec::new();
+    for instruction in instructions {
+        if let CauchemarVMInstruction::Store(name) | CauchemarVMInstruction::Load(name) = instruction {
+            if !locals.contains(&&**name) {
+                locals.push(name);
+            }
+        }
+    }
+    let local = |name: &str| format!("local_{}", locals.iter().position(|local| *local == name).unwrap());
+
+    writeln!(out, "/* {} */", comment(name)).unwrap();
+    writeln!(out, "void {}(void) {{", functions[name]).unwrap();
+    for (i, name) in locals.iter().enumerate() {
+        writeln!(out, "    cm_value local_{} = cm_number(0); /* {} */", i, comment(name)).unwrap();
+        // PARAMS may be stored and never read.
+        writeln!(out, "    (void)local_{};", i).unwrap();
+    }
+    writeln!(out, "    int pc = 0;").unwrap();
+    writeln!(out, "    for (;;) {{").unwrap();
+    writeln!(out, "        switch (pc) {{").unwrap();
+
+    for (i, instruction) in instructions.iter().enumerate() {
+        writeln!(out, "        case {}:", i).unwrap();
+        let line = match instruction {
+            CauchemarVMInstruction::Push(value) => match value {
+                CauchemarVMValue::Number(n) => format!("PUSH(cm_number({}));", n),
+                CauchemarVMValue::Bool(b) => format!("PUSH(cm_bool({}));", *b as i32),
+                CauchemarVMValue::String(s) => format!("PUSH(cm_string({}));", literal(s)),
+                value => unsupported(&value.repr()),
+            },
+            CauchemarVMInstruction::Call(name) => match functions.get(name) {
+                Some(function) => format!("{}();", function),
+                None => native(name),
+            },
+            CauchemarVMInstruction::Store(name) => format!("{} = POP();", local(name)),
+            CauchemarVMInstruction::Load(name) => format!("PUSH({});", local(name)),
+            CauchemarVMInstruction::Jump(target) => format!("pc = {}; continue;", target),
+            CauchemarVMInstruction::JumpIfFalse(target) => {
+                format!("if (!cm_pop_bool()) {{ pc = {}; continue; }}", target)
+            }
+            CauchemarVMInstruction::Add => "cm_arithmetic('+');".to_string(),
+            CauchemarVMInstruction::Sub => "cm_arithmetic('-');".to_string(),
+            CauchemarVMInstruction::Mul => "cm_arithmetic('*');".to_string(),
+            CauchemarVMInstruction::Div => "cm_arithmetic('/');".to_string(),
+            CauchemarVMInstruction::Return => "return;".to_string(),
+            // Which values to keep depends on how many RETURNS said, which
+            // isn't known here.
+            CauchemarVMInstruction::ReturnWith => unsupported("RETURN-WITH"),
+            CauchemarVMInstruction::Returns(_) | CauchemarVMInstruction::Nop => format!("; /* {} */", instruction),
+        };
+        writeln!(out, "            {}", line).unwrap();
+    }
+
+    writeln!(out, "        }}").unwrap();
+    writeln!(out, "        return;").unwrap();
+    writeln!(out, "    }}").unwrap();
+    writeln!(out, "}}").unwrap();
+}
+
+/// A statement for a call to a native, failing for those not written in C.
+fn native(name: &str) -> String {
+    let statement = match name {
+        "PRINT" => "cm_print(stdout, POP()); putchar('\\n');",
+        "EPRINT" => "cm_print(stderr, POP()); fputc('\\n', stderr);",
+        "DROP" => "(void)POP();",
+        "DUP" => "{ cm_value a = POP(); PUSH(a); PUSH(a); }",
+        "SWAP" => "{ cm_value b = POP(); cm_value a = POP(); PUSH(b); PUSH(a); }",
+        "OVER" => "{ cm_value b = POP(); cm_value a = POP(); PUSH(a); PUSH(b); PUSH(a); }",
+        "ROT" => "{ cm_value c = POP(); cm_value b = POP(); cm_value a = POP(); PUSH(b); PUSH(c); PUSH(a); }",
+        "ROT-" => "{ cm_value c = POP(); cm_value b = POP(); cm_value a = POP(); PUSH(c); PUSH(a); PUSH(b); }",
+        "OVER2" => "{ cm_value c = POP(); cm_value b = POP(); cm_value a = POP(); PUSH(a); PUSH(b); PUSH(c); PUSH(a); }",
+        "UNDER" => "{ cm_value c = POP(); cm_value b = POP(); cm_value a = POP(); PUSH(c); PUSH(a); PUSH(b); PUSH(c); }",
+        "TUCK2" => {
+            "{ cm_value d = POP(); cm_value c = POP(); cm_value b = POP(); cm_value a = POP(); \
+             PUSH(c); PUSH(d); PUSH(a); PUSH(b); PUSH(c); PUSH(d); }"
+        }
+        "2DUP" => "{ cm_value b = POP(); cm_value a = POP(); PUSH(a); PUSH(b); PUSH(a); PUSH(b); }",
+        "2DROP" => "(void)POP(); (void)POP();",
+        "2SWAP" => {
+            "{ cm_value d = POP(); cm_value c = POP(); cm_value b = POP(); cm_value a = POP(); \
+             PUSH(c); PUSH(d); PUSH(a); PUSH(b); }"
+        }
+        "2OVER" => {
+            "{ cm_value d = POP(); cm_value c = POP(); cm_value b = POP(); cm_value a = POP(); \
+             PUSH(a); PUSH(b); PUSH(c); PUSH(d); PUSH(a); PUSH(b); }"
+        }
+        "NOT" => "PUSH(cm_bool(!cm_pop_bool()));",
+        "AND" => "{ int b = cm_pop_bool(); int a = cm_pop_bool(); PUSH(cm_bool(a && b)); }",
+        "OR" => "{ int b = cm_pop_bool(); int a = cm_pop_bool(); PUSH(cm_bool(a || b)); }",
+        "EQUALS" => "{ cm_value b = POP(); cm_value a = POP(); PUSH(cm_bool(cm_equals(a, b))); }",
+        "NOT-EQUALS" => "{ cm_value b = POP(); cm_value a = POP(); PUSH(cm_bool(!cm_equals(a, b))); }",
+        "GREATER-THAN" => "PUSH(cm_bool(cm_compare() > 0));",
+        "GREATER-EQUAL" => "PUSH(cm_bool(cm_compare() >= 0));",
+        "LESS-THAN" => "PUSH(cm_bool(cm_compare() < 0));",
+        "LESS-EQUAL" => "PUSH(cm_bool(cm_compare() <= 0));",
+        "EXIT" => "exit(cm_pop_number());",
+        _ => return unsupported(name),
+    };
+    statement.to_string()
+}
+
+fn unsupported(what: &str) -> String {
+    format!("cm_fail({});", literal(&format!("{} is not supported by the C backend", what)))
+}
+
+/// A C string literal, bytes outside of printable ASCII are escaped in
+/// octal so that nothing after them can be taken as part of the escape.
+fn literal(s: &str) -> String {
+    let mut literal = String::from("\"");
+    for byte in s.bytes() {
+        match byte {
+            b'"' => literal.push_str("\\\""),
+            b'\\' => literal.push_str("\\\\"),
+            b'\n' => literal.push_str("\\n"),
+            b'\t' => literal.push_str("\\t"),
+            // Keeps `??` from being read as the start of a trigraph.
+            b'?' => literal.push_str("\\?"),
+            b' '..=b'~' => literal.push(byte as char),
+            _ => write!(literal, "\\{:03o}", byte).unwrap(),
+        }
+    }
+    literal.push('"');
+    literal
+}
+
+/// A routine name inside of a C comment.
+fn comment(name: &str) -> String {
+    name.replace("*/", "*\\/")
+}
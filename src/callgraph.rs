@@ -0,0 +1,85 @@
+//! Graphviz call graph for --dump-callgraph.
+//!
+//! Nodes are the user routines and the natives they call, natives being
+//! boxes. Edges are `Call` instructions, those of thunks counting for the
+//! routine they are written in, labeled with how many there are. Routines
+//! the entry can't reach are dashed. Everything is sorted by name so that
+//! the output can be diffed.
+
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap},
+    fmt::Write,
+    sync::Arc,
+};
+
+use crate::{CauchemarVMInstruction, CauchemarVMRoutine, CauchemarVMValue};
+
+pub fn dot(routines: &HashMap<Arc<str>, CauchemarVMRoutine>, entry: &str) -> String {
+    let mut calls: BTreeMap<&str, BTreeMap<&str, usize>> = BTreeMap::new();
+    for (name, routine) in routines {
+        if let CauchemarVMRoutine::User(instructions) = routine {
+            count_calls(instructions, calls.entry(name).or_default());
+        }
+    }
+    let natives: BTreeSet<&str> = calls
+        .values()
+        .flat_map(|callees| callees.keys().copied())
+        .filter(|callee| !calls.contains_key(callee))
+        .collect();
+
+    let reachable = reachable(&calls, entry);
+    let mut out = String::from("digraph calls {\n");
+    let nodes = calls.keys().map(|name| (*name, false)).chain(natives.iter().map(|name| (*name, true)));
+    let mut nodes: Vec<_> = nodes.collect();
+    nodes.sort();
+    for (name, native) in nodes {
+        let mut attributes = Vec::new();
+        if native {
+            attributes.push("shape=box");
+        }
+        if !reachable.contains(name) {
+            attributes.push("style=dashed");
+        }
+        match attributes.is_empty() {
+            true => writeln!(out, "    {};", quote(name)).unwrap(),
+            false => writeln!(out, "    {} [{}];", quote(name), attributes.join(", ")).unwrap(),
+        }
+    }
+    for (caller, callees) in &calls {
+        for (callee, count) in callees {
+            writeln!(out, "    {} -> {} [label={}];", quote(caller), quote(callee), count).unwrap();
+        }
+    }
+    out.push_str("}\n");
+    out
+}
+
+fn count_calls<'a>(instructions: &'a [CauchemarVMInstruction], callees: &mut BTreeMap<&'a str, usize>) {
+    for instruction in instructions {
+        match instruction {
+            CauchemarVMInstruction::Call(name) => *callees.entry(name).or_default() += 1,
+            CauchemarVMInstruction::Push(CauchemarVMValue::Thunk(body)) => count_calls(body, callees),
+            _ => {}
+        }
+    }
+}
+
+/// Every routine `entry` calls, directly or not, and itself.
+fn reachable<'a>(calls: &BTreeMap<&'a str, BTreeMap<&'a str, usize>>, entry: &'a str) -> BTreeSet<&'a str> {
+    let mut reachable = BTreeSet::new();
+    if !calls.contains_key(entry) {
+        return reachable;
+    }
+    let mut pending = vec![entry];
+    while let Some(name) = pending.pop() {
+        if reachable.insert(name) {
+            pending.extend(calls.get(name).into_iter().flat_map(|callees| callees.keys().copied()));
+        }
+    }
+    reachable
+}
+
+/// Routine names need quoting for the dashes, they can't hold quotes.
+fn quote(name: &str) -> String {
+    format!("\"{}\"", name)
+}
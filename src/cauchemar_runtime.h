@@ -0,0 +1,164 @@
+/* Stack machine used by the C written by `cauchemar --output-c`, copied
+   next to it. Everything is static inline so that only what the program
+   uses ends up in it. */
+
+#ifndef CAUCHEMAR_RUNTIME_H
+#define CAUCHEMAR_RUNTIME_H
+
+#include <inttypes.h>
+#include <stdio.h>
+#include <stdlib.h>
+#include <string.h>
+
+#ifndef CM_STACK_SIZE
+#define CM_STACK_SIZE 1000000
+#endif
+
+/* Same as the interpreter's exit code for runtime errors. */
+#define CM_RUNTIME_ERROR 4
+
+typedef enum { CM_NUMBER, CM_BOOL, CM_STRING } cm_tag;
+
+typedef struct {
+    cm_tag tag;
+    union {
+        int32_t number;
+        int boolean;
+        const char *string;
+    } as;
+} cm_value;
+
+static cm_value cm_stack[CM_STACK_SIZE];
+static size_t cm_sp;
+
+static inline void cm_fail(const char *message) {
+    fprintf(stderr, "%s\n", message);
+    exit(CM_RUNTIME_ERROR);
+}
+
+#define PUSH(value) \
+    (cm_sp < CM_STACK_SIZE ? (void)(cm_stack[cm_sp++] = (value)) : cm_fail("Stack overflow"))
+#define POP() \
+    (cm_sp > 0 ? cm_stack[--cm_sp] : (cm_fail("Stack underflow"), cm_stack[0]))
+
+static inline cm_value cm_number(int32_t number) {
+    cm_value value;
+    value.tag = CM_NUMBER;
+    value.as.number = number;
+    return value;
+}
+
+static inline cm_value cm_bool(int boolean) {
+    cm_value value;
+    value.tag = CM_BOOL;
+    value.as.boolean = boolean != 0;
+    return value;
+}
+
+static inline cm_value cm_string(const char *string) {
+    cm_value value;
+    value.tag = CM_STRING;
+    value.as.string = string;
+    return value;
+}
+
+static inline int32_t cm_pop_number(void) {
+    cm_value value = POP();
+    if (value.tag != CM_NUMBER) {
+        cm_fail("Invalid type: expected number");
+    }
+    return value.as.number;
+}
+
+static inline int cm_pop_bool(void) {
+    cm_value value = POP();
+    if (value.tag != CM_BOOL) {
+        cm_fail("Invalid type: expected boolean");
+    }
+    return value.as.boolean;
+}
+
+/* `op` is one of + - * /, checked like the interpreter does. */
+static inline void cm_arithmetic(char op) {
+    int64_t b = cm_pop_number();
+    int64_t a = cm_pop_number();
+    int64_t result = 0;
+    switch (op) {
+    case '+': result = a + b; break;
+    case '-': result = a - b; break;
+    case '*': result = a * b; break;
+    case '/':
+        if (b == 0) {
+            cm_fail("Division by zero");
+        }
+        result = a / b;
+        break;
+    }
+    if (result < INT32_MIN || result > INT32_MAX) {
+        cm_fail("Integer overflow");
+    }
+    PUSH(cm_number((int32_t)result));
+}
+
+static inline int cm_equals(cm_value a, cm_value b) {
+    if (a.tag != b.tag) {
+        return 0;
+    }
+    switch (a.tag) {
+    case CM_NUMBER: return a.as.number == b.as.number;
+    case CM_BOOL: return a.as.boolean == b.as.boolean;
+    case CM_STRING: return strcmp(a.as.string, b.as.string) == 0;
+    }
+    return 0;
+}
+
+/* Pops two numbers, giving -1, 0 or 1 as the one below is less than, equal
+   to or greater than the one that was on top. */
+static inline int cm_compare(void) {
+    int32_t b = cm_pop_number();
+    int32_t a = cm_pop_number();
+    return (a > b) - (a < b);
+}
+
+static inline void cm_print(FILE *out, cm_value value) {
+    switch (value.tag) {
+    case CM_NUMBER: fprintf(out, "%" PRId32, value.as.number); break;
+    case CM_BOOL: fputs(value.as.boolean ? "TRUE" : "FALSE", out); break;
+    case CM_STRING: fputs(value.as.string, out); break;
+    }
+}
+
+static inline void cm_repr(FILE *out, cm_value value) {
+    const char *c;
+    if (value.tag != CM_STRING) {
+        cm_print(out, value);
+        return;
+    }
+    fputc('"', out);
+    for (c = value.as.string; *c; c++) {
+        switch (*c) {
+        case '"': fputs("\\\"", out); break;
+        case '\\': fputs("\\\\", out); break;
+        case '\n': fputs("\\n", out); break;
+        case '\t': fputs("\\t", out); break;
+        default: fputc(*c, out); break;
+        }
+    }
+    fputc('"', out);
+}
+
+/* Show what the program left on the stack, like the interpreter does. */
+static inline void cm_finish(void) {
+    size_t i;
+    if (cm_sp == 0) {
+        return;
+    }
+    fputs("-- stack:", stderr);
+    for (i = 0; i < cm_sp; i++) {
+        fputc(' ', stderr);
+        cm_repr(stderr, cm_stack[i]);
+    }
+    fputc('\n', stderr);
+}
+
+#endif
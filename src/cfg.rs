@@ -0,0 +1,193 @@
+//! Control-flow graph of a compiled routine, see --dump-cfg.
+//!
+//! Instructions are split into basic blocks at jump targets and after every
+//! jump or return, so that only the last instruction of a block can send
+//! execution anywhere but to the next one.
+
+use std::fmt::Write;
+
+use crate::CauchemarVMInstruction;
+
+/// Instructions `start..end` of a routine.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Block {
+    pub start: usize,
+    pub end: usize,
+    /// Indices of the blocks that can run next, the one after this first.
+    /// Jumping past the last instruction returns and has no block.
+    pub successors: Vec<usize>,
+}
+
+/// Split `instructions` into basic blocks, in order.
+pub fn blocks(instructions: &[CauchemarVMInstruction]) -> Vec<Block> {
+    let mut leaders = vec![false; instructions.len() + 1];
+    leaders[0] = true;
+    for (i, instruction) in instructions.iter().enumerate() {
+        match instruction {
+            CauchemarVMInstruction::Jump(target) | CauchemarVMInstruction::JumpIfFalse(target) => {
+                leaders[(*target).min(instructions.len())] = true;
+                leaders[i + 1] = true;
+            }
+            CauchemarVMInstruction::Return | CauchemarVMInstruction::ReturnWith => leaders[i + 1] = true,
+            _ => {}
+        }
+    }
+    let starts: Vec<usize> = (0..instructions.len()).filter(|i| leaders[*i]).collect();
+    let block_at = |index: usize| starts.binary_search(&index).ok();
+
+    let mut blocks = Vec::new();
+    for (i, start) in starts.iter().enumerate() {
+        let end = starts.get(i + 1).copied().unwrap_or(instructions.len());
+        let mut successors: Vec<usize> = match &instructions[end - 1] {
+            CauchemarVMInstruction::Jump(target) => block_at(*target).into_iter().collect(),
+            CauchemarVMInstruction::JumpIfFalse(target) => block_at(end).into_iter().chain(block_at(*target)).collect(),
+            CauchemarVMInstruction::Return | CauchemarVMInstruction::ReturnWith => Vec::new(),
+            _ => block_at(end).into_iter().collect(),
+        };
+        successors.dedup();
+        blocks.push(Block { start: *start, end, successors });
+    }
+    blocks
+}
+
+/// The blocks one after the other, their instructions indented under them.
+pub fn text(instructions: &[CauchemarVMInstruction]) -> String {
+    let mut out = String::new();
+    for (i, block) in blocks(instructions).iter().enumerate() {
+        let successors: Vec<String> = block.successors.iter().map(|successor| format!("B{}", successor)).collect();
+        match successors.is_empty() {
+            true => writeln!(out, "B{} [{}, {})", i, block.start, block.end).unwrap(),
+            false => writeln!(out, "B{} [{}, {}) -> {}", i, block.start, block.end, successors.join(" ")).unwrap(),
+        }
+        for (index, instruction) in (block.start..).zip(&instructions[block.start..block.end]) {
+            writeln!(out, "    {}", line(index, instruction)).unwrap();
+        }
+    }
+    out
+}
+
+/// The blocks as a Graphviz graph named after the routine.
+pub fn dot(name: &str, instructions: &[CauchemarVMInstruction]) -> String {
+    let mut out = format!("digraph \"{}\" {{\n    node [shape=box];\n", name);
+    let blocks = blocks(instructions);
+    for (i, block) in blocks.iter().enumerate() {
+        let label: String = (block.start..)
+            .zip(&instructions[block.start..block.end])
+            .map(|(index, instruction)| format!("{}\\l", escape(&line(index, instruction))))
+            .collect();
+        writeln!(out, "    B{} [label=\"{}\"];", i, label).unwrap();
+    }
+    for (i, block) in blocks.iter().enumerate() {
+        for successor in &block.successors {
+            writeln!(out, "    B{} -> B{};", i, successor).unwrap();
+        }
+    }
+    out.push_str("}\n");
+    out
+}
+
+fn line(index: usize, instruction: &CauchemarVMInstruction) -> String {
+    match instruction {
+        CauchemarVMInstruction::Push(value) => format!("[{}] PUSH {}", index, value.repr()),
+        instruction => format!("[{}] {}", index, instruction),
+    }
+}
+
+fn escape(label: &str) -> String {
+    label.replace('\\', "\\\\").replace('"', "\\\"")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::CauchemarVMValue;
+    use CauchemarVMInstruction::*;
+
+    fn push(n: i32) -> CauchemarVMInstruction {
+        Push(CauchemarVMValue::Number(n))
+    }
+
+    fn block(start: usize, end: usize, successors: &[usize]) -> Block {
+        Block { start, end, successors: successors.to_vec() }
+    }
+
+    /// Blocks no other block leads to, the first one aside.
+    fn unreachable(blocks: &[Block]) -> Vec<usize> {
+        (1..blocks.len()).filter(|i| blocks.iter().all(|block| !block.successors.contains(i))).collect()
+    }
+
+    #[test]
+    fn straight_line_is_one_block() {
+        let instructions = [push(1), push(2), Add, Return];
+        assert_eq!(blocks(&instructions), [block(0, 4, &[])]);
+    }
+
+    #[test]
+    fn if_else_branches_and_joins() {
+        // TRUE IF 1 ELSE 2 THEN
+        let instructions = [Push(CauchemarVMValue::Bool(true)), JumpIfFalse(4), push(1), Jump(5), push(2), Return];
+        assert_eq!(
+            blocks(&instructions),
+            [block(0, 2, &[1, 2]), block(2, 4, &[3]), block(4, 5, &[3]), block(5, 6, &[])]
+        );
+    }
+
+    #[test]
+    fn jump_to_the_next_instruction_has_one_successor() {
+        let instructions = [Push(CauchemarVMValue::Bool(false)), JumpIfFalse(2), Return];
+        assert_eq!(blocks(&instructions), [block(0, 2, &[1]), block(2, 3, &[])]);
+    }
+
+    #[test]
+    fn while_loops_back() {
+        // 0 DO 1 + DUP 10 LESS-THAN WHILE
+        let instructions = [
+            push(0),
+            push(1),
+            Add,
+            Call("DUP".into()),
+            push(10),
+            Call("LESS-THAN".into()),
+            JumpIfFalse(8),
+            Jump(1),
+            Return,
+        ];
+        assert_eq!(
+            blocks(&instructions),
+            [block(0, 1, &[1]), block(1, 7, &[2, 3]), block(7, 8, &[1]), block(8, 9, &[])]
+        );
+    }
+
+    #[test]
+    fn code_after_a_return_is_unreachable() {
+        let instructions = [push(1), ReturnWith, push(2), Return];
+        let blocks = blocks(&instructions);
+        assert_eq!(blocks, [block(0, 2, &[]), block(2, 4, &[])]);
+        assert_eq!(unreachable(&blocks), [1]);
+    }
+
+    #[test]
+    fn code_jumped_over_is_unreachable() {
+        let instructions = [Jump(3), push(1), Call("PRINT".into()), push(2), Return];
+        let blocks = blocks(&instructions);
+        assert_eq!(blocks, [block(0, 1, &[2]), block(1, 3, &[2]), block(3, 5, &[])]);
+        assert_eq!(unreachable(&blocks), [1]);
+    }
+
+    #[test]
+    fn jumping_past_the_end_returns() {
+        let instructions = [Push(CauchemarVMValue::Bool(true)), JumpIfFalse(10), push(1)];
+        assert_eq!(blocks(&instructions), [block(0, 2, &[1]), block(2, 3, &[])]);
+    }
+
+    #[test]
+    fn text_lists_blocks_with_their_successors() {
+        let instructions = [Push(CauchemarVMValue::Bool(true)), JumpIfFalse(3), push(1), Return];
+        assert_eq!(
+            text(&instructions),
+            "B0 [0, 2) -> B1 B2\n    [0] PUSH TRUE\n    [1] JUMP_IF_FALSE 3\n\
+             B1 [2, 3) -> B2\n    [2] PUSH 1\n\
+             B2 [3, 4)\n    [3] RETURN\n"
+        );
+    }
+}
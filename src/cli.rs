@@ -0,0 +1,1830 @@
+//! The `cauchemar` command line.
+
+use std::{
+    any::Any,
+    cell::RefCell,
+    collections::{HashMap, HashSet},
+    env, fmt, fs,
+    io::{self, IsTerminal, Read, Write},
+    panic::{self, AssertUnwindSafe},
+    path::{Path, PathBuf},
+    process::ExitCode,
+    sync::{mpsc, Arc, Mutex},
+    time::{Duration, Instant, SystemTime},
+};
+
+use clap::{error::ErrorKind, CommandFactory, Parser as ClapParser, Subcommand, ValueEnum};
+use pest::Parser;
+use serde::Serialize;
+
+use crate::{
+    asm_output, c_output, callgraph, cfg, closest_name, compile_user_routines, coverage, dap, diff, display,
+    escape_string, formatter, highlight, parse_cauchemar_file, parse_cauchemar_snippet, snippet, source_spans,
+    style, wasm_output, CauchemarDiagnostic, CauchemarError, CauchemarParser, CauchemarProgram, CauchemarSpan, CauchemarVM,
+    CauchemarVMBuilder, CauchemarVMFrame, CauchemarVMInstruction, CauchemarVMRoutine, CauchemarVMSnapshot,
+    CauchemarVMValue, DEFAULT_MAX_DEPTH, DEFAULT_MAX_STACK, Rule, SandboxPolicy,
+};
+
+/// What to do with the values left on the stack once the program is over.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum StackDump {
+    /// A single line on stderr, so it doesn't end up in piped output.
+    Labeled,
+    /// Nothing at all, see --quiet.
+    Quiet,
+    /// One value per line on stdout, see --print-stack.
+    Print,
+}
+
+/// Show what is left on the stack once the program is over, bottom first,
+/// unless it stopped with EXIT.
+fn print_remaining(vm: &CauchemarVM, dump: StackDump) {
+    if vm.exit.is_some() {
+        return;
+    }
+    match dump {
+        StackDump::Quiet => {}
+        StackDump::Print => {
+            for value in &vm.stack {
+                println!("{}", value);
+            }
+        }
+        StackDump::Labeled if vm.stack.is_empty() => {}
+        StackDump::Labeled => {
+            let values: Vec<_> = vm.stack.iter().map(CauchemarVMValue::repr).collect();
+            eprintln!("-- stack: {}", values.join(" "));
+        }
+    }
+}
+
+
+#[derive(ClapParser)]
+#[command(name = "cauchemar", about = "Cauchemar Interpreter", long_about = None)]
+#[command(args_conflicts_with_subcommands = true)]
+struct Cli {
+    #[command(subcommand)]
+    command: Option<Command>,
+
+    /// Cauchemar source files to run as one program, "-" reads from standard
+    /// input (the default when stdin is piped). The whole input is consumed,
+    /// so the program itself sees an exhausted stdin.
+    #[arg(value_name = "FILE")]
+    files: Vec<PathBuf>,
+
+    /// Arguments for the program, read with ARG, ARG-COUNT and ARGS
+    #[arg(last = true, value_name = "ARGS")]
+    args: Vec<String>,
+
+    /// Run the given code instead of a file, wrapped in PROGRAM when it has
+    /// no routine header of its own
+    #[arg(short, long, value_name = "CODE", conflicts_with = "files")]
+    eval: Option<String>,
+
+    /// Library file whose routines are added before running, may be given
+    /// more than once and doesn't need a PROGRAM
+    #[arg(long, value_name = "FILE")]
+    load: Vec<PathBuf>,
+
+    /// Routine to start running from
+    #[arg(long, value_name = "NAME", default_value = "PROGRAM")]
+    entry: String,
+
+    /// Maximum number of nested routine calls before giving up
+    #[arg(long, default_value_t = DEFAULT_MAX_DEPTH)]
+    max_depth: usize,
+
+    /// Maximum number of instructions to execute, 0 for unlimited
+    #[arg(long, default_value_t = 0)]
+    max_steps: u64,
+
+    /// Maximum number of values on the stack, 0 for unlimited
+    #[arg(long, value_name = "N", default_value_t = DEFAULT_MAX_STACK)]
+    stack_limit: usize,
+
+    /// Don't show the values left on the stack once the program is over
+    #[arg(short, long)]
+    quiet: bool,
+
+    /// Print the values left on the stack to stdout, one per line from the
+    /// bottom, instead of a single line on stderr
+    #[arg(long, conflicts_with = "quiet")]
+    print_stack: bool,
+
+    /// Make EQUALS on values of different types an error instead of FALSE
+    #[arg(long)]
+    strict_equality: bool,
+
+    /// Make routines leaving a different number of values than their
+    /// RETURNS an error
+    #[arg(long)]
+    strict_returns: bool,
+
+    /// Run the program once for every line of stdin, with the line on the
+    /// stack, printing the value it leaves if any
+    #[arg(long, conflicts_with_all = ["benchmark", "profile", "debug_step"])]
+    lines: bool,
+
+    /// Run the program N times and report timing statistics to stderr
+    #[arg(long, value_name = "N", value_parser = clap::value_parser!(u32).range(1..))]
+    benchmark: Option<u32>,
+
+    /// Report benchmark statistics as JSON, to stdout
+    #[arg(long, requires = "benchmark")]
+    benchmark_json: bool,
+
+    /// Report calls, instructions and time spent in each routine to stderr
+    #[arg(long)]
+    profile: bool,
+
+    /// Also write the profile as CSV to this file
+    #[arg(long, value_name = "FILE", requires = "profile")]
+    profile_out: Option<PathBuf>,
+
+    /// Parse and compile the program, report every problem found and exit
+    /// without running it
+    #[arg(long)]
+    check: bool,
+
+    /// Print every instruction along with the VM state to stderr as it runs
+    #[arg(long)]
+    trace: bool,
+
+    /// Print the compiled routines and exit without running
+    #[arg(long)]
+    disassemble: bool,
+
+    /// Print the routines and the calls between them as a Graphviz graph
+    /// and exit without running
+    #[arg(long)]
+    dump_callgraph: bool,
+
+    /// Print the basic blocks of a routine and the jumps between them and
+    /// exit without running
+    #[arg(long, value_name = "ROUTINE")]
+    dump_cfg: Option<String>,
+
+    /// Format of --dump-cfg
+    #[arg(long, value_enum, default_value_t = CfgFormat::Text, requires = "dump_cfg")]
+    cfg_format: CfgFormat,
+
+    /// Compile the user routines to a WebAssembly module and exit without
+    /// running
+    #[arg(long, value_name = "FILE")]
+    output_wasm: Option<PathBuf>,
+
+    /// Write the compiled routines along with the commands they came from
+    /// and exit without running
+    #[arg(long, value_name = "FILE")]
+    output_asm: Option<PathBuf>,
+
+    /// Compile the user routines to C, along with the runtime header in the
+    /// same directory, and exit without running
+    #[arg(long, value_name = "FILE")]
+    output_c: Option<PathBuf>,
+
+    /// Only allow natives that can't reach outside of the interpreter
+    #[arg(long)]
+    sandbox: bool,
+
+    /// Make every native touching the file system fail
+    #[arg(long)]
+    no_fs: bool,
+
+    /// Make every native running other programs fail
+    #[arg(long)]
+    no_exec: bool,
+
+    /// Make SLEEP return right away, implied by --sandbox
+    #[arg(long)]
+    no_sleep: bool,
+
+    /// Only allow the given native routine, may be repeated
+    #[arg(long, value_name = "NAME", conflicts_with = "sandbox")]
+    allow_native: Vec<String>,
+
+    /// Print the parsed routines and exit without running
+    #[arg(long)]
+    dump_ast: bool,
+
+    /// Print the parsed program as S-expressions and exit without running
+    #[arg(long, conflicts_with = "dump_ast")]
+    print_ast: bool,
+
+    /// Print the tree the parser gives before it is turned into routines and
+    /// exit without running
+    #[arg(long)]
+    print_parse_tree: bool,
+
+    /// Print the tokens of the source as JSON, for editors to highlight it,
+    /// and exit without running
+    #[arg(long)]
+    highlight_tokens: bool,
+
+    /// Run the file again whenever it or a file it loaded changes
+    #[arg(long, requires = "files", conflicts_with = "eval")]
+    watch: bool,
+
+    /// Report errors on stderr as JSON objects, one per line
+    #[arg(long)]
+    json_errors: bool,
+
+    /// When to color errors, traces and listings
+    #[arg(long, value_enum, default_value = "auto", global = true)]
+    color: ColorChoice,
+
+    /// Print elapsed time, instruction count and max stack depth on exit
+    #[arg(long, conflicts_with = "profile")]
+    time: bool,
+
+    /// Pause before every instruction, Enter runs it, `c` stops pausing
+    #[arg(long, conflicts_with_all = ["profile", "time", "benchmark"])]
+    debug_step: bool,
+
+    /// Report how much of each routine ran to stderr, along with the source
+    /// marking lines that didn't
+    #[arg(long, conflicts_with_all = ["profile", "time", "benchmark", "debug_step", "lines"])]
+    coverage: bool,
+
+    /// Format of --dump-ast
+    #[arg(long, value_enum, default_value_t = OutputFormat::Text, requires = "dump_ast")]
+    format: OutputFormat,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Reprint a source file in the canonical style
+    Fmt {
+        /// Cauchemar source file to format
+        file: PathBuf,
+
+        /// Write the result back to the file instead of printing it
+        #[arg(long)]
+        write: bool,
+
+        /// Exit with 1 when the file isn't formatted, without printing it
+        #[arg(long, conflicts_with = "write")]
+        check: bool,
+
+        /// Column to wrap routine bodies at
+        #[arg(long, default_value_t = formatter::DEFAULT_WIDTH)]
+        width: usize,
+    },
+    /// Write the compiled routines of a source file as assembly meant to be
+    /// read back by tools
+    Disasm {
+        /// Cauchemar source file to compile
+        file: PathBuf,
+
+        /// Write the assembly to this file instead of stdout
+        #[arg(short, long, value_name = "FILE")]
+        output: Option<PathBuf>,
+    },
+    /// Time a routine of a source file over many runs
+    Bench {
+        /// Cauchemar source file to benchmark
+        file: PathBuf,
+
+        /// Routine to run
+        #[arg(long, default_value = "PROGRAM")]
+        entry: String,
+
+        /// Number of measured runs
+        #[arg(long, default_value_t = 10, value_parser = clap::value_parser!(u32).range(1..))]
+        iterations: u32,
+
+        /// Number of runs before measuring, to warm up caches
+        #[arg(long, default_value_t = 3)]
+        warmup: u32,
+
+        /// Format of the report
+        #[arg(long, value_enum, default_value_t = OutputFormat::Text)]
+        format: OutputFormat,
+    },
+    /// Run every TEST- routine of a source file and report which failed
+    Test {
+        /// Cauchemar source file holding the tests
+        #[arg(required_unless_present = "examples")]
+        file: Option<PathBuf>,
+
+        /// Only run the tests, or examples, with this in their name
+        #[arg(long, value_name = "SUBSTRING")]
+        filter: Option<String>,
+
+        /// Run every file of the directory with `#=>` lines instead, checking
+        /// that it prints them
+        #[arg(long, value_name = "DIR", conflicts_with = "file")]
+        examples: Option<PathBuf>,
+
+        /// Report how much of each routine the tests ran, see the option of
+        /// the same name
+        #[arg(long, conflicts_with = "examples")]
+        coverage: bool,
+    },
+    /// Run a source file one instruction at a time under a prompt
+    Debug {
+        /// Cauchemar source file to debug
+        file: PathBuf,
+    },
+    /// Debug a source file from an editor over the Debug Adapter Protocol on
+    /// stdin and stdout, the file being given by the launch request
+    Dap,
+    /// Serve editors over the Language Server Protocol on stdin and stdout
+    #[cfg(feature = "lsp")]
+    Lsp,
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum ColorChoice {
+    /// When printing to a terminal and NO_COLOR isn't set
+    Auto,
+    Always,
+    Never,
+}
+
+impl ColorChoice {
+    fn enabled(self, terminal: bool) -> bool {
+        match self {
+            ColorChoice::Auto => terminal && env::var_os("NO_COLOR").is_none_or(|v| v.is_empty()),
+            ColorChoice::Always => true,
+            ColorChoice::Never => false,
+        }
+    }
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum CfgFormat {
+    /// Every block followed by its instructions
+    Text,
+    /// A Graphviz graph
+    Dot,
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum OutputFormat {
+    /// Meant to be read by people
+    Text,
+    /// Meant to be read by other tools
+    Json,
+}
+
+fn report(error: CauchemarError) -> ExitCode {
+    eprintln!("{}", style::error(&error));
+    ExitCode::from(error.exit_code())
+}
+
+fn report_json(diagnostic: CauchemarDiagnostic) {
+    eprintln!("{}", serde_json::to_string(&diagnostic).expect("diagnostics always serialize"));
+}
+
+/// The innermost user routine when the program failed and the span of the
+/// command it was running, thunks counting as part of the command that ran
+/// them and natives as the call to them.
+fn error_span<'v>(
+    vm: &'v CauchemarVM,
+    spans: &HashMap<Arc<str>, Vec<CauchemarSpan>>,
+) -> Option<(&'v str, CauchemarSpan)> {
+    let (name, ip) = vm.ip.iter().rev().find_map(|(frame, ip)| match frame {
+        CauchemarVMFrame::Routine(name) if matches!(vm.routines.get(name), Some(CauchemarVMRoutine::User(_))) => {
+            Some((name, ip))
+        }
+        CauchemarVMFrame::Routine(_) | CauchemarVMFrame::Thunk(_) => None,
+    })?;
+    let span = *spans.get(name)?.get(ip.checked_sub(1)?)?;
+    (span.line != 0).then_some((name, span))
+}
+
+fn print_backtrace(vm: &CauchemarVM) {
+    const SHOWN: usize = 10;
+
+    let backtrace = vm.backtrace();
+    if backtrace.len() <= SHOWN * 2 {
+        for frame in &backtrace {
+            eprintln!("    at {}", style::position(frame));
+        }
+        return;
+    }
+
+    for frame in &backtrace[..SHOWN] {
+        eprintln!("    at {}", style::position(frame));
+    }
+    eprintln!("    ... {} frames omitted ...", backtrace.len() - SHOWN * 2);
+    for frame in &backtrace[backtrace.len() - SHOWN..] {
+        eprintln!("    at {}", style::position(frame));
+    }
+}
+
+/// Exit code for bugs in the interpreter itself, as opposed to the program.
+const INTERNAL_ERROR_EXIT_CODE: u8 = 70;
+
+thread_local! {
+    static PANIC_LOCATION: RefCell<Option<String>> = const { RefCell::new(None) };
+}
+
+/// Report a panic, along with where the program was when there is one
+/// running.
+fn report_internal_error(vm: Option<&CauchemarVM>, payload: Box<dyn Any + Send>) -> ExitCode {
+    let message = match payload.downcast_ref::<&str>() {
+        Some(message) => message.to_string(),
+        None => match payload.downcast_ref::<String>() {
+            Some(message) => message.clone(),
+            None => "unknown panic".to_string(),
+        },
+    };
+
+    eprintln!("internal interpreter error, please report this");
+    match PANIC_LOCATION.with(|location| location.borrow_mut().take()) {
+        Some(location) => eprintln!("  panicked at {}: {}", location, message),
+        None => eprintln!("  panicked: {}", message),
+    }
+    if let Some(vm) = vm {
+        eprintln!("  while executing:");
+        print_backtrace(vm);
+    }
+
+    ExitCode::from(INTERNAL_ERROR_EXIT_CODE)
+}
+
+/// Print the pairs pest made for --print-parse-tree, one per line and
+/// indented under the one they are in, with their rule, their span in bytes
+/// and, for those with nothing inside, the text they matched.
+fn print_parse_tree(pair: pest::iterators::Pair<Rule>, depth: usize) {
+    let span = pair.as_span();
+    let mut inner = pair.clone().into_inner().peekable();
+    match inner.peek() {
+        Some(_) => println!("{}{:?} {}..{}", "  ".repeat(depth), pair.as_rule(), span.start(), span.end()),
+        None => println!("{}{:?} {}..{} {:?}", "  ".repeat(depth), pair.as_rule(), span.start(), span.end(), span.as_str()),
+    }
+    for pair in inner {
+        print_parse_tree(pair, depth + 1);
+    }
+}
+
+/// Print every routine on its own line, sorted by name so dumps can be diffed.
+fn dump_ast(program: &CauchemarProgram) {
+    let mut constants: Vec<_> = program.constants.iter().collect();
+    constants.sort_by_key(|(name, _)| *name);
+
+    for (name, value) in constants {
+        println!("DEFINE {} {}", name, value);
+    }
+
+    let mut macros: Vec<_> = program.macros.iter().collect();
+    macros.sort_by_key(|(name, _)| *name);
+
+    for (name, body) in macros {
+        print!("MACRO {}", name);
+        for ast in body {
+            print!(" {}", ast);
+        }
+        println!();
+    }
+
+    let mut routines: Vec<_> = program.routines.iter().collect();
+    routines.sort_by_key(|(name, _)| *name);
+
+    for (routine_name, routine) in routines {
+        print!("{}:", routine_name);
+        for ast in routine {
+            print!(" {}", ast);
+        }
+        println!();
+    }
+}
+
+/// Version of the assembly written by `cauchemar disasm`, bumped whenever a
+/// reader of the previous version would misread the output.
+const CASM_VERSION: u32 = 1;
+
+/// Compile a source file and write it as assembly to `output`, or stdout.
+fn casm_file(file: &Path, output: Option<&Path>) -> Result<(), CauchemarError> {
+    let source = read_source(file)?;
+    let program = parse_source(file, &source)?;
+    let mut routines = HashMap::new();
+    compile_user_routines(&mut routines, program);
+
+    let casm = casm(&routines);
+    match output {
+        Some(output) => match fs::write(output, casm) {
+            Ok(()) => Ok(()),
+            Err(e) => Err(CauchemarError::FileAccess(output.to_path_buf(), e)),
+        },
+        None => {
+            print!("{}", casm);
+            Ok(())
+        }
+    }
+}
+
+/// Write user routines, sorted by name, in the assembly format:
+///
+/// ```text
+/// .casm 1
+/// .routine NAME
+/// L1:
+///     PUSH STRING "escaped like in source"
+///     JUMP_IF_FALSE L1
+///     PUSH THUNK
+///         CALL PRINT
+///         RETURN
+///     END
+/// ```
+///
+/// One instruction per line, indented by nesting. Jump targets are labels
+/// numbered per routine. Values are written as their type followed by the
+/// value, with ARRAY, MAP and THUNK holding one value, entry or instruction
+/// per line until END. Map entries are a KEY line followed by the value.
+fn casm(routines: &HashMap<Arc<str>, CauchemarVMRoutine>) -> String {
+    let mut routines: Vec<_> = routines
+        .iter()
+        .filter_map(|(name, routine)| match routine {
+            CauchemarVMRoutine::User(instructions) => Some((name, instructions)),
+            CauchemarVMRoutine::Native(_) | CauchemarVMRoutine::Host(_) => None,
+        })
+        .collect();
+    routines.sort_by_key(|(name, _)| *name);
+
+    let mut out = format!(".casm {}\n", CASM_VERSION);
+    for (routine_name, instructions) in routines {
+        out.push_str(&format!("\n.routine {}\n", routine_name));
+        casm_instructions(&mut out, instructions, 1, &mut 0);
+    }
+    out
+}
+
+fn casm_instructions(out: &mut String, instructions: &[CauchemarVMInstruction], depth: usize, labels: &mut usize) {
+    let mut targets: Vec<usize> = instructions
+        .iter()
+        .filter_map(|instruction| match instruction {
+            CauchemarVMInstruction::Jump(target) | CauchemarVMInstruction::JumpIfFalse(target) => Some(*target),
+            _ => None,
+        })
+        .collect();
+    targets.sort_unstable();
+    targets.dedup();
+    let first = *labels + 1;
+    *labels += targets.len();
+    let label = |target: usize| format!("L{}", first + targets.binary_search(&target).unwrap());
+    let indent = "    ".repeat(depth);
+
+    for (i, instruction) in instructions.iter().enumerate() {
+        if targets.binary_search(&i).is_ok() {
+            out.push_str(&format!("{}:\n", label(i)));
+        }
+        match instruction {
+            CauchemarVMInstruction::Push(value) => {
+                out.push_str(&format!("{}PUSH ", indent));
+                casm_value(out, value, depth, labels);
+            }
+            CauchemarVMInstruction::Jump(target) => out.push_str(&format!("{}JUMP {}\n", indent, label(*target))),
+            CauchemarVMInstruction::JumpIfFalse(target) => {
+                out.push_str(&format!("{}JUMP_IF_FALSE {}\n", indent, label(*target)))
+            }
+            instruction => out.push_str(&format!("{}{}\n", indent, instruction)),
+        }
+    }
+    // A jump past the last instruction still needs its label.
+    if targets.binary_search(&instructions.len()).is_ok() {
+        out.push_str(&format!("{}:\n", label(instructions.len())));
+    }
+}
+
+fn casm_value(out: &mut String, value: &CauchemarVMValue, depth: usize, labels: &mut usize) {
+    let indent = "    ".repeat(depth);
+    match value {
+        CauchemarVMValue::Number(n) => out.push_str(&format!("NUMBER {}\n", n)),
+        CauchemarVMValue::BigInt(n) => out.push_str(&format!("BIGINT {}\n", n)),
+        CauchemarVMValue::Float(n) => out.push_str(&format!("FLOAT {:?}\n", n)),
+        CauchemarVMValue::Bool(true) => out.push_str("BOOL TRUE\n"),
+        CauchemarVMValue::Bool(false) => out.push_str("BOOL FALSE\n"),
+        CauchemarVMValue::String(s) => out.push_str(&format!("STRING \"{}\"\n", escape_string(s))),
+        CauchemarVMValue::Handle(handle) => out.push_str(&format!("HANDLE {}\n", handle)),
+        CauchemarVMValue::Thunk(instructions) => {
+            out.push_str("THUNK\n");
+            casm_instructions(out, instructions, depth + 1, labels);
+            out.push_str(&format!("{}END\n", indent));
+        }
+        CauchemarVMValue::Array(values) => {
+            out.push_str("ARRAY\n");
+            for value in values {
+                out.push_str(&"    ".repeat(depth + 1));
+                casm_value(out, value, depth + 1, labels);
+            }
+            out.push_str(&format!("{}END\n", indent));
+        }
+        CauchemarVMValue::Map(entries) => {
+            out.push_str("MAP\n");
+            for (key, value) in entries {
+                let indent = "    ".repeat(depth + 1);
+                out.push_str(&format!("{}KEY \"{}\"\n{}", indent, escape_string(key), indent));
+                casm_value(out, value, depth + 1, labels);
+            }
+            out.push_str(&format!("{}END\n", indent));
+        }
+    }
+}
+
+/// What was spent running one routine, see --profile.
+#[derive(Debug, Default)]
+struct RoutineProfile {
+    calls: u64,
+    instructions: u64,
+    time: Duration,
+}
+
+/// Run the program from `initial` once for every line of stdin, pushing the
+/// line first and printing what is left, like PRINT would. Stops early when
+/// the program calls EXIT.
+fn run_lines(
+    vm: &mut CauchemarVM,
+    initial: &CauchemarVMSnapshot,
+    mut max_depth: Option<&mut usize>,
+) -> Result<u8, CauchemarError> {
+    let mut steps = 0;
+    for (i, line) in io::stdin().lines().enumerate() {
+        let line = match line {
+            Ok(line) => line,
+            Err(e) => return Err(CauchemarError::Io(PathBuf::from(STDIN_NAME), e)),
+        };
+        vm.restore(initial.clone());
+        vm.push(CauchemarVMValue::String(line.into()))?;
+        let status = match max_depth.as_deref_mut() {
+            Some(max_depth) => timed_vm(vm, max_depth)?,
+            None => vm.run()?,
+        };
+        steps += vm.steps;
+        if vm.exit.is_some() {
+            vm.steps = steps;
+            return Ok(status);
+        }
+
+        match vm.stack.len() {
+            0 => {}
+            1 => println!("{}", vm.stack[0]),
+            found => return Err(CauchemarError::LineResult { line: i + 1, found }),
+        }
+        vm.stack.clear();
+    }
+    vm.steps = steps;
+    Ok(0)
+}
+
+/// Same as run_vm but waiting for Enter on stdin before every instruction.
+/// `s` shows the stack, `r` the routines being run, `c` runs the rest without
+/// pausing and `q` stops the program with a status of 0.
+fn step_vm(vm: &mut CauchemarVM) -> Result<u8, CauchemarError> {
+    let mut lines = io::stdin().lines();
+    let mut stepping = true;
+    loop {
+        while stepping {
+            match (vm.ip.last(), vm.next_instruction()) {
+                (Some((frame, ip)), Some(instruction)) => eprintln!("{} [{}] {}", frame, ip, instruction),
+                (Some((frame, _)), None) => eprintln!("{} (native)", frame),
+                (None, _) => {}
+            }
+            let line = match lines.next() {
+                Some(Ok(line)) => line,
+                Some(Err(e)) => return Err(CauchemarError::Io(PathBuf::from(STDIN_NAME), e)),
+                None => {
+                    stepping = false;
+                    break;
+                }
+            };
+            match line.trim() {
+                "" => break,
+                "s" => {
+                    for value in vm.stack.iter().rev() {
+                        eprintln!("    {}", value.repr());
+                    }
+                }
+                "r" => print_backtrace(vm),
+                "c" => stepping = false,
+                "q" => return Ok(0),
+                _ => eprintln!("Enter steps, s shows the stack, r the routines, c continues, q quits"),
+            }
+        }
+        if let Some(status) = vm.step()? {
+            return Ok(status);
+        }
+    }
+}
+
+/// Same as run_vm but keeping track of the deepest the stack got. Kept
+/// apart so that runs without --time don't pay for it.
+fn timed_vm(vm: &mut CauchemarVM, max_depth: &mut usize) -> Result<u8, CauchemarError> {
+    loop {
+        *max_depth = (*max_depth).max(vm.stack.len());
+        if let Some(status) = vm.step()? {
+            return Ok(status);
+        }
+    }
+}
+
+/// Same as run_vm but timing every step. Kept apart so that runs without
+/// --profile don't pay for it.
+fn profile_vm(
+    vm: &mut CauchemarVM,
+    profile: &mut HashMap<Arc<str>, RoutineProfile>,
+) -> Result<u8, CauchemarError> {
+    let thunk: Arc<str> = "<thunk>".into();
+    let routine = |frame: &CauchemarVMFrame| match frame {
+        CauchemarVMFrame::Routine(name) => name.clone(),
+        CauchemarVMFrame::Thunk(_) => thunk.clone(),
+    };
+
+    if let Some((frame, _)) = vm.ip.last() {
+        profile.entry(routine(frame)).or_default().calls += 1;
+    }
+    loop {
+        let (current, depth) = match vm.ip.last() {
+            Some((frame, _)) => (routine(frame), vm.ip.len()),
+            None => (thunk.clone(), 0),
+        };
+
+        let start = Instant::now();
+        let result = vm.step();
+        let elapsed = start.elapsed();
+
+        let spent = profile.entry(current).or_default();
+        spent.instructions += 1;
+        spent.time += elapsed;
+
+        if let Some(status) = result? {
+            return Ok(status);
+        }
+        if vm.ip.len() > depth {
+            if let Some((frame, _)) = vm.ip.last() {
+                profile.entry(routine(frame)).or_default().calls += 1;
+            }
+        }
+    }
+}
+
+/// Same as run_vm but recording which instructions of user routines ran,
+/// see --coverage.
+fn coverage_vm(vm: &mut CauchemarVM, executed: &mut coverage::Executed) -> Result<u8, CauchemarError> {
+    loop {
+        if let Some((CauchemarVMFrame::Routine(name), ip)) = vm.ip.last() {
+            if matches!(vm.routines.get(name), Some(CauchemarVMRoutine::User(_))) {
+                executed.entry(name.clone()).or_default().insert(*ip);
+            }
+        }
+        if let Some(status) = vm.step()? {
+            return Ok(status);
+        }
+    }
+}
+
+/// Print the profile to stderr, slowest routine first, and write it to `out`
+/// as CSV when given. Time spent in natives counts against the native.
+fn report_profile(profile: &HashMap<Arc<str>, RoutineProfile>, out: Option<&Path>) -> Result<(), CauchemarError> {
+    let mut routines: Vec<_> = profile.iter().collect();
+    routines.sort_by(|(a, a_spent), (b, b_spent)| b_spent.time.cmp(&a_spent.time).then(a.cmp(b)));
+    let total = profile.values().map(|spent| spent.time).sum::<Duration>().as_secs_f64();
+    let percent = |time: Duration| if total > 0.0 { time.as_secs_f64() / total * 100.0 } else { 0.0 };
+
+    let headers: Vec<String> = ["routine", "calls", "instructions", "time", "%"].map(String::from).into();
+    let rows: Vec<Vec<String>> = routines
+        .iter()
+        .map(|(name, spent)| {
+            vec![
+                name.to_string(),
+                spent.calls.to_string(),
+                spent.instructions.to_string(),
+                format!("{:.3?}", spent.time),
+                format!("{:.1}", percent(spent.time)),
+            ]
+        })
+        .collect();
+    eprint!("{}", display::format_table(&headers, &rows));
+
+    if let Some(out) = out {
+        let mut csv = String::from("routine,calls,instructions,time_ns,percent\n");
+        for (name, spent) in &routines {
+            csv.push_str(&format!(
+                "{},{},{},{},{:.3}\n",
+                name,
+                spent.calls,
+                spent.instructions,
+                spent.time.as_nanos(),
+                percent(spent.time)
+            ));
+        }
+        if let Err(e) = fs::write(out, csv) {
+            return Err(CauchemarError::FileAccess(out.to_path_buf(), e));
+        }
+    }
+    Ok(())
+}
+
+/// Print timing statistics of the benchmark runs in milliseconds, along with
+/// the instructions executed by a run. JSON goes to stdout for scripts to
+/// read and text to stderr, out of the way of the program's own output.
+/// What --benchmark-json prints, times in milliseconds.
+#[derive(Serialize)]
+struct BenchmarkReport {
+    runs: usize,
+    instructions: u64,
+    min_ms: f64,
+    max_ms: f64,
+    mean_ms: f64,
+    median_ms: f64,
+    stddev_ms: f64,
+}
+
+fn report_benchmark(timings: &[Duration], steps: u64, json: bool) {
+    let mut ms: Vec<f64> = timings.iter().map(|t| t.as_secs_f64() * 1000.0).collect();
+    ms.sort_by(f64::total_cmp);
+
+    let runs = ms.len();
+    let mean = ms.iter().sum::<f64>() / runs as f64;
+    let stddev = (ms.iter().map(|t| (t - mean).powi(2)).sum::<f64>() / runs as f64).sqrt();
+    let median = if runs.is_multiple_of(2) {
+        (ms[runs / 2 - 1] + ms[runs / 2]) / 2.0
+    } else {
+        ms[runs / 2]
+    };
+    let (min, max) = (ms[0], ms[runs - 1]);
+
+    if json {
+        let report = BenchmarkReport {
+            runs,
+            instructions: steps,
+            min_ms: min,
+            max_ms: max,
+            mean_ms: mean,
+            median_ms: median,
+            stddev_ms: stddev,
+        };
+        println!("{}", serde_json::to_string(&report).expect("benchmark reports always serialize"));
+    } else {
+        eprintln!("runs:         {}", runs);
+        eprintln!("instructions: {}", steps);
+        eprintln!("min:          {:.3} ms", min);
+        eprintln!("max:          {:.3} ms", max);
+        eprintln!("mean:         {:.3} ms", mean);
+        eprintln!("median:       {:.3} ms", median);
+        eprintln!("stddev:       {:.3} ms", stddev);
+    }
+}
+
+/// Same as a failed assertion, whatever made the tests fail.
+const TEST_FAILURE_EXIT_CODE: u8 = 5;
+
+/// Run the TEST- routines of a file in name order, each from a fresh VM, and
+/// print how they went. Returns whether they all passed.
+fn test_file(file: &Path, filter: Option<&str>, coverage: bool) -> Result<bool, CauchemarError> {
+    let source = read_source(file)?;
+    let program = parse_source(file, &source)?;
+    let maps = coverage.then(|| coverage::source_maps(&program));
+
+    let mut tests: Vec<&str> = program
+        .routines
+        .keys()
+        .copied()
+        .filter(|name| name.starts_with("TEST-") && filter.is_none_or(|filter| name.contains(filter)))
+        .collect();
+    tests.sort_unstable();
+
+    println!("running {} tests", tests.len());
+    let Some(first) = tests.first() else {
+        println!("\ntest result: ok. 0 passed; 0 failed");
+        return Ok(true);
+    };
+
+    let mut vm = CauchemarVMBuilder::new().entry(first).sleep(false).fixed_clock(true).build(program)?;
+    let initial = vm.snapshot();
+    let mut failures = Vec::new();
+    let mut executed = HashMap::new();
+    for test in &tests {
+        vm.restore(initial.clone());
+        vm.ip = vec![(CauchemarVMFrame::Routine((*test).into()), 0)];
+
+        let result = match coverage {
+            true => coverage_vm(&mut vm, &mut executed),
+            false => vm.run(),
+        };
+        // EXIT with anything but 0 says the test failed as much as an error.
+        let failure = match result {
+            Ok(0) => None,
+            Ok(status) => Some(format!("Exited with status {}", status)),
+            Err(e) => Some(e.to_string()),
+        };
+        match failure {
+            None => println!("{} ... ok", test),
+            Some(failure) => {
+                println!("{} ... FAILED", test);
+                failures.push((test, failure, vm.backtrace()));
+            }
+        }
+    }
+
+    for (test, error, backtrace) in &failures {
+        println!("\n---- {} ----", test);
+        println!("{}", error);
+        for frame in backtrace {
+            println!("    at {}", frame);
+        }
+    }
+
+    let passed = tests.len() - failures.len();
+    let result = if failures.is_empty() { "ok" } else { "FAILED" };
+    println!("\ntest result: {}. {} passed; {} failed", result, passed, failures.len());
+    if let Some(maps) = &maps {
+        coverage::report(maps, &executed, Some(&source));
+    }
+    Ok(failures.is_empty())
+}
+
+/// What an example is expected to print, the text of its `#=>` comments in
+/// order, one line each. None when it has none.
+fn expected_output(source: &str) -> Result<Option<String>, pest::error::Error<Rule>> {
+    let mut expected = None;
+    for pair in CauchemarParser::parse(Rule::program, source)?.flatten() {
+        if pair.as_rule() != Rule::COMMENT {
+            continue;
+        }
+        if let Some(line) = pair.as_str().strip_prefix("#=>") {
+            let text = expected.get_or_insert_with(String::new);
+            text.push_str(line.strip_prefix(' ').unwrap_or(line));
+            text.push('\n');
+        }
+    }
+    Ok(expected)
+}
+
+/// Collects what an example prints, shared with the VM running it.
+#[derive(Clone, Default)]
+struct CapturedOutput(Arc<Mutex<Vec<u8>>>);
+
+impl Write for CapturedOutput {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.lock().unwrap().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Run the source files of `dir` having `#=>` comments in name order, and
+/// diff what each printed against them. Returns whether they all matched.
+fn test_examples(dir: &Path, filter: Option<&str>) -> Result<bool, CauchemarError> {
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(e) => return Err(CauchemarError::Io(dir.to_path_buf(), e)),
+    };
+    let mut files: Vec<PathBuf> = entries
+        .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+        .filter(|path| path.extension().is_some_and(|extension| extension == "cauchemar"))
+        .filter(|path| filter.is_none_or(|filter| path.to_string_lossy().contains(filter)))
+        .collect();
+    files.sort();
+
+    let mut examples = Vec::new();
+    for file in files {
+        let source = read_source(&file)?;
+        match expected_output(&source) {
+            Ok(Some(expected)) => examples.push((file, source, expected)),
+            Ok(None) => {}
+            // Reported along with the other failures.
+            Err(_) => examples.push((file, source, String::new())),
+        }
+    }
+
+    println!("running {} examples", examples.len());
+    let mut failures = Vec::new();
+    for (file, source, expected) in &examples {
+        let output = CapturedOutput::default();
+        let result = parse_source(file, source).and_then(|program| {
+            let mut vm = CauchemarVMBuilder::new()
+                .sleep(false)
+                .fixed_clock(true)
+                .color(false)
+                .input(Box::new(io::empty()))
+                .output(Box::new(output.clone()))
+                .build(program)?;
+            vm.run()
+        });
+        let actual = String::from_utf8_lossy(&output.0.lock().unwrap()).into_owned();
+
+        let failure = match result {
+            Ok(_) => diff::unified(expected, &actual),
+            Err(e) => e.to_string(),
+        };
+        match failure.is_empty() {
+            true => println!("{} ... ok", file.display()),
+            false => {
+                println!("{} ... FAILED", file.display());
+                failures.push((file, failure));
+            }
+        }
+    }
+
+    for (file, failure) in &failures {
+        println!("\n---- {} ----", file.display());
+        print!("{}", failure);
+        if !failure.ends_with('\n') {
+            println!();
+        }
+    }
+
+    let passed = examples.len() - failures.len();
+    let result = if failures.is_empty() { "ok" } else { "FAILED" };
+    println!("\ntest result: {}. {} passed; {} failed", result, passed, failures.len());
+    Ok(failures.is_empty())
+}
+
+const DEBUG_HELP: &str =
+    "Commands: step, next, continue, break ROUTINE[:INDEX], delete ID, list, stack, frames, quit";
+
+/// Where the debugger stops, when entering a routine or at one of its
+/// instructions.
+struct Breakpoint {
+    id: usize,
+    routine: Arc<str>,
+    index: Option<usize>,
+    hits: usize,
+}
+
+impl Breakpoint {
+    /// Whether to stop at `ip` of `frame`, `entered` being whether the frame
+    /// was just called. Breakpoints on a whole routine stop once per call,
+    /// not again when a loop jumps back to its first instruction.
+    fn matches(&self, frame: &CauchemarVMFrame, ip: usize, entered: bool) -> bool {
+        let routine = matches!(frame, CauchemarVMFrame::Routine(name) if *name == self.routine);
+        match self.index {
+            Some(index) => routine && ip == index,
+            None => routine && entered && ip == 0,
+        }
+    }
+}
+
+impl fmt::Display for Breakpoint {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.index {
+            Some(index) => write!(f, "{}:{}", self.routine, index),
+            None => write!(f, "{}", self.routine),
+        }
+    }
+}
+
+/// Step until `done` or a breakpoint is reached. Breakpoints are only looked
+/// at here, so running outside of the debugger doesn't pay for them.
+fn debug_run(
+    vm: &mut CauchemarVM,
+    breakpoints: &mut [Breakpoint],
+    done: impl Fn(&CauchemarVM) -> bool,
+) -> Result<Option<u8>, CauchemarError> {
+    loop {
+        let depth = vm.ip.len();
+        let result = vm.step();
+        if !matches!(result, Ok(None)) || done(vm) {
+            return result;
+        }
+        let entered = vm.ip.len() > depth;
+        let Some((frame, ip)) = vm.ip.last() else {
+            return result;
+        };
+        if let Some(breakpoint) = breakpoints.iter_mut().find(|b| b.matches(frame, *ip, entered)) {
+            breakpoint.hits += 1;
+            eprintln!("Breakpoint {} at {}, hit {} times", breakpoint.id, breakpoint, breakpoint.hits);
+            return result;
+        }
+    }
+}
+
+/// Run a source file under the debugger prompt, returns the exit status of
+/// the program. Commands are read from standard input and everything the
+/// debugger prints goes to stderr, out of the way of the program's output.
+fn debug_file(file: &Path) -> Result<u8, CauchemarError> {
+    let source = read_source(file)?;
+    let program = parse_source(file, &source)?;
+    let mut vm = CauchemarVMBuilder::new().build(program)?;
+    let mut breakpoints: Vec<Breakpoint> = Vec::new();
+    let mut next_id = 1;
+
+    eprintln!("{}", DEBUG_HELP);
+    loop {
+        match (vm.ip.last(), vm.next_instruction()) {
+            (Some((frame, ip)), Some(instruction)) => eprintln!("{} [{}] {}", frame, ip, instruction),
+            (Some((frame, _)), None) => eprintln!("{} (native)", frame),
+            (None, _) => {}
+        }
+        eprint!("(debug) ");
+
+        let mut line = String::new();
+        match io::stdin().read_line(&mut line) {
+            Ok(0) => return Ok(0),
+            Ok(_) => {}
+            Err(e) => return Err(CauchemarError::Io(PathBuf::from(STDIN_NAME), e)),
+        }
+
+        let (command, argument) = match line.trim().split_once(' ') {
+            Some((command, argument)) => (command, argument.trim()),
+            None => (line.trim(), ""),
+        };
+        let result = match command {
+            "step" | "s" => vm.step(),
+            "next" | "n" => {
+                // Keep going until the frames called by this instruction
+                // have returned.
+                let depth = vm.ip.len();
+                debug_run(&mut vm, &mut breakpoints, |vm| vm.ip.len() <= depth)
+            }
+            "continue" | "c" => debug_run(&mut vm, &mut breakpoints, |_| false),
+            "break" | "b" => {
+                let (routine, index) = match argument.split_once(':') {
+                    Some((routine, index)) => match index.parse() {
+                        Ok(index) => (routine, Some(index)),
+                        Err(_) => {
+                            eprintln!("Invalid instruction index {}", index);
+                            continue;
+                        }
+                    },
+                    None => (argument, None),
+                };
+                let Some((routine, _)) = vm.routines.get_key_value(routine) else {
+                    eprintln!("Unknown routine: {}", routine);
+                    continue;
+                };
+                let breakpoint = Breakpoint { id: next_id, routine: routine.clone(), index, hits: 0 };
+                eprintln!("Breakpoint {} at {}", breakpoint.id, breakpoint);
+                breakpoints.push(breakpoint);
+                next_id += 1;
+                continue;
+            }
+            "delete" | "d" => {
+                match argument.parse::<usize>() {
+                    Ok(id) if breakpoints.iter().any(|b| b.id == id) => breakpoints.retain(|b| b.id != id),
+                    _ => eprintln!("No breakpoint {}", argument),
+                }
+                continue;
+            }
+            "list" | "l" => {
+                for breakpoint in &breakpoints {
+                    eprintln!("    {} {}, hit {} times", breakpoint.id, breakpoint, breakpoint.hits);
+                }
+                continue;
+            }
+            "stack" => {
+                for value in vm.stack.iter().rev() {
+                    eprintln!("    {}", value.repr());
+                }
+                continue;
+            }
+            "frames" => {
+                print_backtrace(&vm);
+                continue;
+            }
+            "quit" | "q" => return Ok(0),
+            "" => continue,
+            _ => {
+                eprintln!("{}", DEBUG_HELP);
+                continue;
+            }
+        };
+
+        match result {
+            Ok(None) => {}
+            Ok(Some(status)) => {
+                print_remaining(&vm, StackDump::Labeled);
+                eprintln!("Program exited with status {}", status);
+                return Ok(status);
+            }
+            Err(e) => {
+                eprintln!("{}", style::error(&e));
+                print_backtrace(&vm);
+                return Ok(e.exit_code());
+            }
+        }
+    }
+}
+
+pub(crate) fn read_source(file: &Path) -> Result<String, CauchemarError> {
+    match fs::read_to_string(file) {
+        Ok(source) => Ok(source),
+        Err(e) => Err(CauchemarError::Io(file.to_path_buf(), e)),
+    }
+}
+
+pub(crate) fn parse_source<'a>(file: &Path, source: &'a str) -> Result<CauchemarProgram<'a>, CauchemarError> {
+    match parse_cauchemar_file(source) {
+        Ok(program) => Ok(program),
+        Err(e) => Err(CauchemarError::Parse(e.with_path(&file.display().to_string()))),
+    }
+}
+
+/// Where each routine was written, the name of its file along with the
+/// source, so runtime errors can point into the right one.
+type Origins<'a> = HashMap<&'a str, (String, &'a str)>;
+
+/// Parse every file and merge them into a single program, each routine,
+/// constant and macro may only be defined by one of them.
+fn parse_files<'a>(
+    files: &[PathBuf],
+    sources: &'a [String],
+) -> Result<(CauchemarProgram<'a>, Origins<'a>), CauchemarError> {
+    let mut merged: Option<CauchemarProgram> = None;
+    let mut origins: HashMap<&str, &Path> = HashMap::new();
+    let mut routines = Origins::new();
+
+    for (file, source) in files.iter().zip(sources) {
+        let file = match file.as_os_str() == "-" {
+            true => Path::new(STDIN_NAME),
+            false => file.as_path(),
+        };
+        let program = parse_source(file, source)?;
+
+        let names: HashSet<&str> = program
+            .routines
+            .keys()
+            .chain(program.constants.keys())
+            .chain(program.macros.keys())
+            .copied()
+            .collect();
+        for name in names {
+            if let Some(first) = origins.insert(name, file) {
+                return Err(CauchemarError::DuplicateDefinition {
+                    name: name.to_string(),
+                    first: first.to_path_buf(),
+                    second: file.to_path_buf(),
+                });
+            }
+        }
+        for name in program.routines.keys() {
+            routines.insert(name, (file.display().to_string(), source.as_str()));
+        }
+
+        match &mut merged {
+            None => merged = Some(program),
+            Some(merged) => {
+                merged.routines.extend(program.routines);
+                merged.constants.extend(program.constants);
+                merged.macros.extend(program.macros);
+                merged.spans.extend(program.spans);
+                merged.layout.extend(program.layout);
+            }
+        }
+    }
+
+    Ok((merged.expect("there is always at least one file"), routines))
+}
+
+/// Run `entry` for the warmup runs and then the measured ones, from the same
+/// starting point each time, and report how long they took.
+fn bench_file(
+    file: &Path,
+    entry: &str,
+    iterations: u32,
+    warmup: u32,
+    format: OutputFormat,
+) -> Result<(), CauchemarError> {
+    let source = read_source(file)?;
+    let program = parse_source(file, &source)?;
+    let mut vm = CauchemarVMBuilder::new().entry(entry).build(program)?;
+
+    let initial = vm.snapshot();
+    let mut timings = Vec::new();
+    for run in 0..warmup + iterations {
+        vm.restore(initial.clone());
+        let start = Instant::now();
+        vm.run()?;
+        if run >= warmup {
+            timings.push(start.elapsed());
+        }
+    }
+
+    report_benchmark(&timings, vm.steps, matches!(format, OutputFormat::Json));
+    Ok(())
+}
+
+/// Format a source file, returns whether it was already formatted.
+fn format_file(file: &Path, width: usize, write: bool, check: bool) -> Result<bool, CauchemarError> {
+    let source = read_source(file)?;
+    let program = parse_source(file, &source)?;
+
+    let formatted = formatter::SourceFormatter::new(width).program(&program);
+    let unchanged = formatted == source;
+
+    if write && !unchanged {
+        if let Err(e) = fs::write(file, &formatted) {
+            return Err(CauchemarError::Io(file.to_path_buf(), e));
+        }
+    } else if !write && !check {
+        print!("{}", formatted);
+    }
+
+    Ok(unchanged)
+}
+
+/// Name used in diagnostics for programs read from standard input.
+pub(crate) const STDIN_NAME: &str = "<stdin>";
+
+/// What runtime errors call the code given to --eval.
+const EVAL_NAME: &str = "<eval>";
+
+pub(crate) const STDOUT_NAME: &str = "<stdout>";
+
+pub(crate) const STDERR_NAME: &str = "<stderr>";
+
+fn read_stdin() -> Result<String, CauchemarError> {
+    let mut source = String::new();
+    match io::stdin().read_to_string(&mut source) {
+        Ok(_) => Ok(source),
+        Err(e) => Err(CauchemarError::Io(PathBuf::from(STDIN_NAME), e)),
+    }
+}
+
+/// How long a change has to be left alone before running again, editors
+/// often write a file several times when saving it.
+const WATCH_DEBOUNCE: Duration = Duration::from_millis(100);
+
+/// Used when the platform can't notify about changes.
+const WATCH_POLL_INTERVAL: Duration = Duration::from_millis(500);
+
+/// Run the program, then again every time one of its files changes, until
+/// interrupted.
+fn watch(cli: &Cli) -> ExitCode {
+    loop {
+        let since_epoch = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+        print!("\x1b[2J\x1b[H");
+        println!(
+            "=== {:02}:{:02}:{:02} UTC ===",
+            since_epoch / 3600 % 24,
+            since_epoch / 60 % 60,
+            since_epoch % 60
+        );
+
+        // Files given that can't be read yet are watched for as well.
+        let given = cli.load.iter().chain(&cli.files);
+        let mut watched: HashSet<PathBuf> = given.filter_map(|file| watched_path(file)).collect();
+        run_cli(cli, &mut watched);
+        if let Err(e) = wait_for_change(&watched) {
+            return report(e);
+        }
+    }
+}
+
+/// The absolute path of `file`, even when it doesn't exist as long as the
+/// directory it would be in does.
+fn watched_path(file: &Path) -> Option<PathBuf> {
+    if let Ok(path) = fs::canonicalize(file) {
+        return Some(path);
+    }
+    let directory = match file.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent,
+        _ => Path::new("."),
+    };
+    Some(fs::canonicalize(directory).ok()?.join(file.file_name()?))
+}
+
+/// Block until one of `files` is written, created or removed.
+fn wait_for_change(files: &HashSet<PathBuf>) -> Result<(), CauchemarError> {
+    let (sender, receiver) = mpsc::channel();
+    // Directories are watched rather than the files themselves, as editors
+    // often save by replacing the file.
+    let directories: HashSet<&Path> = files.iter().filter_map(|file| file.parent()).collect();
+    if directories.is_empty() {
+        // Nothing would ever wake us up.
+        return Err(CauchemarError::Watch(notify::Error::path_not_found()));
+    }
+    let _watcher = match watch_directories(&directories, notify::recommended_watcher(sender.clone())) {
+        Ok(watcher) => watcher,
+        Err(_) => {
+            let config = notify::Config::default().with_poll_interval(WATCH_POLL_INTERVAL);
+            watch_directories(&directories, notify::PollWatcher::new(sender, config))
+                .map_err(CauchemarError::Watch)?
+        }
+    };
+
+    let changed = |event: notify::Event| {
+        !matches!(event.kind, notify::EventKind::Access(_)) && event.paths.iter().any(|path| files.contains(path))
+    };
+    for event in &receiver {
+        if changed(event.map_err(CauchemarError::Watch)?) {
+            while receiver.recv_timeout(WATCH_DEBOUNCE).is_ok() {}
+            return Ok(());
+        }
+    }
+    Ok(())
+}
+
+fn watch_directories<W: notify::Watcher + 'static>(
+    directories: &HashSet<&Path>,
+    watcher: notify::Result<W>,
+) -> notify::Result<Box<dyn notify::Watcher>> {
+    let mut watcher = watcher?;
+    for directory in directories {
+        watcher.watch(directory, notify::RecursiveMode::NonRecursive)?;
+    }
+    Ok(Box::new(watcher))
+}
+
+/// The `cauchemar` command line.
+pub fn main() -> ExitCode {
+    let cli = Cli::parse();
+    style::init(cli.color.enabled(io::stdout().is_terminal()), cli.color.enabled(io::stderr().is_terminal()));
+
+    // Errors in the program are reported through CauchemarError, anything
+    // that panics is a bug in the interpreter, whatever the command.
+    panic::set_hook(Box::new(|info| {
+        let location = info.location().map(|l| l.to_string());
+        PANIC_LOCATION.with(|p| *p.borrow_mut() = location);
+    }));
+    match panic::catch_unwind(AssertUnwindSafe(|| dispatch(cli))) {
+        Ok(code) => code,
+        Err(payload) => report_internal_error(None, payload),
+    }
+}
+
+fn dispatch(mut cli: Cli) -> ExitCode {
+    match &cli.command {
+        Some(Command::Fmt { file, write, check, width }) => {
+            return match format_file(file, *width, *write, *check) {
+                Ok(false) if *check => ExitCode::FAILURE,
+                Ok(_) => ExitCode::SUCCESS,
+                Err(e) => report(e),
+            };
+        }
+        Some(Command::Disasm { file, output }) => {
+            return match casm_file(file, output.as_deref()) {
+                Ok(()) => ExitCode::SUCCESS,
+                Err(e) => report(e),
+            };
+        }
+        Some(Command::Bench { file, entry, iterations, warmup, format }) => {
+            return match bench_file(file, entry, *iterations, *warmup, *format) {
+                Ok(()) => ExitCode::SUCCESS,
+                Err(e) => report(e),
+            };
+        }
+        Some(Command::Test { file, filter, examples, coverage }) => {
+            let result = match (file, examples) {
+                (_, Some(examples)) => test_examples(examples, filter.as_deref()),
+                (Some(file), None) => test_file(file, filter.as_deref(), *coverage),
+                (None, None) => unreachable!("clap requires one of them"),
+            };
+            return match result {
+                Ok(true) => ExitCode::SUCCESS,
+                Ok(false) => ExitCode::from(TEST_FAILURE_EXIT_CODE),
+                Err(e) => report(e),
+            };
+        }
+        Some(Command::Debug { file }) => {
+            return match debug_file(file) {
+                Ok(status) => ExitCode::from(status),
+                Err(e) => report(e),
+            };
+        }
+        Some(Command::Dap) => {
+            return match dap::serve() {
+                Ok(()) => ExitCode::SUCCESS,
+                Err(e) => report(CauchemarError::Io(PathBuf::from(STDIN_NAME), e)),
+            };
+        }
+        #[cfg(feature = "lsp")]
+        Some(Command::Lsp) => {
+            crate::lsp::serve();
+            return ExitCode::SUCCESS;
+        }
+        None => {}
+    }
+    if cli.lines {
+        if cli.eval.is_none() && cli.files.is_empty() {
+            Cli::command()
+                .error(ErrorKind::MissingRequiredArgument, "--lines needs a source file or --eval")
+                .exit();
+        }
+        if cli.files.iter().any(|file| file.as_os_str() == "-") {
+            Cli::command()
+                .error(ErrorKind::ArgumentConflict, "--lines reads standard input, the program can't")
+                .exit();
+        }
+    }
+
+    if cli.eval.is_none() && cli.files.is_empty() {
+        if io::stdin().is_terminal() {
+            Cli::command()
+                .error(ErrorKind::MissingRequiredArgument, "a source file, \"-\" or --eval is required")
+                .exit();
+        }
+        cli.files.push(PathBuf::from("-"));
+    }
+
+    if cli.watch {
+        if cli.files.iter().any(|file| file.as_os_str() == "-") {
+            Cli::command()
+                .error(ErrorKind::ArgumentConflict, "--watch needs a source file, not standard input")
+                .exit();
+        }
+        return watch(&cli);
+    }
+    run_cli(&cli, &mut HashSet::new())
+}
+
+/// Run the program as asked on the command line, adding every source file it
+/// read to `watched`.
+fn run_cli(cli: &Cli, watched: &mut HashSet<PathBuf>) -> ExitCode {
+    // Libraries go first, a file that was already read, whether loaded or
+    // given on its own, is skipped instead of defining everything twice.
+    let mut files = Vec::new();
+    let mut canonical = Vec::new();
+    for file in cli.load.iter().chain(&cli.files) {
+        match fs::canonicalize(file) {
+            Ok(path) if canonical.contains(&path) => continue,
+            Ok(path) => canonical.push(path),
+            Err(_) => {}
+        }
+        files.push(file.clone());
+    }
+    watched.extend(canonical.iter().cloned());
+
+    let fail = |error: CauchemarError| {
+        match cli.json_errors {
+            true => report_json(error.diagnostic(None)),
+            false => eprintln!("{}", style::error(&error)),
+        }
+        ExitCode::from(error.exit_code())
+    };
+
+    let mut sources = Vec::new();
+    for file in &files {
+        let source = match file.as_os_str() == "-" {
+            true => read_stdin(),
+            false => read_source(file),
+        };
+        match source {
+            Ok(source) => sources.push(source),
+            Err(e) => return fail(e),
+        }
+    }
+    // Coverage can only show lines when everything came from one place.
+    let single_source = match (&sources[..], &cli.eval) {
+        ([source], None) => Some(source.as_str()),
+        ([], Some(code)) => Some(code.as_str()),
+        _ => None,
+    };
+
+    if cli.print_parse_tree {
+        let inputs: Vec<(&str, Rule)> = match &cli.eval {
+            Some(code) => vec![(code, Rule::snippet)],
+            None => sources.iter().map(|source| (source.as_str(), Rule::program)).collect(),
+        };
+        for (source, rule) in inputs {
+            match CauchemarParser::parse(rule, source) {
+                Ok(pairs) => pairs.for_each(|pair| print_parse_tree(pair, 0)),
+                Err(e) => return fail(CauchemarError::Parse(e)),
+            }
+        }
+        return ExitCode::SUCCESS;
+    }
+
+    if cli.highlight_tokens {
+        let inputs: Vec<(&str, Rule)> = match &cli.eval {
+            Some(code) => vec![(code, Rule::snippet)],
+            None => sources.iter().map(|source| (source.as_str(), Rule::program)).collect(),
+        };
+        // One array per file, in the order they were given.
+        for (source, rule) in inputs {
+            match highlight::tokens(source, rule) {
+                Ok(tokens) => println!("{}", serde_json::to_string_pretty(&tokens).expect("tokens always serialize")),
+                Err(e) => return fail(CauchemarError::Parse(e)),
+            }
+        }
+        return ExitCode::SUCCESS;
+    }
+
+    let parsed = match &cli.eval {
+        Some(code) => match parse_cauchemar_snippet(code) {
+            Ok(snippet) if files.is_empty() => {
+                let origins = snippet.routines.keys().map(|name| (*name, (EVAL_NAME.to_string(), code.as_str())));
+                Ok((origins.collect(), snippet))
+            }
+            Ok(snippet) => parse_files(&files, &sources).map(|(mut program, mut origins)| {
+                origins.extend(snippet.routines.keys().map(|name| (*name, (EVAL_NAME.to_string(), code.as_str()))));
+                program.routines.extend(snippet.routines);
+                program.constants.extend(snippet.constants);
+                program.spans.extend(snippet.spans);
+                program.macros.extend(snippet.macros);
+                program.layout.extend(snippet.layout);
+                (origins, program)
+            }),
+            Err(e) => Err(CauchemarError::Parse(e)),
+        },
+        None => parse_files(&files, &sources).map(|(program, origins)| (origins, program)),
+    };
+    let (origins, program) = match parsed {
+        Ok(parsed) => parsed,
+        Err(e) => return fail(e),
+    };
+
+    if cli.dump_ast {
+        match cli.format {
+            OutputFormat::Text => dump_ast(&program),
+            OutputFormat::Json => {
+                println!("{}", serde_json::to_string_pretty(&program).expect("the AST always serializes"))
+            }
+        }
+        return ExitCode::SUCCESS;
+    }
+
+    if cli.print_ast {
+        print!("{}", program.sexp());
+        return ExitCode::SUCCESS;
+    }
+
+    if cli.dump_callgraph {
+        let mut routines = HashMap::new();
+        compile_user_routines(&mut routines, program);
+        print!("{}", callgraph::dot(&routines, &cli.entry));
+        return ExitCode::SUCCESS;
+    }
+
+    if let Some(name) = &cli.dump_cfg {
+        let mut routines = HashMap::new();
+        let names: Vec<_> = program.routines.keys().copied().collect();
+        compile_user_routines(&mut routines, program);
+        let Some(CauchemarVMRoutine::User(instructions)) = routines.get(name.as_str()) else {
+            return fail(CauchemarError::MissingRoutine {
+                name: name.clone(),
+                suggestion: closest_name(name, names.into_iter()).map(str::to_string),
+            });
+        };
+        match cli.cfg_format {
+            CfgFormat::Text => print!("{}", cfg::text(instructions)),
+            CfgFormat::Dot => print!("{}", cfg::dot(name, instructions)),
+        }
+        return ExitCode::SUCCESS;
+    }
+
+    if let Some(output) = &cli.output_asm {
+        return match fs::write(output, asm_output::asm(&program)) {
+            Ok(()) => ExitCode::SUCCESS,
+            Err(e) => fail(CauchemarError::FileAccess(output.clone(), e)),
+        };
+    }
+
+    let policy = if cli.sandbox {
+        SandboxPolicy::Restricted
+    } else if !cli.allow_native.is_empty() {
+        // Only done once per run, the names live as long as the VM anyway.
+        let allowed = cli.allow_native.iter().map(|name| &*Box::leak(name.clone().into_boxed_str()));
+        SandboxPolicy::Custom(allowed.collect())
+    } else {
+        SandboxPolicy::Permissive
+    };
+
+    let builder = CauchemarVMBuilder::new()
+        .entry(&cli.entry)
+        .max_call_depth(cli.max_depth)
+        .max_stack_depth(cli.stack_limit)
+        .instruction_budget(cli.max_steps)
+        .strict_equality(cli.strict_equality)
+        .strict_returns(cli.strict_returns)
+        .trace(cli.trace)
+        .args(cli.args.clone())
+        .filesystem(!cli.no_fs)
+        .exec(!cli.no_exec)
+        .sleep(!cli.no_sleep && !cli.sandbox)
+        .fixed_clock(cli.sandbox)
+        .sandbox(policy);
+
+    if cli.check {
+        let errors = builder.check(program);
+        for error in &errors {
+            match cli.json_errors {
+                true => report_json(error.diagnostic(None)),
+                false => eprintln!("{}", style::error(error)),
+            }
+        }
+        return match errors.first() {
+            Some(error) => ExitCode::from(error.exit_code()),
+            None => ExitCode::SUCCESS,
+        };
+    }
+
+    let maps = cli.coverage.then(|| coverage::source_maps(&program));
+    let spans = source_spans(&program);
+    let mut vm = match builder.build(program) {
+        Ok(vm) => vm,
+        Err(e) => return fail(e),
+    };
+    vm.loaded_files.extend(canonical);
+
+    if cli.disassemble {
+        print!("{}", vm.disassemble());
+        return ExitCode::SUCCESS;
+    }
+
+    if let Some(output) = &cli.output_c {
+        let runtime = output.with_file_name(c_output::RUNTIME_NAME);
+        let written = fs::write(output, c_output::c(&vm.routines, &cli.entry))
+            .map_err(|e| CauchemarError::FileAccess(output.clone(), e))
+            .and_then(|()| fs::write(&runtime, c_output::RUNTIME).map_err(|e| CauchemarError::FileAccess(runtime, e)));
+        return match written {
+            Ok(()) => ExitCode::SUCCESS,
+            Err(e) => fail(e),
+        };
+    }
+
+    if let Some(output) = &cli.output_wasm {
+        return match fs::write(output, wasm_output::wasm(&vm.routines)) {
+            Ok(()) => ExitCode::SUCCESS,
+            Err(e) => fail(CauchemarError::FileAccess(output.clone(), e)),
+        };
+    }
+
+    #[cfg(feature = "debug")]
+    {
+        println!("!!! COMPILER OUTPUT !!!");
+        print!("{}", vm.disassemble());
+        println!("!!! VM START !!!");
+    }
+
+    let initial = vm.snapshot();
+    let mut timings = Vec::new();
+    let mut profile = HashMap::new();
+    let mut executed = HashMap::new();
+    let mut max_depth = 0;
+    let started = Instant::now();
+    let result = panic::catch_unwind(AssertUnwindSafe(|| -> Result<u8, CauchemarError> {
+        if cli.lines {
+            return run_lines(&mut vm, &initial, cli.time.then_some(&mut max_depth));
+        }
+        let mut status = 0;
+        for _ in 0..cli.benchmark.unwrap_or(1) {
+            vm.restore(initial.clone());
+            let start = Instant::now();
+            status = match (cli.profile, cli.time, cli.debug_step) {
+                _ if cli.coverage => coverage_vm(&mut vm, &mut executed)?,
+                (true, _, _) => profile_vm(&mut vm, &mut profile)?,
+                (_, true, _) => timed_vm(&mut vm, &mut max_depth)?,
+                (_, _, true) => step_vm(&mut vm)?,
+                _ => vm.run()?,
+            };
+            timings.push(start.elapsed());
+        }
+        Ok(status)
+    }));
+    watched.extend(vm.loaded_files.iter().cloned());
+
+    if cli.time && result.is_ok() {
+        eprintln!(
+            "elapsed: {:.1}ms, instructions: {}, max stack depth: {}",
+            started.elapsed().as_secs_f64() * 1000.0,
+            vm.steps,
+            max_depth
+        );
+    }
+
+    if let (Some(maps), Ok(_)) = (&maps, &result) {
+        coverage::report(maps, &executed, single_source);
+    }
+
+    if cli.profile && result.is_ok() {
+        if let Err(e) = report_profile(&profile, cli.profile_out.as_deref()) {
+            return fail(e);
+        }
+    }
+
+    match result {
+        Ok(Ok(status)) => {
+            let dump = match (cli.quiet, cli.print_stack) {
+                (true, _) => StackDump::Quiet,
+                (_, true) => StackDump::Print,
+                _ => StackDump::Labeled,
+            };
+            print_remaining(&vm, dump);
+            if cli.benchmark.is_some() {
+                report_benchmark(&timings, vm.steps, cli.benchmark_json);
+            }
+            ExitCode::from(status)
+        }
+        Ok(Err(e)) => {
+            let location = error_span(&vm, &spans)
+                .and_then(|(routine, span)| origins.get(routine).map(|(name, source)| (name, *source, span)));
+            match cli.json_errors {
+                true => {
+                    let diagnostic = e.diagnostic(Some(vm.backtrace()));
+                    match location {
+                        Some((name, source, span)) => report_json(diagnostic.at(name, source, span)),
+                        None => report_json(diagnostic),
+                    }
+                }
+                false => {
+                    match location {
+                        Some((name, source, span)) => snippet::emit(&e, name, source, span),
+                        None => eprintln!("{}", style::error(&e)),
+                    }
+                    print_backtrace(&vm);
+                }
+            }
+            ExitCode::from(e.exit_code())
+        }
+        Err(payload) => report_internal_error(Some(&vm), payload),
+    }
+}
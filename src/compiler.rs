@@ -0,0 +1,268 @@
+//! Compiling routines into VM instructions.
+
+use std::{collections::HashMap, sync::Arc};
+
+use crate::{
+    CauchemarAST, CauchemarError, CauchemarProgram, CauchemarSpan, CauchemarVMInstruction, CauchemarVMRoutine,
+    CauchemarVMValue,
+};
+
+pub(crate) fn compile_routine(
+    instructions: &mut Vec<CauchemarVMInstruction>,
+    routine: Vec<CauchemarAST>,
+    constants: &HashMap<&str, CauchemarAST>,
+    macros: &HashMap<&str, Vec<CauchemarAST>>,
+) {
+    compile_mapped(instructions, routine, constants, macros, &mut CauchemarSourceMap::default());
+}
+
+/// Source spans of the instructions of a routine as it is compiled.
+#[derive(Default)]
+struct CauchemarSourceMap<'l> {
+    /// From CauchemarProgram::spans, nothing is recorded when empty.
+    commands: &'l [CauchemarSpan],
+    next: usize,
+    /// Span of every instruction so far, the default one for those the
+    /// compiler adds on its own like the jumps of IF.
+    spans: Vec<CauchemarSpan>,
+}
+
+impl<'l> CauchemarSourceMap<'l> {
+    fn new(commands: &'l [CauchemarSpan]) -> Self {
+        CauchemarSourceMap { commands, next: 0, spans: Vec::new() }
+    }
+
+    fn next_span(&mut self) -> CauchemarSpan {
+        let span = self.commands.get(self.next).copied().unwrap_or_default();
+        self.next += 1;
+        span
+    }
+
+    /// Give `span` to the instructions up to `len` that don't have one yet.
+    fn mark(&mut self, len: usize, span: CauchemarSpan) {
+        if !self.commands.is_empty() {
+            self.spans.resize(len, span);
+        }
+    }
+}
+
+/// Span of every instruction of every user routine, as they are compiled by
+/// compile_user_routines.
+pub(crate) fn source_spans(program: &CauchemarProgram) -> HashMap<Arc<str>, Vec<CauchemarSpan>> {
+    let mut maps = HashMap::new();
+    for (name, routine) in &program.routines {
+        let spans = program.spans.get(name).map_or(&[][..], Vec::as_slice);
+        let mut map = CauchemarSourceMap::new(spans);
+        let mut instructions = Vec::new();
+        compile_mapped(&mut instructions, routine.clone(), &program.constants, &program.macros, &mut map);
+        map.spans.resize(instructions.len(), CauchemarSpan::default());
+        // The Return added after the routine.
+        map.spans.push(CauchemarSpan::default());
+        maps.insert((*name).into(), map.spans);
+    }
+    maps
+}
+
+/// compile_routine, recording the span of each instruction in `map`.
+/// Expansions of constants and macros get the span of the name they are
+/// used by.
+fn compile_mapped(
+    instructions: &mut Vec<CauchemarVMInstruction>,
+    routine: Vec<CauchemarAST>,
+    constants: &HashMap<&str, CauchemarAST>,
+    macros: &HashMap<&str, Vec<CauchemarAST>>,
+    map: &mut CauchemarSourceMap,
+) {
+    for command in routine {
+        let span = map.next_span();
+        match command {
+            CauchemarAST::Number(n, _) => instructions.push(CauchemarVMInstruction::Push(
+                CauchemarVMValue::Number(n),
+            )),
+            CauchemarAST::BigInt(n, _) => instructions.push(CauchemarVMInstruction::Push(
+                CauchemarVMValue::BigInt(n),
+            )),
+            CauchemarAST::Bool(b) => instructions.push(CauchemarVMInstruction::Push(
+                CauchemarVMValue::Bool(b),
+            )),
+            CauchemarAST::String(s, _) => instructions.push(CauchemarVMInstruction::Push(
+                CauchemarVMValue::String(s.into()),
+            )),
+            CauchemarAST::Identifier(s) => match (constants.get(s), macros.get(s)) {
+                (Some(value), _) => compile_routine(instructions, vec![value.clone()], constants, macros),
+                (_, Some(body)) => compile_routine(instructions, body.clone(), constants, macros),
+                (None, None) => instructions.push(CauchemarVMInstruction::Call(s.into())),
+            },
+            CauchemarAST::Params(names) => {
+                for name in names.into_iter().rev() {
+                    instructions.push(CauchemarVMInstruction::Store(name.into()));
+                }
+            }
+            CauchemarAST::Param(name) => instructions.push(CauchemarVMInstruction::Load(name.into())),
+            CauchemarAST::Returns(count) => instructions.push(CauchemarVMInstruction::Returns(count)),
+            CauchemarAST::ReturnWith => instructions.push(CauchemarVMInstruction::ReturnWith),
+            CauchemarAST::If(then, otherwise) => {
+                instructions.push(CauchemarVMInstruction::JumpIfFalse(0));
+                let false_jump_index = instructions.len() - 1;
+
+                map.mark(instructions.len(), CauchemarSpan::default());
+                compile_mapped(instructions, then, constants, macros, map);
+                instructions.push(CauchemarVMInstruction::Jump(0));
+                let end_jump_index = instructions.len() - 1;
+
+                let false_jump = end_jump_index + 1;
+                map.mark(instructions.len(), CauchemarSpan::default());
+                compile_mapped(instructions, otherwise, constants, macros, map);
+
+                instructions.push(CauchemarVMInstruction::Nop);
+                let end_jump = instructions.len() - 1;
+                map.mark(instructions.len(), CauchemarSpan::default());
+
+                instructions[false_jump_index] = CauchemarVMInstruction::JumpIfFalse(false_jump);
+                instructions[end_jump_index] = CauchemarVMInstruction::Jump(end_jump);
+            }
+            CauchemarAST::Switch(value, cases, default) => {
+                compile_mapped(instructions, value, constants, macros, map);
+
+                let mut end_jump_indices = Vec::new();
+                for (case_value, body) in cases {
+                    instructions.push(CauchemarVMInstruction::Call("DUP".into()));
+                    map.mark(instructions.len(), CauchemarSpan::default());
+                    compile_mapped(instructions, case_value, constants, macros, map);
+                    instructions.push(CauchemarVMInstruction::Call("EQUALS".into()));
+                    instructions.push(CauchemarVMInstruction::JumpIfFalse(0));
+                    let false_jump_index = instructions.len() - 1;
+
+                    instructions.push(CauchemarVMInstruction::Call("DROP".into()));
+                    map.mark(instructions.len(), CauchemarSpan::default());
+                    compile_mapped(instructions, body, constants, macros, map);
+                    instructions.push(CauchemarVMInstruction::Jump(0));
+                    end_jump_indices.push(instructions.len() - 1);
+
+                    instructions[false_jump_index] = CauchemarVMInstruction::JumpIfFalse(instructions.len());
+                }
+
+                instructions.push(CauchemarVMInstruction::Call("DROP".into()));
+                map.mark(instructions.len(), CauchemarSpan::default());
+                compile_mapped(instructions, default, constants, macros, map);
+
+                instructions.push(CauchemarVMInstruction::Nop);
+                let end_jump = instructions.len() - 1;
+                map.mark(instructions.len(), CauchemarSpan::default());
+                for index in end_jump_indices {
+                    instructions[index] = CauchemarVMInstruction::Jump(end_jump);
+                }
+            }
+            CauchemarAST::While(body) => {
+                let start_index = instructions.len();
+                compile_mapped(instructions, body, constants, macros, map);
+                instructions.push(CauchemarVMInstruction::JumpIfFalse(0));
+                let false_jump_index = instructions.len() - 1;
+                instructions.push(CauchemarVMInstruction::Jump(start_index));
+
+                instructions.push(CauchemarVMInstruction::Nop);
+                let false_jump = instructions.len() - 1;
+                map.mark(instructions.len(), CauchemarSpan::default());
+
+                instructions[false_jump_index] = CauchemarVMInstruction::JumpIfFalse(false_jump);
+            }
+            CauchemarAST::Thunk(body) => {
+                let mut thunk = Vec::new();
+                compile_routine(&mut thunk, body, constants, macros);
+                thunk.push(CauchemarVMInstruction::Return);
+                instructions.push(CauchemarVMInstruction::Push(CauchemarVMValue::Thunk(thunk)));
+            }
+            CauchemarAST::Add => instructions.push(CauchemarVMInstruction::Add),
+            CauchemarAST::Sub => instructions.push(CauchemarVMInstruction::Sub),
+            CauchemarAST::Mul => instructions.push(CauchemarVMInstruction::Mul),
+            CauchemarAST::Div => instructions.push(CauchemarVMInstruction::Div),
+            CauchemarAST::Comment { .. } => {}
+        }
+        map.mark(instructions.len(), span);
+    }
+}
+
+/// Compile every routine of the program into the routine table, replacing
+/// routines of the same name. Returns the names of the compiled routines.
+pub(crate) fn compile_user_routines(
+    routines: &mut HashMap<Arc<str>, CauchemarVMRoutine>,
+    program: CauchemarProgram,
+) -> Vec<Arc<str>> {
+    let mut names = Vec::new();
+
+    for (name, routine) in program.routines {
+        let mut compiled_routine = Vec::new();
+        compile_routine(&mut compiled_routine, routine, &program.constants, &program.macros);
+        compiled_routine.push(CauchemarVMInstruction::Return);
+
+        let name: Arc<str> = name.into();
+        routines.insert(name.clone(), CauchemarVMRoutine::User(Arc::new(compiled_routine)));
+        names.push(name);
+    }
+
+    names
+}
+
+/// Collect every call to a routine that doesn't exist, in program order.
+pub(crate) fn unknown_calls(
+    routines: &HashMap<Arc<str>, CauchemarVMRoutine>,
+    instructions: &[CauchemarVMInstruction],
+    unknown: &mut Vec<Arc<str>>,
+) {
+    for instruction in instructions {
+        match instruction {
+            CauchemarVMInstruction::Call(name) if !routines.contains_key(name) => unknown.push(name.clone()),
+            CauchemarVMInstruction::Push(CauchemarVMValue::Thunk(body)) => unknown_calls(routines, body, unknown),
+            _ => {}
+        }
+    }
+}
+
+pub(crate) fn check_calls(
+    routines: &HashMap<Arc<str>, CauchemarVMRoutine>,
+    instructions: &[CauchemarVMInstruction],
+) -> Result<(), CauchemarError> {
+    let mut unknown = Vec::new();
+    unknown_calls(routines, instructions, &mut unknown);
+    match unknown.first() {
+        Some(name) => Err(CauchemarError::UnknownRoutine(name.to_string())),
+        None => Ok(()),
+    }
+}
+
+/// Make sure every routine called from the given routines exists.
+pub(crate) fn check_routines(routines: &HashMap<Arc<str>, CauchemarVMRoutine>, names: &[Arc<str>]) -> Result<(), CauchemarError> {
+    for name in names {
+        if let Some(CauchemarVMRoutine::User(instructions)) = routines.get(name) {
+            check_calls(routines, instructions)?;
+        }
+    }
+    Ok(())
+}
+
+/// Levenshtein distance between two names.
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut previous: Vec<usize> = (0..=b.len()).collect();
+
+    for (i, ca) in a.chars().enumerate() {
+        let mut current = vec![i + 1];
+        for (j, cb) in b.iter().enumerate() {
+            let substitution = previous[j] + usize::from(ca != *cb);
+            current.push(substitution.min(previous[j + 1] + 1).min(current[j] + 1));
+        }
+        previous = current;
+    }
+
+    previous[b.len()]
+}
+
+/// The name closest to `name` if it's close enough to be a likely typo.
+pub(crate) fn closest_name<'a>(name: &str, names: impl Iterator<Item = &'a str>) -> Option<&'a str> {
+    let threshold = (name.len() / 3).max(1);
+    names
+        .map(|candidate| (edit_distance(name, candidate), candidate))
+        .filter(|(distance, _)| *distance <= threshold)
+        .min()
+        .map(|(_, candidate)| candidate)
+}
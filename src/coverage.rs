@@ -0,0 +1,73 @@
+//! Source coverage for --coverage.
+//!
+//! The instructions that ran are mapped back to the lines they were compiled
+//! from through CauchemarProgram::spans. Those the compiler adds on its own,
+//! like the jumps of IF and WHILE, don't count either way.
+
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    sync::Arc,
+};
+
+use crate::{display, source_spans, CauchemarProgram};
+
+/// Indices of the instructions that ran, by routine.
+pub type Executed = HashMap<Arc<str>, HashSet<usize>>;
+
+/// Line of every instruction of every routine, 0 for those that don't count.
+pub fn source_maps(program: &CauchemarProgram) -> HashMap<Arc<str>, Vec<usize>> {
+    source_spans(program)
+        .into_iter()
+        .map(|(name, spans)| (name, spans.iter().map(|span| span.line).collect()))
+        .collect()
+}
+
+/// Print to stderr how much of each routine ran and, given the source of
+/// the only file the routines came from, every line of it marked with
+/// whether it ran: `-` for lines without code, `#####` for those where
+/// nothing did.
+pub fn report(maps: &HashMap<Arc<str>, Vec<usize>>, executed: &Executed, source: Option<&str>) {
+    let none = HashSet::new();
+    let mut routines: Vec<_> = maps.iter().collect();
+    routines.sort_by_key(|(name, _)| *name);
+
+    let headers: Vec<String> = ["routine", "covered", "total", "%"].map(String::from).into();
+    let mut rows = Vec::new();
+    // Whether anything on each line ran.
+    let mut lines: BTreeMap<usize, bool> = BTreeMap::new();
+    let (mut all_covered, mut all_total) = (0, 0);
+    for (name, map) in routines {
+        let ran = executed.get(name).unwrap_or(&none);
+        let (mut covered, mut total) = (0, 0);
+        for (i, line) in map.iter().enumerate().filter(|(_, line)| **line != 0) {
+            total += 1;
+            covered += ran.contains(&i) as usize;
+            *lines.entry(*line).or_default() |= ran.contains(&i);
+        }
+        rows.push(vec![name.to_string(), covered.to_string(), total.to_string(), percent(covered, total)]);
+        all_covered += covered;
+        all_total += total;
+    }
+    rows.push(vec!["total".into(), all_covered.to_string(), all_total.to_string(), percent(all_covered, all_total)]);
+    eprint!("{}", display::format_table(&headers, &rows));
+
+    let Some(source) = source else {
+        return;
+    };
+    eprintln!();
+    for (i, text) in source.lines().enumerate() {
+        let mark = match lines.get(&(i + 1)) {
+            None => "-",
+            Some(true) => "+",
+            Some(false) => "#####",
+        };
+        eprintln!("{:>5}:{:>5}: {}", mark, i + 1, text);
+    }
+}
+
+fn percent(covered: usize, total: usize) -> String {
+    match total {
+        0 => "-".into(),
+        _ => format!("{:.1}", covered as f64 / total as f64 * 100.0),
+    }
+}
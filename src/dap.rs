@@ -0,0 +1,545 @@
+//! Debug adapter for `cauchemar dap`, speaking the Debug Adapter Protocol
+//! over stdin and stdout. Launches a single file, stops at breakpoints set by
+//! line, steps by line and shows the call frames along with the data stack
+//! and the parameters of each frame as variables.
+//!
+//! Lines come from the same map as --coverage, instructions the compiler
+//! adds on its own have none and are never stopped at.
+
+use std::{
+    collections::{HashMap, HashSet},
+    io::{self, BufRead, Write},
+    path::PathBuf,
+    sync::{Arc, Mutex},
+};
+
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::{
+    coverage, parse_source, read_source, CauchemarError, CauchemarVM, CauchemarVMBuilder, CauchemarVMFrame,
+    CauchemarVMValue,
+};
+
+/// There is a single thread to report, the one running the program.
+const THREAD_ID: i64 = 1;
+
+/// Variables reference of the data stack, those of the parameters of a
+/// frame are its id plus this.
+const STACK_REFERENCE: usize = 1;
+
+#[derive(Deserialize)]
+struct Request {
+    seq: i64,
+    command: String,
+    #[serde(default)]
+    arguments: Value,
+}
+
+#[derive(Serialize)]
+struct Response<'a> {
+    seq: i64,
+    #[serde(rename = "type")]
+    kind: &'static str,
+    request_seq: i64,
+    success: bool,
+    command: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    message: Option<String>,
+    body: Value,
+}
+
+#[derive(Serialize)]
+struct Event<'a> {
+    seq: i64,
+    #[serde(rename = "type")]
+    kind: &'static str,
+    event: &'a str,
+    body: Value,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct LaunchArguments {
+    program: PathBuf,
+    #[serde(default)]
+    stop_on_entry: bool,
+    #[serde(default)]
+    args: Vec<String>,
+}
+
+#[derive(Deserialize)]
+struct SetBreakpointsArguments {
+    #[serde(default)]
+    breakpoints: Vec<SourceBreakpoint>,
+}
+
+#[derive(Deserialize)]
+struct SourceBreakpoint {
+    line: usize,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct ScopesArguments {
+    frame_id: usize,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct VariablesArguments {
+    variables_reference: usize,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct Capabilities {
+    supports_configuration_done_request: bool,
+}
+
+#[derive(Serialize)]
+struct Breakpoints {
+    breakpoints: Vec<Breakpoint>,
+}
+
+#[derive(Serialize)]
+struct Breakpoint {
+    verified: bool,
+    line: usize,
+}
+
+#[derive(Serialize)]
+struct Threads {
+    threads: Vec<Thread>,
+}
+
+#[derive(Serialize)]
+struct Thread {
+    id: i64,
+    name: &'static str,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct StackTrace {
+    stack_frames: Vec<StackFrame>,
+    total_frames: usize,
+}
+
+#[derive(Serialize)]
+struct StackFrame {
+    id: usize,
+    name: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    source: Option<Source>,
+    line: usize,
+    column: usize,
+}
+
+#[derive(Serialize)]
+struct Source {
+    path: PathBuf,
+}
+
+#[derive(Serialize)]
+struct Scopes {
+    scopes: Vec<Scope>,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct Scope {
+    name: &'static str,
+    variables_reference: usize,
+    expensive: bool,
+}
+
+#[derive(Serialize)]
+struct Variables {
+    variables: Vec<Variable>,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct Variable {
+    name: String,
+    value: String,
+    #[serde(rename = "type")]
+    kind: &'static str,
+    variables_reference: usize,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct Stopped<'a> {
+    reason: &'a str,
+    thread_id: i64,
+    all_threads_stopped: bool,
+}
+
+#[derive(Serialize)]
+struct Output {
+    category: &'static str,
+    output: String,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct Exited {
+    exit_code: u8,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct Continued {
+    all_threads_continued: bool,
+}
+
+/// Writes messages to stdout, numbering them. Shared with the program so
+/// that what it prints is sent in between as output events.
+#[derive(Clone, Default)]
+struct Connection {
+    seq: Arc<Mutex<i64>>,
+}
+
+impl Connection {
+    fn send(&self, message: impl FnOnce(i64) -> Value) {
+        let mut seq = self.seq.lock().unwrap();
+        *seq += 1;
+        let body = message(*seq).to_string();
+        let mut stdout = io::stdout().lock();
+        // Nothing to tell when the editor went away, the next read ends the
+        // session.
+        let _ = write!(stdout, "Content-Length: {}\r\n\r\n{}", body.len(), body).and_then(|()| stdout.flush());
+    }
+
+    fn respond(&self, request: &Request, result: Result<Value, String>) {
+        let (success, message, body) = match result {
+            Ok(body) => (true, None, body),
+            Err(message) => (false, Some(message), Value::Null),
+        };
+        self.send(|seq| {
+            to_value(Response {
+                seq,
+                kind: "response",
+                request_seq: request.seq,
+                success,
+                command: &request.command,
+                message,
+                body,
+            })
+        });
+    }
+
+    fn event(&self, event: &str, body: impl Serialize) {
+        let body = to_value(body);
+        self.send(|seq| to_value(Event { seq, kind: "event", event, body }));
+    }
+}
+
+/// What the program prints, sent to the editor instead of stdout or stderr,
+/// the category saying which.
+struct ProgramOutput(Connection, &'static str);
+
+impl Write for ProgramOutput {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let output = String::from_utf8_lossy(buf).into_owned();
+        self.0.event("output", Output { category: self.1, output });
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// How far to run before stopping again, breakpoints aside.
+#[derive(Clone, Copy)]
+enum Resume {
+    /// Until another line is reached, in this frame or any other.
+    StepIn,
+    /// Until another line of this frame or of a caller is reached.
+    Next,
+    /// Until the frame returns.
+    StepOut,
+    Continue,
+}
+
+struct Session {
+    connection: Connection,
+    program: PathBuf,
+    vm: Option<CauchemarVM>,
+    /// Line of every instruction of every user routine.
+    maps: HashMap<Arc<str>, Vec<usize>>,
+    breakpoints: HashSet<usize>,
+    stop_on_entry: bool,
+}
+
+impl Session {
+    /// The depth and line of the instruction about to run, if it has one.
+    fn location(&self) -> Option<(usize, usize)> {
+        let vm = self.vm.as_ref()?;
+        let (CauchemarVMFrame::Routine(name), ip) = vm.ip.last()? else {
+            return None;
+        };
+        let line = *self.maps.get(name)?.get(*ip)?;
+        (line != 0).then_some((vm.ip.len(), line))
+    }
+
+    fn launch(&mut self, arguments: LaunchArguments) -> Result<(), CauchemarError> {
+        let source = read_source(&arguments.program)?;
+        let program = parse_source(&arguments.program, &source)?;
+        self.maps = coverage::source_maps(&program);
+        self.vm = Some(
+            CauchemarVMBuilder::new()
+                .args(arguments.args)
+                .color(false)
+                .input(Box::new(io::empty()))
+                .output(Box::new(ProgramOutput(self.connection.clone(), "stdout")))
+                .error_output(Box::new(ProgramOutput(self.connection.clone(), "stderr")))
+                .build(program)?,
+        );
+        self.program = arguments.program;
+        self.stop_on_entry = arguments.stop_on_entry;
+        Ok(())
+    }
+
+    /// Run until `resume` is done, a breakpoint is reached or the program
+    /// ends, telling the editor which it was.
+    fn resume(&mut self, resume: Resume) {
+        let Some(start) = self.vm.as_ref().map(|vm| vm.ip.len()) else {
+            return;
+        };
+        let mut last = self.location();
+        loop {
+            let vm = self.vm.as_mut().unwrap();
+            match vm.step() {
+                Ok(None) => {}
+                Ok(Some(status)) => return self.exit(status),
+                Err(e) => {
+                    let output = format!("{}\n", e);
+                    self.connection.event("output", Output { category: "stderr", output });
+                    return self.exit(e.exit_code());
+                }
+            }
+
+            let Some(here) = self.location() else {
+                continue;
+            };
+            let moved = last != Some(here);
+            last = Some(here);
+            let (depth, line) = here;
+            if moved && self.breakpoints.contains(&line) {
+                return self.stopped("breakpoint");
+            }
+            let done = match resume {
+                Resume::StepIn => moved,
+                Resume::Next => moved && depth <= start,
+                Resume::StepOut => depth < start,
+                Resume::Continue => false,
+            };
+            if done {
+                return self.stopped("step");
+            }
+        }
+    }
+
+    fn stopped(&self, reason: &str) {
+        self.connection.event("stopped", Stopped { reason, thread_id: THREAD_ID, all_threads_stopped: true });
+    }
+
+    fn exit(&mut self, status: u8) {
+        self.vm = None;
+        self.connection.event("exited", Exited { exit_code: status });
+        self.connection.event("terminated", Value::Null);
+    }
+
+    fn set_breakpoints(&mut self, arguments: SetBreakpointsArguments) -> Breakpoints {
+        self.breakpoints = arguments.breakpoints.iter().map(|breakpoint| breakpoint.line).collect();
+        let breakpoints = arguments
+            .breakpoints
+            .into_iter()
+            .map(|SourceBreakpoint { line }| Breakpoint {
+                verified: self.maps.values().any(|map| map.contains(&line)),
+                line,
+            })
+            .collect();
+        Breakpoints { breakpoints }
+    }
+
+    /// The frames being run, innermost first. Frames are numbered from the
+    /// entry routine up, which is also the depth their parameters are kept
+    /// at.
+    fn stack_trace(&self) -> StackTrace {
+        let frames = self.vm.as_ref().map_or(&[][..], |vm| vm.ip.as_slice());
+        let stack_frames: Vec<StackFrame> = frames
+            .iter()
+            .enumerate()
+            .rev()
+            .map(|(i, (frame, ip))| {
+                // Callers have already moved past the call.
+                let index = if i + 1 == frames.len() { *ip } else { ip.saturating_sub(1) };
+                let line = match frame {
+                    CauchemarVMFrame::Routine(name) => {
+                        self.maps.get(name).and_then(|map| map.get(index)).copied().unwrap_or(0)
+                    }
+                    CauchemarVMFrame::Thunk(_) => 0,
+                };
+                StackFrame {
+                    id: i + 1,
+                    name: frame.to_string(),
+                    source: (line != 0).then(|| Source { path: self.program.clone() }),
+                    line,
+                    column: (line != 0) as usize,
+                }
+            })
+            .collect();
+        StackTrace { total_frames: stack_frames.len(), stack_frames }
+    }
+
+    fn scopes(&self, frame: usize) -> Scopes {
+        let scope = |name, variables_reference| Scope { name, variables_reference, expensive: false };
+        Scopes { scopes: vec![scope("Parameters", STACK_REFERENCE + frame), scope("Stack", STACK_REFERENCE)] }
+    }
+
+    fn variables(&self, reference: usize) -> Variables {
+        let Some(vm) = &self.vm else {
+            return Variables { variables: Vec::new() };
+        };
+        let variable = |name: String, value: &CauchemarVMValue| Variable {
+            name,
+            value: value.repr(),
+            kind: value.type_name(),
+            variables_reference: 0,
+        };
+        let variables = match reference.checked_sub(STACK_REFERENCE) {
+            // Top of the stack first, as `stack` in `cauchemar debug`.
+            Some(0) => vm.stack.iter().rev().enumerate().map(|(i, value)| variable(i.to_string(), value)).collect(),
+            Some(depth) => {
+                let mut parameters: Vec<Variable> = vm
+                    .locals
+                    .iter()
+                    .filter(|(frame, _)| *frame == depth)
+                    .flat_map(|(_, locals)| locals.iter().map(|(name, value)| variable(name.to_string(), value)))
+                    .collect();
+                parameters.sort_by(|a, b| a.name.cmp(&b.name));
+                parameters
+            }
+            // 0 means "nothing to expand" in DAP, it is never handed out.
+            None => Vec::new(),
+        };
+        Variables { variables }
+    }
+
+    /// Answer `request`, returns false once the editor is done debugging.
+    fn handle(&mut self, request: &Request) -> bool {
+        let arguments = request.arguments.clone();
+        let (result, then): (Result<Value, String>, Option<Resume>) = match request.command.as_str() {
+            "initialize" => {
+                self.connection.respond(request, Ok(to_value(Capabilities { supports_configuration_done_request: true })));
+                self.connection.event("initialized", Value::Null);
+                return true;
+            }
+            "launch" => match parse(arguments).and_then(|arguments| self.launch(arguments).map_err(|e| e.to_string())) {
+                Ok(()) => (Ok(Value::Null), None),
+                Err(e) => (Err(e), None),
+            },
+            "setBreakpoints" => (parse(arguments).map(|arguments| to_value(self.set_breakpoints(arguments))), None),
+            "configurationDone" => {
+                // Resuming only stops on lines it moves to, not the first.
+                let breakpoint = self.location().is_some_and(|(_, line)| self.breakpoints.contains(&line));
+                let reason = match (self.stop_on_entry, breakpoint) {
+                    (true, _) => "entry",
+                    (false, true) => "breakpoint",
+                    (false, false) => return self.respond_then(request, Ok(Value::Null), Some(Resume::Continue)),
+                };
+                self.connection.respond(request, Ok(Value::Null));
+                self.stopped(reason);
+                return true;
+            }
+            "threads" => (Ok(to_value(Threads { threads: vec![Thread { id: THREAD_ID, name: "main" }] })), None),
+            "stackTrace" => (Ok(to_value(self.stack_trace())), None),
+            "scopes" => (parse(arguments).map(|ScopesArguments { frame_id }| to_value(self.scopes(frame_id))), None),
+            "variables" => (
+                parse(arguments)
+                    .map(|VariablesArguments { variables_reference }| to_value(self.variables(variables_reference))),
+                None,
+            ),
+            "continue" => (Ok(to_value(Continued { all_threads_continued: true })), Some(Resume::Continue)),
+            "next" => (Ok(Value::Null), Some(Resume::Next)),
+            "stepIn" => (Ok(Value::Null), Some(Resume::StepIn)),
+            "stepOut" => (Ok(Value::Null), Some(Resume::StepOut)),
+            // The program only runs while a request is being answered, so it
+            // is already paused by the time this one is read.
+            "pause" => (Ok(Value::Null), None),
+            "disconnect" | "terminate" => {
+                self.connection.respond(request, Ok(Value::Null));
+                if self.vm.take().is_some() {
+                    self.connection.event("terminated", Value::Null);
+                }
+                return false;
+            }
+            command => (Err(format!("Unsupported request {}", command)), None),
+        };
+        self.respond_then(request, result, then)
+    }
+
+    fn respond_then(&mut self, request: &Request, result: Result<Value, String>, then: Option<Resume>) -> bool {
+        self.connection.respond(request, result);
+        if let Some(resume) = then {
+            self.resume(resume);
+        }
+        true
+    }
+}
+
+fn parse<T: serde::de::DeserializeOwned>(arguments: Value) -> Result<T, String> {
+    serde_json::from_value(arguments).map_err(|e| format!("Invalid arguments: {}", e))
+}
+
+fn to_value(value: impl Serialize) -> Value {
+    serde_json::to_value(value).expect("DAP messages are always valid JSON")
+}
+
+/// Read the next request, None once the editor closed stdin.
+fn read_request(input: &mut impl BufRead) -> io::Result<Option<Request>> {
+    let mut length = None;
+    loop {
+        let mut line = String::new();
+        if input.read_line(&mut line)? == 0 {
+            return Ok(None);
+        }
+        let line = line.trim_end();
+        if line.is_empty() {
+            break;
+        }
+        if let Some(value) = line.strip_prefix("Content-Length:") {
+            length = value.trim().parse().ok();
+        }
+    }
+    let Some(length) = length else {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "message without Content-Length"));
+    };
+    let mut body = vec![0; length];
+    input.read_exact(&mut body)?;
+    serde_json::from_slice(&body).map(Some).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+}
+
+/// Answer the editor until it disconnects or closes stdin.
+pub fn serve() -> io::Result<()> {
+    let mut session = Session {
+        connection: Connection::default(),
+        program: PathBuf::new(),
+        vm: None,
+        maps: HashMap::new(),
+        breakpoints: HashSet::new(),
+        stop_on_entry: false,
+    };
+    let mut input = io::stdin().lock();
+    while let Some(request) = read_request(&mut input)? {
+        if !session.handle(&request) {
+            break;
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,93 @@
+//! Line diffs for test failures.
+
+/// Lines of context kept around each change.
+const CONTEXT: usize = 3;
+
+#[derive(Clone, Copy, PartialEq)]
+enum Edit {
+    Keep,
+    Remove,
+    Add,
+}
+
+/// Diff `expected` against `actual` line by line in the unified format,
+/// empty when they are the same.
+pub fn unified(expected: &str, actual: &str) -> String {
+    let old: Vec<&str> = expected.lines().collect();
+    let new: Vec<&str> = actual.lines().collect();
+    let edits = edits(&old, &new);
+    if edits.iter().all(|(edit, _, _)| *edit == Edit::Keep) {
+        return String::new();
+    }
+
+    let mut diff = String::from("--- expected\n+++ actual\n");
+    let mut start = 0;
+    while let Some(first) = edits[start..].iter().position(|(edit, _, _)| *edit != Edit::Keep) {
+        // A hunk runs until a stretch of unchanged lines too long to be
+        // shared as context with the next change.
+        let first = start + first;
+        let mut end = first;
+        let mut unchanged = 0;
+        for (i, (edit, _, _)) in edits.iter().enumerate().skip(first) {
+            match edit {
+                Edit::Keep => unchanged += 1,
+                _ => {
+                    unchanged = 0;
+                    end = i + 1;
+                }
+            }
+            if unchanged > CONTEXT * 2 {
+                break;
+            }
+        }
+        let from = first.saturating_sub(CONTEXT);
+        let to = (end + CONTEXT).min(edits.len());
+        let hunk = &edits[from..to];
+
+        let (old_start, new_start) = (hunk[0].1, hunk[0].2);
+        let old_count = hunk.iter().filter(|(edit, _, _)| *edit != Edit::Add).count();
+        let new_count = hunk.iter().filter(|(edit, _, _)| *edit != Edit::Remove).count();
+        diff.push_str(&format!("@@ -{},{} +{},{} @@\n", old_start + 1, old_count, new_start + 1, new_count));
+        for (edit, i, j) in hunk {
+            match edit {
+                Edit::Keep => diff.push_str(&format!(" {}\n", old[*i])),
+                Edit::Remove => diff.push_str(&format!("-{}\n", old[*i])),
+                Edit::Add => diff.push_str(&format!("+{}\n", new[*j])),
+            }
+        }
+        start = to;
+    }
+    diff
+}
+
+/// The shortest edit from `old` to `new`, each edit along with the index of
+/// the line it is at in both.
+fn edits(old: &[&str], new: &[&str]) -> Vec<(Edit, usize, usize)> {
+    // Length of the longest common subsequence of old[i..] and new[j..].
+    let mut common = vec![vec![0; new.len() + 1]; old.len() + 1];
+    for i in (0..old.len()).rev() {
+        for j in (0..new.len()).rev() {
+            common[i][j] = match old[i] == new[j] {
+                true => common[i + 1][j + 1] + 1,
+                false => common[i + 1][j].max(common[i][j + 1]),
+            };
+        }
+    }
+
+    let mut edits = Vec::new();
+    let (mut i, mut j) = (0, 0);
+    while i < old.len() || j < new.len() {
+        if i < old.len() && j < new.len() && old[i] == new[j] {
+            edits.push((Edit::Keep, i, j));
+            i += 1;
+            j += 1;
+        } else if j == new.len() || (i < old.len() && common[i + 1][j] >= common[i][j + 1]) {
+            edits.push((Edit::Remove, i, j));
+            i += 1;
+        } else {
+            edits.push((Edit::Add, i, j));
+            j += 1;
+        }
+    }
+    edits
+}
@@ -0,0 +1,41 @@
+//! Text layout for natives that print more than a single value.
+
+/// Lay out `rows` under `headers` with every column as wide as its widest
+/// cell. Rows shorter than the others are padded with empty cells.
+pub fn format_table(headers: &[String], rows: &[Vec<String>]) -> String {
+    let columns = rows.iter().map(Vec::len).chain([headers.len()]).max().unwrap_or(0);
+
+    let mut widths = vec![0; columns];
+    for row in std::iter::once(headers).chain(rows.iter().map(Vec::as_slice)) {
+        for (width, cell) in widths.iter_mut().zip(row) {
+            *width = (*width).max(cell.chars().count());
+        }
+    }
+
+    let mut table = String::new();
+    push_row(&mut table, headers, &widths);
+
+    let separator: Vec<String> = widths.iter().map(|width| "-".repeat(*width)).collect();
+    table.push_str(&separator.join("-+-"));
+    table.push('\n');
+
+    for row in rows {
+        push_row(&mut table, row, &widths);
+    }
+
+    table
+}
+
+fn push_row(table: &mut String, row: &[String], widths: &[usize]) {
+    let cells: Vec<String> = widths
+        .iter()
+        .enumerate()
+        .map(|(i, width)| {
+            let cell = row.get(i).map(String::as_str).unwrap_or("");
+            format!("{:width$}", cell, width = width)
+        })
+        .collect();
+
+    table.push_str(cells.join(" | ").trim_end());
+    table.push('\n');
+}
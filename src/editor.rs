@@ -0,0 +1,193 @@
+//! What editor integrations need to know about a source file, see
+//! `cauchemar lsp`. Lines and columns start at 1, columns count characters.
+
+use std::collections::{HashMap, HashSet};
+
+use pest::Parser;
+use regex::Regex;
+
+use crate::{
+    highlight, parse_cauchemar_file, register_natives, CauchemarAST, CauchemarError, CauchemarParser,
+    CauchemarVMBuilder, Rule,
+};
+
+/// Something wrong with a file, from `column` up to `end_column` of `line`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Problem {
+    pub line: usize,
+    pub column: usize,
+    pub end_column: usize,
+    pub message: String,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DefinitionKind {
+    Routine,
+    Define,
+    Macro,
+}
+
+/// A routine, constant or macro of a file.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Definition {
+    pub name: String,
+    pub kind: DefinitionKind,
+    /// Where the name is written.
+    pub line: usize,
+    pub column: usize,
+    /// The name as it is defined, with the PARAMS and RETURNS of routines.
+    pub signature: String,
+    /// The comment right above the definition, without its delimiters.
+    pub doc: Option<String>,
+}
+
+/// Why `source` doesn't parse or, when it does, every call to a routine
+/// that doesn't exist.
+pub fn check(source: &str) -> Vec<Problem> {
+    let program = match parse_cauchemar_file(source) {
+        Ok(program) => program,
+        Err(e) => {
+            let ((line, column), end) = match e.line_col {
+                pest::error::LineColLocation::Pos(start) => (start, start),
+                pest::error::LineColLocation::Span(start, end) => (start, end),
+            };
+            let end_column = if end.0 == line { end.1 } else { column };
+            return vec![Problem { line, column, end_column, message: e.variant.message().into_owned() }];
+        }
+    };
+
+    // Whether the entry exists depends on what the file is run with, a
+    // library has none.
+    let unknown: HashSet<String> = CauchemarVMBuilder::new()
+        .check(program)
+        .into_iter()
+        .filter_map(|e| match e {
+            CauchemarError::UnknownRoutine(name) => Some(name),
+            _ => None,
+        })
+        .collect();
+    let Ok(tokens) = highlight::tokens(source, Rule::program) else {
+        return Vec::new();
+    };
+    tokens
+        .iter()
+        .filter(|token| token.kind == "identifier" && unknown.contains(token.text))
+        .map(|token| {
+            let (line, column) = line_col(source, token.start);
+            Problem {
+                line,
+                column,
+                end_column: column + token.text.chars().count(),
+                message: CauchemarError::UnknownRoutine(token.text.to_string()).to_string(),
+            }
+        })
+        .collect()
+}
+
+/// Everything `source` defines in the order it does. Items that don't parse
+/// are left out one by one, so that a typo in a routine doesn't hide the
+/// others.
+pub fn definitions(source: &str) -> Vec<Definition> {
+    let mut source = source.to_string();
+    loop {
+        let e = match parse_cauchemar_file(&source) {
+            Ok(_) => return parsed_definitions(&source),
+            Err(e) => e,
+        };
+        let offset = match e.location {
+            pest::error::InputLocation::Pos(offset) => offset,
+            pest::error::InputLocation::Span((offset, _)) => offset,
+        };
+        if !blank_item(&mut source, offset) {
+            return Vec::new();
+        }
+    }
+}
+
+/// Replace the item around `offset` with spaces, keeping line breaks so that
+/// everything else stays where it was. Returns false when it already was.
+fn blank_item(source: &mut String, offset: usize) -> bool {
+    let header = Regex::new(r"(?m)^[ \t]*(?:[A-Z][A-Z0-9-]*\??:|DEFINE\s|MACRO\s)").unwrap();
+    let starts: Vec<usize> = header.find_iter(source).map(|m| m.start()).collect();
+    let start = starts.iter().rev().find(|start| **start <= offset).copied().unwrap_or(0);
+    let end = starts.iter().find(|start| **start > offset).copied().unwrap_or(source.len());
+    if source[start..end].trim().is_empty() {
+        return false;
+    }
+    let blank: String = source[start..end].chars().map(|c| if c == '\n' { '\n' } else { ' ' }).collect();
+    source.replace_range(start..end, &blank);
+    true
+}
+
+fn parsed_definitions(source: &str) -> Vec<Definition> {
+    let Ok(program) = parse_cauchemar_file(source) else {
+        return Vec::new();
+    };
+    let Ok(mut items) = CauchemarParser::parse(Rule::program, source) else {
+        return Vec::new();
+    };
+
+    let mut definitions = Vec::new();
+    // The last comment and the line it ends on.
+    let mut comment: Option<(&str, usize)> = None;
+    for item in items.next().unwrap().into_inner() {
+        let kind = match item.as_rule() {
+            Rule::routine => DefinitionKind::Routine,
+            Rule::definition => DefinitionKind::Define,
+            Rule::macro_definition => DefinitionKind::Macro,
+            Rule::COMMENT => {
+                comment = Some((item.as_str(), item.as_span().end_pos().line_col().0));
+                continue;
+            }
+            _ => continue,
+        };
+        let (start, _) = item.line_col();
+        let doc = match comment.take() {
+            Some((text, end)) if end + 1 >= start => doc(text),
+            _ => None,
+        };
+        let name = item.into_inner().next().unwrap();
+        let (line, column) = name.line_col();
+        let name = name.as_str();
+
+        let signature = match kind {
+            DefinitionKind::Routine => {
+                let header: Vec<String> = program.routines[name]
+                    .iter()
+                    .filter(|command| !matches!(command, CauchemarAST::Comment { .. }))
+                    .take_while(|command| matches!(command, CauchemarAST::Params(_) | CauchemarAST::Returns(_)))
+                    .map(ToString::to_string)
+                    .collect();
+                format!("{}: {}", name, header.join(" ")).trim_end().to_string()
+            }
+            DefinitionKind::Define => format!("DEFINE {} {}", name, program.constants[name]),
+            DefinitionKind::Macro => format!("MACRO {}", name),
+        };
+        definitions.push(Definition { name: name.to_string(), kind, line, column, signature, doc });
+    }
+    definitions
+}
+
+/// The text of a `/* */` comment, each line trimmed.
+fn doc(comment: &str) -> Option<String> {
+    let text = comment.strip_prefix("/*")?.strip_suffix("*/")?;
+    let lines: Vec<&str> = text.lines().map(str::trim).collect();
+    let doc = lines.join("\n").trim().to_string();
+    (!doc.is_empty()).then_some(doc)
+}
+
+/// The line and column of a byte offset.
+fn line_col(source: &str, offset: usize) -> (usize, usize) {
+    let before = &source[..offset];
+    let line_start = before.rfind('\n').map_or(0, |i| i + 1);
+    (before.matches('\n').count() + 1, before[line_start..].chars().count() + 1)
+}
+
+/// Names of the native routines, sorted.
+pub fn natives() -> Vec<String> {
+    let mut routines = HashMap::new();
+    register_natives(&mut routines);
+    let mut names: Vec<String> = routines.keys().map(ToString::to_string).collect();
+    names.sort();
+    names
+}
@@ -0,0 +1,216 @@
+//! Everything that can go wrong, from parsing a file to running it.
+
+use std::{fmt, io, path::PathBuf};
+
+use serde::Serialize;
+
+use crate::{CauchemarSpan, CauchemarVMValue, Rule};
+
+/// Why parsing, compiling or running a program failed, its Display being
+/// the message the command line prints.
+#[derive(Debug)]
+pub enum CauchemarError {
+    Io(PathBuf, io::Error),
+    Parse(pest::error::Error<Rule>),
+    InvalidSnapshot(PathBuf, serde_json::Error),
+    MissingRoutine { name: String, suggestion: Option<String> },
+    UnknownRoutine(String),
+    DuplicateDefinition { name: String, first: PathBuf, second: PathBuf },
+    /// A native registered by the host has the name of a built-in native or,
+    /// when `builtin` is false, of a routine of the program.
+    NativeCollision { name: String, builtin: bool },
+    ReturnCount { routine: String, expected: usize, found: usize },
+    /// More than one value left after running on an input line of --lines.
+    LineResult { line: usize, found: usize },
+    StackUnderflow,
+    CallDepthExceeded(usize),
+    /// `top` holds the last values pushed, the most recent first.
+    StackOverflow { max: usize, routine: String, top: Vec<String> },
+    StepLimitExceeded(u64),
+    InvalidType { expected: &'static str, found: String },
+    MismatchedTypes { left: String, right: String },
+    IntegerOverflow,
+    DivisionByZero,
+    InvalidArgument(String),
+    FileAccess(PathBuf, io::Error),
+    Spawn(String, io::Error),
+    /// A request to the given URL failed before a response came back.
+    IoError(String, io::Error),
+    Socket(String, io::Error),
+    Watch(notify::Error),
+    Disabled { routine: &'static str, flag: &'static str },
+    AssertionFailed { routine: String, detail: Option<String> },
+}
+
+impl CauchemarError {
+    pub(crate) fn invalid_type(expected: &'static str, found: &CauchemarVMValue) -> CauchemarError {
+        CauchemarError::InvalidType {
+            expected,
+            found: format!("{} {}", found.type_name(), found.repr()),
+        }
+    }
+
+    /// Process exit code for this error, these are stable and safe to rely on
+    /// from scripts.
+    pub fn exit_code(&self) -> u8 {
+        match self {
+            CauchemarError::Io(..) | CauchemarError::InvalidSnapshot(..) => 1,
+            CauchemarError::Parse(_) => 2,
+            CauchemarError::MissingRoutine { .. }
+            | CauchemarError::UnknownRoutine(_)
+            | CauchemarError::DuplicateDefinition { .. }
+            | CauchemarError::NativeCollision { .. } => 3,
+            CauchemarError::AssertionFailed { .. } => 5,
+            _ => 4,
+        }
+    }
+
+    /// Identifies the kind of error in --json-errors, as stable as the exit
+    /// code.
+    fn code(&self) -> &'static str {
+        match self {
+            CauchemarError::Io(..) => "io",
+            CauchemarError::Parse(_) => "parse",
+            CauchemarError::InvalidSnapshot(..) => "invalid-snapshot",
+            CauchemarError::MissingRoutine { .. } => "missing-routine",
+            CauchemarError::UnknownRoutine(_) => "unknown-routine",
+            CauchemarError::DuplicateDefinition { .. } => "duplicate-definition",
+            CauchemarError::NativeCollision { .. } => "native-collision",
+            CauchemarError::ReturnCount { .. } => "return-count",
+            CauchemarError::LineResult { .. } => "line-result",
+            CauchemarError::StackUnderflow => "stack-underflow",
+            CauchemarError::CallDepthExceeded(_) => "call-depth-exceeded",
+            CauchemarError::StackOverflow { .. } => "stack-overflow",
+            CauchemarError::StepLimitExceeded(_) => "step-limit-exceeded",
+            CauchemarError::InvalidType { .. } => "invalid-type",
+            CauchemarError::MismatchedTypes { .. } => "mismatched-types",
+            CauchemarError::IntegerOverflow => "integer-overflow",
+            CauchemarError::DivisionByZero => "division-by-zero",
+            CauchemarError::InvalidArgument(_) => "invalid-argument",
+            CauchemarError::FileAccess(..) => "file-access",
+            CauchemarError::Spawn(..) => "spawn",
+            CauchemarError::IoError(..) => "io-error",
+            CauchemarError::Socket(..) => "socket",
+            CauchemarError::Watch(_) => "watch",
+            CauchemarError::Disabled { .. } => "disabled",
+            CauchemarError::AssertionFailed { .. } => "assertion-failed",
+        }
+    }
+
+    /// The error as a single --json-errors line, `trace` being the call
+    /// frames of a runtime error, innermost first.
+    pub(crate) fn diagnostic(&self, trace: Option<Vec<String>>) -> CauchemarDiagnostic {
+        let mut diagnostic = CauchemarDiagnostic {
+            severity: "error",
+            code: self.code(),
+            message: self.to_string(),
+            file: None,
+            line: None,
+            column: None,
+            trace,
+        };
+        match self {
+            CauchemarError::Io(path, _) => diagnostic.file = Some(path.display().to_string()),
+            CauchemarError::Parse(e) => {
+                let ((line, column), _) = match e.line_col {
+                    pest::error::LineColLocation::Pos(start) => (start, start),
+                    pest::error::LineColLocation::Span(start, end) => (start, end),
+                };
+                diagnostic.message = e.variant.message().into_owned();
+                diagnostic.file = e.path().map(str::to_string);
+                diagnostic.line = Some(line);
+                diagnostic.column = Some(column);
+            }
+            _ => {}
+        }
+        diagnostic
+    }
+}
+
+/// One line of --json-errors output. Tools rely on the field names, see
+/// examples/json-errors.jsonl.
+#[derive(Debug, Serialize)]
+pub(crate) struct CauchemarDiagnostic {
+    severity: &'static str,
+    code: &'static str,
+    message: String,
+    file: Option<String>,
+    line: Option<usize>,
+    column: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    trace: Option<Vec<String>>,
+}
+
+impl CauchemarDiagnostic {
+    /// Point a runtime error at the command that failed, `span` being where
+    /// it is in `source`, read from `file`. Columns count characters from 1,
+    /// as they do for parse errors.
+    pub(crate) fn at(mut self, file: &str, source: &str, span: CauchemarSpan) -> CauchemarDiagnostic {
+        let line_start = source[..span.start].rfind('\n').map_or(0, |newline| newline + 1);
+        self.file = Some(file.to_string());
+        self.line = Some(span.line);
+        self.column = Some(source[line_start..span.start].chars().count() + 1);
+        self
+    }
+}
+
+impl fmt::Display for CauchemarError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CauchemarError::Io(path, e) => write!(f, "Unable to read {}: {}", path.display(), e),
+            CauchemarError::Parse(e) => write!(f, "Parse error\n{}", e),
+            CauchemarError::InvalidSnapshot(path, e) => write!(f, "Invalid snapshot {}: {}", path.display(), e),
+            CauchemarError::MissingRoutine { name, suggestion: None } => write!(f, "Missing {} routine", name),
+            CauchemarError::MissingRoutine { name, suggestion: Some(suggestion) } => {
+                write!(f, "Missing {} routine, did you mean {}?", name, suggestion)
+            }
+            CauchemarError::UnknownRoutine(name) => write!(f, "Unknown routine: {}", name),
+            CauchemarError::DuplicateDefinition { name, first, second } => {
+                write!(f, "{} is defined in both {} and {}", name, first.display(), second.display())
+            }
+            CauchemarError::NativeCollision { name, builtin: true } => {
+                write!(f, "Cannot register native {}, a built-in native has that name", name)
+            }
+            CauchemarError::NativeCollision { name, builtin: false } => {
+                write!(f, "Cannot register native {}, the program defines a routine of that name", name)
+            }
+            CauchemarError::ReturnCount { routine, expected, found } => {
+                write!(f, "{} left {} values, RETURNS says {}", routine, found, expected)
+            }
+            CauchemarError::LineResult { line, found } => {
+                write!(f, "Line {} left {} values, --lines expects one at most", line, found)
+            }
+            CauchemarError::StackUnderflow => write!(f, "Stack underflow"),
+            CauchemarError::CallDepthExceeded(max) => write!(f, "Call depth exceeded {} frames", max),
+            CauchemarError::StackOverflow { max, routine, top } => write!(
+                f,
+                "Stack overflow in {}, more than {} values, top: {} ...",
+                routine,
+                max,
+                top.join(" ")
+            ),
+            CauchemarError::StepLimitExceeded(steps) => write!(f, "Step limit exceeded after {} steps", steps),
+            CauchemarError::InvalidType { expected, found } => {
+                write!(f, "Invalid type: expected {}, found {}", expected, found)
+            }
+            CauchemarError::MismatchedTypes { left, right } => {
+                write!(f, "Cannot compare {} with {}", left, right)
+            }
+            CauchemarError::IntegerOverflow => write!(f, "Integer overflow"),
+            CauchemarError::DivisionByZero => write!(f, "Division by zero"),
+            CauchemarError::InvalidArgument(message) => write!(f, "Invalid argument: {}", message),
+            CauchemarError::FileAccess(path, e) => write!(f, "Unable to access {}: {}", path.display(), e),
+            CauchemarError::Spawn(command, e) => write!(f, "Unable to run {}: {}", command, e),
+            CauchemarError::IoError(url, e) => write!(f, "Request to {} failed: {}", url, e),
+            CauchemarError::Socket(socket, e) => write!(f, "Socket {} failed: {}", socket, e),
+            CauchemarError::Watch(e) => write!(f, "Unable to watch for changes: {}", e),
+            CauchemarError::Disabled { routine, flag } => write!(f, "{} is disabled by {}", routine, flag),
+            CauchemarError::AssertionFailed { routine, detail: None } => {
+                write!(f, "Assertion failed in {}", routine)
+            }
+            CauchemarError::AssertionFailed { routine, detail: Some(detail) } => {
+                write!(f, "Assertion failed in {}: {}", routine, detail)
+            }
+        }
+    }
+}
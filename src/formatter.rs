@@ -0,0 +1,175 @@
+//! Reprinting source for `cauchemar fmt`, from the parsed program so that
+//! only comments and the order of items are taken from the file itself.
+
+use crate::{CauchemarAST, CauchemarItem, CauchemarProgram};
+
+pub const DEFAULT_WIDTH: usize = 80;
+
+/// A command as it should be written back, literals as they were written.
+fn source(command: &CauchemarAST) -> String {
+    match command {
+        CauchemarAST::Number(_, text) | CauchemarAST::BigInt(_, text) | CauchemarAST::String(_, text) => text.to_string(),
+        command => command.to_string(),
+    }
+}
+
+/// Reprints parsed source in the canonical style: two space indentation,
+/// bodies wrapped at `width`, and every block keyword on its own line.
+pub struct SourceFormatter {
+    width: usize,
+    indent: usize,
+    line: String,
+    output: String,
+}
+
+impl SourceFormatter {
+    pub fn new(width: usize) -> SourceFormatter {
+        SourceFormatter {
+            width,
+            indent: 0,
+            line: String::new(),
+            output: String::new(),
+        }
+    }
+
+    fn word(&mut self, word: &str) {
+        let indent = self.indent * 2;
+        if !self.line.is_empty() && indent + self.line.len() + 1 + word.len() > self.width {
+            self.end_line();
+        }
+        if !self.line.is_empty() {
+            self.line.push(' ');
+        }
+        self.line.push_str(word);
+    }
+
+    fn end_line(&mut self) {
+        if !self.line.is_empty() {
+            self.output.push_str(&"  ".repeat(self.indent));
+            self.output.push_str(&self.line);
+            self.output.push('\n');
+            self.line.clear();
+        }
+    }
+
+    /// Put a keyword on a line of its own.
+    fn keyword(&mut self, keyword: &str) {
+        self.end_line();
+        self.word(keyword);
+        self.end_line();
+    }
+
+    fn block(&mut self, commands: &[CauchemarAST]) {
+        self.indent += 1;
+        self.commands(commands);
+        self.end_line();
+        self.indent -= 1;
+    }
+
+    fn commands(&mut self, commands: &[CauchemarAST]) {
+        for command in commands {
+            match command {
+                CauchemarAST::If(then, otherwise) => {
+                    self.keyword("IF");
+                    self.block(then);
+                    if !otherwise.is_empty() {
+                        self.keyword("ELSE");
+                        self.block(otherwise);
+                    }
+                    self.keyword("THEN");
+                }
+                CauchemarAST::Switch(value, cases, default) => {
+                    self.end_line();
+                    self.word("SWITCH");
+                    self.commands(value);
+                    self.end_line();
+                    for (case_value, body) in cases {
+                        self.word("CASE");
+                        self.commands(case_value);
+                        self.word("DO");
+                        self.end_line();
+                        self.block(body);
+                    }
+                    if !default.is_empty() {
+                        self.keyword("DEFAULT");
+                        self.block(default);
+                    }
+                    self.keyword("END");
+                }
+                CauchemarAST::While(body) => {
+                    self.keyword("DO");
+                    self.block(body);
+                    self.keyword("WHILE");
+                }
+                CauchemarAST::Thunk(body) if body.iter().any(|c| {
+                    matches!(c, CauchemarAST::If(..) | CauchemarAST::Switch(..) | CauchemarAST::While(_) | CauchemarAST::Comment { .. })
+                }) => {
+                    self.word("{");
+                    self.end_line();
+                    self.block(body);
+                    self.word("}");
+                }
+                CauchemarAST::Comment { text, own_line } => {
+                    if *own_line {
+                        self.end_line();
+                    }
+                    self.word(text);
+                    self.end_line();
+                }
+                command => self.word(&source(command)),
+            }
+        }
+    }
+
+    pub fn program(mut self, program: &CauchemarProgram) -> String {
+        // Routines are separated by a blank line, as is the block of
+        // definitions from whatever comes after it.
+        let mut previous: Option<&CauchemarItem> = None;
+
+        for item in &program.layout {
+            match item {
+                // A comment following something on the same line stays there.
+                CauchemarItem::Comment { text, own_line: false } if previous.is_some() => {
+                    self.output.pop();
+                    self.output.push(' ');
+                    self.output.push_str(text);
+                    self.output.push('\n');
+                }
+                CauchemarItem::Comment { text, .. } => {
+                    if let Some(CauchemarItem::Routine(_) | CauchemarItem::Macro(_)) = previous {
+                        self.output.push('\n');
+                    }
+                    self.output.push_str(text);
+                    self.output.push('\n');
+                    previous = None;
+                }
+                CauchemarItem::Define(name) => {
+                    if let Some(CauchemarItem::Routine(_) | CauchemarItem::Macro(_)) = previous {
+                        self.output.push('\n');
+                    }
+                    self.output.push_str(&format!("DEFINE {} {}\n", name, source(&program.constants[name])));
+                    previous = Some(item);
+                }
+                CauchemarItem::Routine(name) => {
+                    if previous.is_some() {
+                        self.output.push('\n');
+                    }
+                    self.output.push_str(name);
+                    self.output.push_str(":\n");
+                    self.block(&program.routines[name]);
+                    previous = Some(item);
+                }
+                CauchemarItem::Macro(name) => {
+                    if previous.is_some() {
+                        self.output.push('\n');
+                    }
+                    self.output.push_str(&format!("MACRO {}\n", name));
+                    self.block(&program.macros[name]);
+                    previous = Some(item);
+                }
+            }
+        }
+
+        self.output
+    }
+}
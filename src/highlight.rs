@@ -0,0 +1,79 @@
+//! Token stream for --highlight-tokens.
+//!
+//! Tokens are the leaves of the parse tree, typed after the grammar rule
+//! matching them. The words the grammar only checks for, like `IF` or
+//! `PARAMS`, are typed `keyword`. Offsets count bytes, `end` excluded.
+
+use pest::Parser;
+use regex::Regex;
+use serde::Serialize;
+
+use crate::{CauchemarParser, Rule};
+
+/// Rules that make up a token on their own.
+const TOKENS: [Rule; 18] = [
+    Rule::COMMENT,
+    Rule::number,
+    Rule::radix_number,
+    Rule::bigint,
+    Rule::radix_bigint,
+    Rule::char_literal,
+    Rule::string,
+    Rule::long_string,
+    Rule::true_,
+    Rule::false_,
+    Rule::identifier,
+    Rule::local,
+    Rule::count,
+    Rule::return_with,
+    Rule::add,
+    Rule::sub,
+    Rule::mul,
+    Rule::div,
+];
+
+#[derive(Serialize)]
+pub struct Token<'a> {
+    #[serde(rename = "type")]
+    pub kind: String,
+    pub start: usize,
+    pub end: usize,
+    pub text: &'a str,
+}
+
+/// Every token of `source` parsed as `rule`, in order.
+pub fn tokens(source: &str, rule: Rule) -> Result<Vec<Token<'_>>, pest::error::Error<Rule>> {
+    let mut tokens = Vec::new();
+    for pair in CauchemarParser::parse(rule, source)?.flatten() {
+        if TOKENS.contains(&pair.as_rule()) {
+            let span = pair.as_span();
+            tokens.push(Token {
+                kind: format!("{:?}", pair.as_rule()),
+                start: span.start(),
+                end: span.end(),
+                text: span.as_str(),
+            });
+        }
+    }
+
+    // Whatever is left between the tokens is whitespace, punctuation and
+    // keywords.
+    let word = Regex::new(r"[A-Z][A-Z0-9-]*\??").unwrap();
+    let mut keywords = Vec::new();
+    let mut gap_start = 0;
+    let spans: Vec<(usize, usize)> = tokens.iter().map(|token| (token.start, token.end)).collect();
+    for (start, end) in spans.into_iter().chain([(source.len(), source.len())]) {
+        for keyword in word.find_iter(&source[gap_start..start]) {
+            keywords.push(Token {
+                kind: "keyword".into(),
+                start: gap_start + keyword.start(),
+                end: gap_start + keyword.end(),
+                text: keyword.as_str(),
+            });
+        }
+        gap_start = end;
+    }
+    tokens.extend(keywords);
+    tokens.sort_by_key(|token| token.start);
+    Ok(tokens)
+}
@@ -0,0 +1,151 @@
+//! The Cauchemar interpreter, shared by the `cauchemar` command line and
+//! `cauchemar-lsp`.
+//!
+//! Programs can also be run from Rust: [`Program::parse`] a source file,
+//! [`Program::compile`] it into a [`Vm`] and [`Vm::run`] it, each step
+//! failing with a [`CauchemarError`]. A [`VmBuilder`] adds natives of the
+//! host's own before compiling.
+
+extern crate pest;
+#[macro_use]
+extern crate pest_derive;
+
+mod asm_output;
+mod ast;
+mod c_output;
+mod callgraph;
+mod cfg;
+mod cli;
+mod compiler;
+mod coverage;
+mod dap;
+mod diff;
+mod display;
+pub mod editor;
+mod error;
+mod formatter;
+mod highlight;
+#[cfg(feature = "lsp")]
+pub mod lsp;
+mod natives;
+mod parser;
+mod snippet;
+mod style;
+mod value;
+mod vm;
+mod wasm_output;
+
+use ast::{escape_string, CauchemarAST, CauchemarItem, CauchemarProgram, CauchemarSpan};
+use cli::{parse_source, read_source, STDERR_NAME, STDIN_NAME, STDOUT_NAME};
+use compiler::{
+    check_calls, check_routines, closest_name, compile_routine, compile_user_routines, source_spans, unknown_calls,
+};
+use error::CauchemarDiagnostic;
+use natives::{arithmetic, register_natives};
+use parser::{parse_cauchemar_file, parse_cauchemar_snippet, CauchemarParser};
+use value::CauchemarVMValue;
+use vm::{
+    CauchemarChannel, CauchemarMutex, CauchemarMutexGuard, CauchemarThread, CauchemarVM, CauchemarVMBuilder,
+    CauchemarVMFrame, CauchemarVMInstruction, CauchemarVMRoutine, CauchemarVMSnapshot,
+    DEFAULT_MAX_DEPTH, DEFAULT_MAX_STACK,
+};
+
+pub use cli::main;
+pub use error::CauchemarError;
+pub use parser::Rule;
+pub use value::CauchemarVMValue as Value;
+pub use vm::CauchemarVMInstruction as Instruction;
+pub use vm::SandboxPolicy;
+pub use vm::CauchemarVMSnapshot as Snapshot;
+pub use vm::{load_snapshot, save_snapshot};
+pub use vm::CauchemarVM as Vm;
+
+/// A parsed source file, borrowing from its source.
+pub struct Program<'a>(CauchemarProgram<'a>);
+
+impl<'a> Program<'a> {
+    /// Parse a whole source file, as `cauchemar FILE` would.
+    pub fn parse(source: &'a str) -> Result<Program<'a>, CauchemarError> {
+        parse_cauchemar_file(source).map(Program).map_err(CauchemarError::Parse)
+    }
+
+    /// Compile every routine with the same defaults as the command line,
+    /// see [`VmBuilder`] to change them.
+    pub fn compile(self) -> Result<Vm, CauchemarError> {
+        VmBuilder::new(self).build()
+    }
+
+    /// Every constant, macro and routine as S-expressions, one per line,
+    /// as printed by `--print-ast`.
+    pub fn dump_ast(&self) -> String {
+        self.0.sexp()
+    }
+}
+
+/// Compiles a program into a [`Vm`], with natives of the host's own.
+pub struct VmBuilder<'a> {
+    program: CauchemarProgram<'a>,
+    builder: CauchemarVMBuilder,
+}
+
+impl<'a> VmBuilder<'a> {
+    pub fn new(program: Program<'a>) -> VmBuilder<'a> {
+        VmBuilder { program: program.0, builder: CauchemarVMBuilder::new() }
+    }
+
+    /// Routine to start running from, PROGRAM by default.
+    pub fn entry(mut self, name: &str) -> VmBuilder<'a> {
+        self.builder = self.builder.entry(name);
+        self
+    }
+
+    /// Largest number of values on the stack, a million by default and
+    /// zero for unlimited.
+    pub fn max_stack_depth(mut self, n: usize) -> VmBuilder<'a> {
+        self.builder = self.builder.max_stack_depth(n);
+        self
+    }
+
+    /// Maximum number of nested frames.
+    pub fn max_call_depth(mut self, n: usize) -> VmBuilder<'a> {
+        self.builder = self.builder.max_call_depth(n);
+        self
+    }
+
+    /// Maximum number of instructions to execute, zero means unlimited.
+    pub fn instruction_budget(mut self, n: u64) -> VmBuilder<'a> {
+        self.builder = self.builder.instruction_budget(n);
+        self
+    }
+
+    /// Which built-in natives the program may call, all of them by default.
+    pub fn sandbox(mut self, policy: SandboxPolicy) -> VmBuilder<'a> {
+        self.builder = self.builder.sandbox(policy);
+        self
+    }
+
+    /// Let the program call `name`, running `native` with the VM to pop its
+    /// arguments and push its results. It can hold on to state of its own
+    /// but has to be `Send`, as programs calling THREAD-SPAWN share it.
+    pub fn register_native<F>(mut self, name: &str, native: F) -> VmBuilder<'a>
+    where
+        F: FnMut(&mut Vm) -> Result<(), CauchemarError> + Send + 'static,
+    {
+        self.builder = self.builder.register_native(name, native);
+        self
+    }
+
+    /// Values the program starts with, the last one being the top.
+    pub fn initial_stack(mut self, stack: Vec<Value>) -> VmBuilder<'a> {
+        self.builder = self.builder.initial_stack(stack);
+        self
+    }
+
+    /// Compile the program, checking every call names a routine, built-in
+    /// or registered with [`VmBuilder::register_native`]. Fails with
+    /// [`CauchemarError::NativeCollision`] when one of those already has
+    /// its name.
+    pub fn build(self) -> Result<Vm, CauchemarError> {
+        self.builder.build(self.program)
+    }
+}
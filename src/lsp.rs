@@ -0,0 +1,203 @@
+//! Language server for `cauchemar lsp`, over stdin and stdout. Reports parse
+//! errors and calls to unknown routines, goes to where routines are defined,
+//! shows their signature and doc comment on hover and completes their names.
+
+use std::{collections::HashMap, sync::Mutex};
+
+use tower_lsp::{
+    jsonrpc::Result,
+    lsp_types::{
+        CompletionItem, CompletionItemKind, CompletionOptions, CompletionParams, CompletionResponse, Diagnostic,
+        DiagnosticSeverity, DidChangeTextDocumentParams, DidCloseTextDocumentParams, DidOpenTextDocumentParams,
+        GotoDefinitionParams, GotoDefinitionResponse, Hover, HoverContents, HoverParams, HoverProviderCapability,
+        InitializeParams, InitializeResult, Location, MarkupContent, MarkupKind, OneOf, Position, Range,
+        ServerCapabilities, ServerInfo, TextDocumentSyncCapability, TextDocumentSyncKind, Url,
+    },
+    Client, LanguageServer, LspService, Server,
+};
+
+use crate::editor::{self, Definition, DefinitionKind};
+
+struct Backend {
+    client: Client,
+    /// Text of the open documents, sent whole on every change.
+    documents: Mutex<HashMap<Url, String>>,
+    natives: Vec<String>,
+}
+
+impl Backend {
+    async fn update(&self, uri: Url, text: String, version: Option<i32>) {
+        let diagnostics = editor::check(&text)
+            .into_iter()
+            .map(|problem| {
+                let line = problem.line - 1;
+                let start = Position::new(line as u32, utf16_column(&text, line, problem.column - 1));
+                let end = Position::new(line as u32, utf16_column(&text, line, problem.end_column - 1));
+                Diagnostic {
+                    range: Range::new(start, end),
+                    severity: Some(DiagnosticSeverity::ERROR),
+                    source: Some("cauchemar".into()),
+                    message: problem.message,
+                    ..Default::default()
+                }
+            })
+            .collect();
+        self.documents.lock().unwrap().insert(uri.clone(), text);
+        self.client.publish_diagnostics(uri, diagnostics, version).await;
+    }
+
+    /// The definition of the name under `position` in the document, if any.
+    fn definition(&self, uri: &Url, position: Position) -> Option<(Definition, String)> {
+        let documents = self.documents.lock().unwrap();
+        let text = documents.get(uri)?;
+        let word = word_at(text, position)?;
+        let definition = editor::definitions(text).into_iter().find(|definition| definition.name == word)?;
+        Some((definition, text.clone()))
+    }
+}
+
+#[tower_lsp::async_trait]
+impl LanguageServer for Backend {
+    async fn initialize(&self, _: InitializeParams) -> Result<InitializeResult> {
+        Ok(InitializeResult {
+            capabilities: ServerCapabilities {
+                text_document_sync: Some(TextDocumentSyncCapability::Kind(TextDocumentSyncKind::FULL)),
+                hover_provider: Some(HoverProviderCapability::Simple(true)),
+                completion_provider: Some(CompletionOptions::default()),
+                definition_provider: Some(OneOf::Left(true)),
+                ..Default::default()
+            },
+            server_info: Some(ServerInfo {
+                name: "cauchemar".into(),
+                version: Some(env!("CARGO_PKG_VERSION").into()),
+            }),
+        })
+    }
+
+    async fn shutdown(&self) -> Result<()> {
+        Ok(())
+    }
+
+    async fn did_open(&self, params: DidOpenTextDocumentParams) {
+        let document = params.text_document;
+        self.update(document.uri, document.text, Some(document.version)).await;
+    }
+
+    async fn did_change(&self, params: DidChangeTextDocumentParams) {
+        let Some(change) = params.content_changes.into_iter().last() else {
+            return;
+        };
+        let document = params.text_document;
+        self.update(document.uri, change.text, Some(document.version)).await;
+    }
+
+    async fn did_close(&self, params: DidCloseTextDocumentParams) {
+        self.documents.lock().unwrap().remove(&params.text_document.uri);
+    }
+
+    async fn goto_definition(&self, params: GotoDefinitionParams) -> Result<Option<GotoDefinitionResponse>> {
+        let position = params.text_document_position_params;
+        let uri = position.text_document.uri;
+        let Some((definition, text)) = self.definition(&uri, position.position) else {
+            return Ok(None);
+        };
+        let line = definition.line - 1;
+        let start = utf16_column(&text, line, definition.column - 1);
+        let end = start + definition.name.len() as u32;
+        let range = Range::new(Position::new(line as u32, start), Position::new(line as u32, end));
+        Ok(Some(GotoDefinitionResponse::Scalar(Location::new(uri, range))))
+    }
+
+    async fn hover(&self, params: HoverParams) -> Result<Option<Hover>> {
+        let position = params.text_document_position_params;
+        let value = match self.definition(&position.text_document.uri, position.position) {
+            Some((definition, _)) => match definition.doc {
+                Some(doc) => format!("```cauchemar\n{}\n```\n\n{}", definition.signature, doc),
+                None => format!("```cauchemar\n{}\n```", definition.signature),
+            },
+            None => {
+                let documents = self.documents.lock().unwrap();
+                let word = documents.get(&position.text_document.uri).and_then(|text| word_at(text, position.position));
+                match word {
+                    Some(word) if self.natives.iter().any(|native| native == word) => {
+                        format!("```cauchemar\n{}\n```\n\nNative routine", word)
+                    }
+                    _ => return Ok(None),
+                }
+            }
+        };
+        Ok(Some(Hover {
+            contents: HoverContents::Markup(MarkupContent { kind: MarkupKind::Markdown, value }),
+            range: None,
+        }))
+    }
+
+    async fn completion(&self, params: CompletionParams) -> Result<Option<CompletionResponse>> {
+        let documents = self.documents.lock().unwrap();
+        let definitions = documents
+            .get(&params.text_document_position.text_document.uri)
+            .map(|text| editor::definitions(text))
+            .unwrap_or_default();
+
+        let mut items: Vec<CompletionItem> = definitions.into_iter().map(completion).collect();
+        items.extend(self.natives.iter().map(|name| CompletionItem {
+            label: name.clone(),
+            kind: Some(CompletionItemKind::FUNCTION),
+            detail: Some("native".into()),
+            ..Default::default()
+        }));
+        Ok(Some(CompletionResponse::Array(items)))
+    }
+}
+
+fn completion(definition: Definition) -> CompletionItem {
+    let kind = match definition.kind {
+        DefinitionKind::Routine | DefinitionKind::Macro => CompletionItemKind::FUNCTION,
+        DefinitionKind::Define => CompletionItemKind::CONSTANT,
+    };
+    CompletionItem {
+        label: definition.name,
+        kind: Some(kind),
+        detail: Some(definition.signature),
+        ..Default::default()
+    }
+}
+
+/// The routine name `position` is on, if any.
+fn word_at(text: &str, position: Position) -> Option<&str> {
+    let line = text.lines().nth(position.line as usize)?;
+    // Positions count UTF-16 code units, not characters.
+    let mut units = 0;
+    let offset = line
+        .char_indices()
+        .find(|(_, c)| {
+            units += c.len_utf16() as u32;
+            units > position.character
+        })
+        .map_or(line.len(), |(i, _)| i);
+
+    let is_name = |c: char| c.is_ascii_uppercase() || c.is_ascii_digit() || c == '-' || c == '?';
+    let start = line[..offset].rfind(|c| !is_name(c)).map_or(0, |i| i + 1);
+    let end = line[offset..].find(|c| !is_name(c)).map_or(line.len(), |i| offset + i);
+    let word = &line[start..end];
+    let letters = word.trim_start_matches(|c: char| c.is_ascii_digit());
+    letters.starts_with(|c: char| c.is_ascii_uppercase()).then_some(word)
+}
+
+/// The character `column` of `line` as UTF-16 code units, which is what LSP
+/// positions count.
+fn utf16_column(text: &str, line: usize, column: usize) -> u32 {
+    let line = text.lines().nth(line).unwrap_or_default();
+    line.chars().take(column).map(|c| c.len_utf16() as u32).sum()
+}
+
+/// Serve editors until they shut the server down.
+#[tokio::main]
+pub async fn serve() {
+    let (service, socket) = LspService::new(|client| Backend {
+        client,
+        documents: Mutex::new(HashMap::new()),
+        natives: editor::natives(),
+    });
+    Server::new(tokio::io::stdin(), tokio::io::stdout(), socket).serve(service).await;
+}
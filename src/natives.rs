@@ -0,0 +1,1485 @@
+//! Routines written in Rust rather than Cauchemar.
+
+use std::{
+    any::Any,
+    collections::HashMap,
+    env, fs,
+    io::{self, BufRead, Read, Write},
+    net::TcpStream,
+    panic,
+    path::PathBuf,
+    process::{self, Child, Stdio},
+    sync::{
+        atomic::Ordering,
+        mpsc,
+        Arc, Mutex, OnceLock,
+    },
+    thread,
+    time::{Duration, Instant, SystemTime},
+};
+
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine};
+use hmac::{Hmac, Mac};
+use md5::Md5;
+use num_bigint::{BigInt, Sign};
+use sha2::{Digest, Sha256, Sha512};
+
+use crate::{
+    check_calls, check_routines, compile_routine, compile_user_routines, display, parse_cauchemar_file,
+    parse_cauchemar_snippet, CauchemarChannel, CauchemarError, CauchemarMutex, CauchemarMutexGuard, CauchemarThread,
+    CauchemarVM, CauchemarVMFrame, CauchemarVMInstruction, CauchemarVMRoutine, CauchemarVMValue, STDIN_NAME,
+    STDOUT_NAME,
+};
+
+pub(crate) fn register_natives(routines: &mut HashMap<Arc<str>, CauchemarVMRoutine>) {
+    routines.insert("PRINT".into(), CauchemarVMRoutine::Native(|vm| {
+        let value = vm.pop()?;
+        vm.print(&format!("{}\n", value))
+    }));
+
+    routines.insert("COLOR-RED".into(), CauchemarVMRoutine::Native(|vm| ansi(vm, "31")));
+    routines.insert("COLOR-GREEN".into(), CauchemarVMRoutine::Native(|vm| ansi(vm, "32")));
+    routines.insert("COLOR-YELLOW".into(), CauchemarVMRoutine::Native(|vm| ansi(vm, "33")));
+    routines.insert("COLOR-BLUE".into(), CauchemarVMRoutine::Native(|vm| ansi(vm, "34")));
+    routines.insert("COLOR-RESET".into(), CauchemarVMRoutine::Native(|vm| ansi(vm, "0")));
+    routines.insert("BOLD".into(), CauchemarVMRoutine::Native(|vm| ansi(vm, "1")));
+    routines.insert("ITALIC".into(), CauchemarVMRoutine::Native(|vm| ansi(vm, "3")));
+    routines.insert("UNDERLINE".into(), CauchemarVMRoutine::Native(|vm| ansi(vm, "4")));
+    routines.insert("COLOR-SET-FG".into(), CauchemarVMRoutine::Native(|vm| {
+        let color = pop_color(vm)?;
+        ansi(vm, &format!("38;5;{}", color))
+    }));
+    routines.insert("COLOR-SET-BG".into(), CauchemarVMRoutine::Native(|vm| {
+        let color = pop_color(vm)?;
+        ansi(vm, &format!("48;5;{}", color))
+    }));
+
+    // Negative numbers are written in two's complement.
+    routines.insert("TO-BINARY-STR".into(), CauchemarVMRoutine::Native(|vm| number_string(vm, |n| format!("{:b}", n))));
+    routines.insert("TO-OCTAL-STR".into(), CauchemarVMRoutine::Native(|vm| number_string(vm, |n| format!("{:o}", n))));
+    routines.insert("TO-HEX-STR".into(), CauchemarVMRoutine::Native(|vm| number_string(vm, |n| format!("{:#x}", n))));
+    routines.insert("PRINT-BINARY".into(), CauchemarVMRoutine::Native(|vm| print_number(vm, |n| format!("{:b}", n))));
+    routines.insert("PRINT-OCTAL".into(), CauchemarVMRoutine::Native(|vm| print_number(vm, |n| format!("{:o}", n))));
+    routines.insert("PRINT-HEX".into(), CauchemarVMRoutine::Native(|vm| print_number(vm, |n| format!("{:#x}", n))));
+    routines.insert("PRINT-HEX-UPPER".into(), CauchemarVMRoutine::Native(|vm| print_number(vm, |n| format!("0x{:X}", n))));
+
+    routines.insert("SLEEP".into(), CauchemarVMRoutine::Native(|vm| {
+        let milliseconds = vm.pop_number()?;
+        if milliseconds < 0 {
+            return Err(CauchemarError::InvalidArgument(format!("cannot sleep for {} ms", milliseconds)));
+        }
+        if vm.sleep {
+            thread::sleep(Duration::from_millis(milliseconds as u64));
+        }
+        Ok(())
+    }));
+
+    routines.insert("NOW".into(), CauchemarVMRoutine::Native(|vm| {
+        let seconds = match vm.fixed_clock {
+            true => 0,
+            false => SystemTime::now()
+                .duration_since(SystemTime::UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_secs(),
+        };
+        vm.push(number_or_big_int(seconds))?;
+        Ok(())
+    }));
+
+    // Counted from the first call in the process, so threads agree.
+    routines.insert("CLOCK-MS".into(), CauchemarVMRoutine::Native(|vm| {
+        static START: OnceLock<Instant> = OnceLock::new();
+
+        let start = *START.get_or_init(Instant::now);
+        let milliseconds = match vm.fixed_clock {
+            true => 0,
+            false => start.elapsed().as_millis() as u64,
+        };
+        vm.push(number_or_big_int(milliseconds))?;
+        Ok(())
+    }));
+
+    routines.insert("EPRINT".into(), CauchemarVMRoutine::Native(|vm| {
+        let value = vm.pop()?;
+        vm.eprint(&format!("{}\n", value))
+    }));
+
+    routines.insert("EPRINT-REPR".into(), CauchemarVMRoutine::Native(|vm| {
+        let value = vm.pop()?;
+        vm.eprint(&format!("{}\n", value.repr()))
+    }));
+
+    routines.insert("DROP".into(), CauchemarVMRoutine::Native(|vm| {
+        vm.pop()?;
+        Ok(())
+    }));
+
+    routines.insert("DUP".into(), CauchemarVMRoutine::Native(|vm| {
+        let value = vm.pop()?;
+        vm.push(value.clone())?;
+        vm.push(value)?;
+        Ok(())
+    }));
+
+    routines.insert("SWAP".into(), CauchemarVMRoutine::Native(|vm| {
+        let a = vm.pop()?;
+        let b = vm.pop()?;
+        vm.push(a)?;
+        vm.push(b)?;
+        Ok(())
+    }));
+
+    routines.insert("ROT".into(), CauchemarVMRoutine::Native(|vm| {
+        let a = vm.pop()?;
+        let b = vm.pop()?;
+        let c = vm.pop()?;
+        vm.push(b)?;
+        vm.push(a)?;
+        vm.push(c)?;
+        Ok(())
+    }));
+
+    routines.insert("OVER".into(), CauchemarVMRoutine::Native(|vm| {
+        let a = vm.pop()?;
+        let b = vm.pop()?;
+        vm.push(b.clone())?;
+        vm.push(a)?;
+        vm.push(b)?;
+        Ok(())
+    }));
+
+    // ( a b -- a b a b )
+    routines.insert("2DUP".into(), CauchemarVMRoutine::Native(|vm| {
+        let a = vm.pop()?;
+        let b = vm.pop()?;
+        vm.push(b.clone())?;
+        vm.push(a.clone())?;
+        vm.push(b)?;
+        vm.push(a)?;
+        Ok(())
+    }));
+
+    // ( a b -- )
+    routines.insert("2DROP".into(), CauchemarVMRoutine::Native(|vm| {
+        vm.pop()?;
+        vm.pop()?;
+        Ok(())
+    }));
+
+    // ( a b c d -- c d a b )
+    routines.insert("2SWAP".into(), CauchemarVMRoutine::Native(|vm| {
+        let a = vm.pop()?;
+        let b = vm.pop()?;
+        let c = vm.pop()?;
+        let d = vm.pop()?;
+        vm.push(b)?;
+        vm.push(a)?;
+        vm.push(d)?;
+        vm.push(c)?;
+        Ok(())
+    }));
+
+    // ( a b c d -- a b c d a b )
+    routines.insert("2OVER".into(), CauchemarVMRoutine::Native(|vm| {
+        let a = vm.pop()?;
+        let b = vm.pop()?;
+        let c = vm.pop()?;
+        let d = vm.pop()?;
+        vm.push(d.clone())?;
+        vm.push(c.clone())?;
+        vm.push(b)?;
+        vm.push(a)?;
+        vm.push(d)?;
+        vm.push(c)?;
+        Ok(())
+    }));
+
+    // ( a b c 2 -- c b a ), swapping in place however deep it goes.
+    routines.insert("PICK-SWAP".into(), CauchemarVMRoutine::Native(|vm| {
+        let depth = vm.pop_number()?;
+        vm.swap_top(depth)
+    }));
+
+    // ( a b c -- c a b )
+    routines.insert("ROT-".into(), CauchemarVMRoutine::Native(|vm| {
+        let a = vm.pop()?;
+        let b = vm.pop()?;
+        let c = vm.pop()?;
+        vm.push(a)?;
+        vm.push(c)?;
+        vm.push(b)?;
+        Ok(())
+    }));
+
+    // ( a b c -- a b c a )
+    routines.insert("OVER2".into(), CauchemarVMRoutine::Native(|vm| {
+        let a = vm.pop()?;
+        let b = vm.pop()?;
+        let c = vm.pop()?;
+        vm.push(c.clone())?;
+        vm.push(b)?;
+        vm.push(a)?;
+        vm.push(c)?;
+        Ok(())
+    }));
+
+    // ( a b c -- c a b c )
+    routines.insert("UNDER".into(), CauchemarVMRoutine::Native(|vm| {
+        let a = vm.pop()?;
+        let b = vm.pop()?;
+        let c = vm.pop()?;
+        vm.push(a.clone())?;
+        vm.push(c)?;
+        vm.push(b)?;
+        vm.push(a)?;
+        Ok(())
+    }));
+
+    // ( a b c d -- c d a b c d ), the top pair tucked under the one below.
+    routines.insert("TUCK2".into(), CauchemarVMRoutine::Native(|vm| {
+        let a = vm.pop()?;
+        let b = vm.pop()?;
+        let c = vm.pop()?;
+        let d = vm.pop()?;
+        vm.push(b.clone())?;
+        vm.push(a.clone())?;
+        vm.push(d)?;
+        vm.push(c)?;
+        vm.push(b)?;
+        vm.push(a)?;
+        Ok(())
+    }));
+
+    // The native's own frame is on top while it runs, so it is left out.
+    routines.insert("SHOW-IP".into(), CauchemarVMRoutine::Native(|vm| {
+        let callers = &vm.ip[..vm.ip.len() - 1];
+        let frames = callers.iter().map(|(frame, _)| CauchemarVMValue::String(frame.to_string().into()));
+        vm.push(CauchemarVMValue::Array(frames.collect()))?;
+        Ok(())
+    }));
+
+    routines.insert("SHOW-STACK".into(), CauchemarVMRoutine::Native(|vm| {
+        vm.push(CauchemarVMValue::Array(vm.stack.clone()))?;
+        Ok(())
+    }));
+
+    routines.insert("STACK-FRAME-DEPTH".into(), CauchemarVMRoutine::Native(|vm| {
+        vm.push(CauchemarVMValue::Number((vm.ip.len() - 1) as i32))?;
+        Ok(())
+    }));
+
+    routines.insert("EQUALS".into(), CauchemarVMRoutine::Native(|vm| {
+        let b = vm.pop()?;
+        let a = vm.pop()?;
+        let equals = vm.equals(&a, &b)?;
+        vm.push(CauchemarVMValue::Bool(equals))?;
+        Ok(())
+    }));
+
+    routines.insert("NOT-EQUALS".into(), CauchemarVMRoutine::Native(|vm| {
+        let b = vm.pop()?;
+        let a = vm.pop()?;
+        let equals = vm.equals(&a, &b)?;
+        vm.push(CauchemarVMValue::Bool(!equals))?;
+        Ok(())
+    }));
+
+    routines.insert("NOT".into(), CauchemarVMRoutine::Native(|vm| {
+        let value = vm.pop_bool()?;
+        vm.push(CauchemarVMValue::Bool(!value))?;
+        Ok(())
+    }));
+
+    routines.insert("OR".into(), CauchemarVMRoutine::Native(|vm| {
+        let a = vm.pop_bool()?;
+        let b = vm.pop_bool()?;
+        vm.push(CauchemarVMValue::Bool(a || b))?;
+        Ok(())
+    }));
+
+    routines.insert("AND".into(), CauchemarVMRoutine::Native(|vm| {
+        let a = vm.pop_bool()?;
+        let b = vm.pop_bool()?;
+        vm.push(CauchemarVMValue::Bool(a && b))?;
+        Ok(())
+    }));
+
+    fn number_comparison<F>(vm: &mut CauchemarVM, f: F) -> Result<(), CauchemarError>
+    where
+        F: Fn(i32, i32) -> bool,
+    {
+        let b = vm.pop_number()?;
+        let a = vm.pop_number()?;
+        vm.push(CauchemarVMValue::Bool(f(a, b)))?;
+        Ok(())
+    }
+
+    routines.insert("GREATER-THAN".into(), CauchemarVMRoutine::Native(|vm| number_comparison(vm, |a, b| a > b)));
+    routines.insert("GREATER-EQUAL".into(), CauchemarVMRoutine::Native(|vm| number_comparison(vm, |a, b| a >= b)));
+    routines.insert("LESS-THAN".into(), CauchemarVMRoutine::Native(|vm| number_comparison(vm, |a, b| a < b)));
+    routines.insert("LESS-EQUAL".into(), CauchemarVMRoutine::Native(|vm| number_comparison(vm, |a, b| a <= b)));
+
+    routines.insert("GCD".into(), CauchemarVMRoutine::Native(|vm| {
+        binop(vm, |a, b| i32::try_from(gcd(a, b)).ok())
+    }));
+
+    routines.insert("LCM".into(), CauchemarVMRoutine::Native(|vm| {
+        binop(vm, |a, b| {
+            if a == 0 || b == 0 {
+                return Some(0);
+            }
+
+            let lcm = (a.unsigned_abs() / gcd(a, b)).checked_mul(b.unsigned_abs())?;
+            i32::try_from(lcm).ok()
+        })
+    }));
+
+    fn checked_binop<F>(vm: &mut CauchemarVM, f: F) -> Result<(), CauchemarError>
+    where
+        F: Fn(i32, i32) -> Option<i32>,
+    {
+        let b = vm.pop_number()?;
+        let a = vm.pop_number()?;
+
+        match f(a, b) {
+            Some(n) => {
+                vm.push(CauchemarVMValue::Number(n))?;
+                vm.push(CauchemarVMValue::Bool(true))?;
+            }
+            None => {
+                vm.push(CauchemarVMValue::Number(a))?;
+                vm.push(CauchemarVMValue::Number(b))?;
+                vm.push(CauchemarVMValue::Bool(false))?;
+            }
+        }
+        Ok(())
+    }
+
+    routines.insert("CHECKED-ADD".into(), CauchemarVMRoutine::Native(|vm| checked_binop(vm, |a, b| a.checked_add(b))));
+    routines.insert("CHECKED-SUB".into(), CauchemarVMRoutine::Native(|vm| checked_binop(vm, |a, b| a.checked_sub(b))));
+    routines.insert("CHECKED-MUL".into(), CauchemarVMRoutine::Native(|vm| checked_binop(vm, |a, b| a.checked_mul(b))));
+    routines.insert("CHECKED-DIV".into(), CauchemarVMRoutine::Native(|vm| checked_binop(vm, |a, b| a.checked_div(b))));
+
+    routines.insert("POPCOUNT".into(), CauchemarVMRoutine::Native(|vm| unop(vm, |n| n.count_ones() as i32)));
+    routines.insert("LEADING-ZEROS".into(), CauchemarVMRoutine::Native(|vm| unop(vm, |n| n.leading_zeros() as i32)));
+    routines.insert("TRAILING-ZEROS".into(), CauchemarVMRoutine::Native(|vm| unop(vm, |n| n.trailing_zeros() as i32)));
+    routines.insert("BIT-LENGTH".into(), CauchemarVMRoutine::Native(|vm| {
+        unop(vm, |n| (u32::BITS - n.unsigned_abs().leading_zeros()) as i32)
+    }));
+
+    routines.insert("ZERO?".into(), CauchemarVMRoutine::Native(|vm| predicate(vm, |n| n == 0)));
+    routines.insert("POSITIVE?".into(), CauchemarVMRoutine::Native(|vm| predicate(vm, |n| n > 0)));
+    routines.insert("NEGATIVE?".into(), CauchemarVMRoutine::Native(|vm| predicate(vm, |n| n < 0)));
+    routines.insert("EVEN?".into(), CauchemarVMRoutine::Native(|vm| predicate(vm, |n| n % 2 == 0)));
+    routines.insert("ODD?".into(), CauchemarVMRoutine::Native(|vm| predicate(vm, |n| n % 2 != 0)));
+    routines.insert("EMPTY?".into(), CauchemarVMRoutine::Native(|vm| {
+        let empty = match vm.pop()? {
+            CauchemarVMValue::String(s) => s.is_empty(),
+            CauchemarVMValue::Array(values) => values.is_empty(),
+            CauchemarVMValue::Map(entries) => entries.is_empty(),
+            value => return Err(CauchemarError::invalid_type("String, Array or Map", &value)),
+        };
+        vm.push(CauchemarVMValue::Bool(empty))?;
+        Ok(())
+    }));
+
+    routines.insert("BIG-INT".into(), CauchemarVMRoutine::Native(|vm| {
+        let n = vm.pop_number()?;
+        vm.push(CauchemarVMValue::BigInt(n.into()))?;
+        Ok(())
+    }));
+
+    routines.insert("BIG-INT-TO-NUMBER".into(), CauchemarVMRoutine::Native(|vm| {
+        let (sign, digits) = vm.pop_bigint()?.to_u32_digits();
+        let low = digits.first().copied().unwrap_or(0) as i32;
+        let n = match sign {
+            Sign::Minus => low.wrapping_neg(),
+            _ => low,
+        };
+        vm.push(CauchemarVMValue::Number(n))?;
+        Ok(())
+    }));
+
+    routines.insert("BIG-INT-STR".into(), CauchemarVMRoutine::Native(|vm| {
+        let n = vm.pop_bigint()?;
+        vm.push(CauchemarVMValue::String(n.to_string().into()))?;
+        Ok(())
+    }));
+
+    routines.insert("BASE64-ENCODE".into(), CauchemarVMRoutine::Native(|vm| {
+        let s = vm.pop_string()?;
+        vm.push(CauchemarVMValue::String(BASE64.encode(s).into()))?;
+        Ok(())
+    }));
+
+    routines.insert("BASE64-DECODE".into(), CauchemarVMRoutine::Native(|vm| {
+        let s = vm.pop_string()?;
+        let bytes = match BASE64.decode(s) {
+            Ok(bytes) => bytes,
+            Err(e) => return Err(CauchemarError::InvalidArgument(format!("invalid base64: {}", e))),
+        };
+        vm.push(CauchemarVMValue::String(decoded_string(bytes)?.into()))?;
+        Ok(())
+    }));
+
+    routines.insert("HEX-ENCODE".into(), CauchemarVMRoutine::Native(|vm| {
+        let s = vm.pop_string()?;
+        vm.push(CauchemarVMValue::String(hex_string(s.as_bytes()).into()))?;
+        Ok(())
+    }));
+
+    routines.insert("HEX-DECODE".into(), CauchemarVMRoutine::Native(|vm| {
+        let s = vm.pop_string()?;
+        let invalid = || CauchemarError::InvalidArgument(format!("invalid hex: {:?}", s));
+
+        if s.len() % 2 != 0 {
+            return Err(invalid());
+        }
+
+        let mut bytes = Vec::with_capacity(s.len() / 2);
+        for pair in s.as_bytes().chunks(2) {
+            let digits = std::str::from_utf8(pair).map_err(|_| invalid())?;
+            bytes.push(u8::from_str_radix(digits, 16).map_err(|_| invalid())?);
+        }
+
+        vm.push(CauchemarVMValue::String(decoded_string(bytes)?.into()))?;
+        Ok(())
+    }));
+
+    routines.insert("URL-ENCODE".into(), CauchemarVMRoutine::Native(|vm| {
+        let s = vm.pop_string()?;
+        let mut encoded = String::with_capacity(s.len());
+        for b in s.bytes() {
+            match b {
+                b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => encoded.push(b as char),
+                _ => encoded.push_str(&format!("%{:02X}", b)),
+            }
+        }
+        vm.push(CauchemarVMValue::String(encoded.into()))?;
+        Ok(())
+    }));
+
+    routines.insert("URL-DECODE".into(), CauchemarVMRoutine::Native(|vm| {
+        let s = vm.pop_string()?;
+        let invalid = || CauchemarError::InvalidArgument(format!("invalid percent-encoding: {:?}", s));
+
+        let mut bytes = Vec::with_capacity(s.len());
+        let mut input = s.bytes();
+        while let Some(b) = input.next() {
+            if b != b'%' {
+                bytes.push(b);
+                continue;
+            }
+
+            let digits = [input.next().ok_or_else(invalid)?, input.next().ok_or_else(invalid)?];
+            let digits = std::str::from_utf8(&digits).map_err(|_| invalid())?;
+            bytes.push(u8::from_str_radix(digits, 16).map_err(|_| invalid())?);
+        }
+
+        vm.push(CauchemarVMValue::String(decoded_string(bytes)?.into()))?;
+        Ok(())
+    }));
+
+    routines.insert("SHA256".into(), CauchemarVMRoutine::Native(|vm| {
+        let s = vm.pop_string()?;
+        vm.push(CauchemarVMValue::String(hex_string(&Sha256::digest(s)).into()))?;
+        Ok(())
+    }));
+
+    routines.insert("SHA512".into(), CauchemarVMRoutine::Native(|vm| {
+        let s = vm.pop_string()?;
+        vm.push(CauchemarVMValue::String(hex_string(&Sha512::digest(s)).into()))?;
+        Ok(())
+    }));
+
+    routines.insert("MD5".into(), CauchemarVMRoutine::Native(|vm| {
+        let s = vm.pop_string()?;
+        vm.push(CauchemarVMValue::String(hex_string(&Md5::digest(s)).into()))?;
+        Ok(())
+    }));
+
+    routines.insert("HMAC-SHA256".into(), CauchemarVMRoutine::Native(|vm| {
+        let key = vm.pop_string()?;
+        let message = vm.pop_string()?;
+
+        // HMAC accepts keys of any length, this can't fail.
+        let mut mac = Hmac::<Sha256>::new_from_slice(key.as_bytes()).unwrap();
+        mac.update(message.as_bytes());
+
+        vm.push(CauchemarVMValue::String(hex_string(&mac.finalize().into_bytes()).into()))?;
+        Ok(())
+    }));
+
+    routines.insert("ARRAY".into(), CauchemarVMRoutine::Native(|vm| {
+        let count = vm.pop_number()?;
+        let values = vm.take(count)?;
+        vm.push(CauchemarVMValue::Array(values))?;
+        Ok(())
+    }));
+
+    routines.insert("PRINT-TABLE".into(), CauchemarVMRoutine::Native(|vm| {
+        let rows = vm.pop_array()?;
+        let headers = vm.pop_array()?;
+
+        let headers = headers
+            .into_iter()
+            .map(|header| match header {
+                CauchemarVMValue::String(s) => Ok(s.to_string()),
+                value => Err(CauchemarError::invalid_type("String", &value)),
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        let rows = rows
+            .into_iter()
+            .map(|row| match row {
+                CauchemarVMValue::Array(cells) => Ok(cells.iter().map(|cell| cell.to_string()).collect()),
+                value => Err(CauchemarError::invalid_type("Array", &value)),
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+
+        vm.print(&display::format_table(&headers, &rows))
+    }));
+
+    routines.insert("ARRAY-LENGTH".into(), CauchemarVMRoutine::Native(|vm| {
+        let values = vm.pop_array()?;
+        vm.push(CauchemarVMValue::Number(values.len() as i32))?;
+        Ok(())
+    }));
+
+    routines.insert("ARRAY-GET".into(), CauchemarVMRoutine::Native(|vm| {
+        let index = vm.pop_number()?;
+        let mut values = vm.pop_array()?;
+        if index < 0 || index as usize >= values.len() {
+            return Err(CauchemarError::InvalidArgument(format!(
+                "index {} out of bounds for array of length {}",
+                index,
+                values.len(),
+            )));
+        }
+        vm.push(values.swap_remove(index as usize))?;
+        Ok(())
+    }));
+
+    routines.insert("MAP-GET".into(), CauchemarVMRoutine::Native(|vm| {
+        let key = vm.pop_string()?;
+        let mut entries = vm.pop_map()?;
+        match entries.remove(&key) {
+            Some(value) => vm.push(value)?,
+            None => return Err(CauchemarError::InvalidArgument(format!("no {:?} key in map", key))),
+        }
+        Ok(())
+    }));
+
+    routines.insert("GLOBAL-SET".into(), CauchemarVMRoutine::Native(|vm| {
+        let name = vm.pop_string()?;
+        let value = vm.pop()?;
+        vm.globals.insert(name, value);
+        Ok(())
+    }));
+
+    routines.insert("GLOBAL-GET".into(), CauchemarVMRoutine::Native(|vm| {
+        let name = vm.pop_string()?;
+        match vm.globals.get(&name) {
+            Some(value) => vm.push(value.clone())?,
+            None => return Err(CauchemarError::InvalidArgument(format!("no {:?} global", name))),
+        }
+        Ok(())
+    }));
+
+    routines.insert("GLOBAL-EXISTS?".into(), CauchemarVMRoutine::Native(|vm| {
+        let name = vm.pop_string()?;
+        vm.push(CauchemarVMValue::Bool(vm.globals.contains_key(&name)))?;
+        Ok(())
+    }));
+
+    routines.insert("GLOBAL-DELETE".into(), CauchemarVMRoutine::Native(|vm| {
+        let name = vm.pop_string()?;
+        vm.globals.remove(&name);
+        Ok(())
+    }));
+
+    routines.insert("GLOBAL-KEYS".into(), CauchemarVMRoutine::Native(|vm| {
+        let mut names: Vec<_> = vm.globals.keys().cloned().collect();
+        names.sort_unstable();
+        let names = names.into_iter().map(|name| CauchemarVMValue::String(name.into()));
+        vm.push(CauchemarVMValue::Array(names.collect()))?;
+        Ok(())
+    }));
+
+    routines.insert("JSON-PARSE".into(), CauchemarVMRoutine::Native(|vm| {
+        let text = vm.pop_string()?;
+        let json = match serde_json::from_str(&text) {
+            Ok(json) => json,
+            Err(e) => return Err(CauchemarError::InvalidArgument(format!("invalid JSON: {}", e))),
+        };
+        vm.push(from_json(json)?)?;
+        Ok(())
+    }));
+
+    routines.insert("JSON-STRINGIFY".into(), CauchemarVMRoutine::Native(|vm| {
+        let json = to_json(&vm.pop()?)?;
+        vm.push(CauchemarVMValue::String(json.to_string().into()))?;
+        Ok(())
+    }));
+
+    routines.insert("JSON-STRINGIFY-PRETTY".into(), CauchemarVMRoutine::Native(|vm| {
+        let json = to_json(&vm.pop()?)?;
+        let pretty = serde_json::to_string_pretty(&json).expect("JSON values always serialize");
+        vm.push(CauchemarVMValue::String(pretty.into()))?;
+        Ok(())
+    }));
+
+    routines.insert("TOML-PARSE".into(), CauchemarVMRoutine::Native(|vm| {
+        let text = vm.pop_string()?;
+        let table = match text.parse::<toml::Table>() {
+            Ok(table) => table,
+            Err(e) => return Err(CauchemarError::InvalidArgument(format!("invalid TOML: {}", e))),
+        };
+        vm.push(from_toml(toml::Value::Table(table))?)?;
+        Ok(())
+    }));
+
+    routines.insert("TOML-STRINGIFY".into(), CauchemarVMRoutine::Native(|vm| {
+        let entries = vm.pop_map()?;
+        let table = entries
+            .iter()
+            .map(|(k, v)| Ok((k.clone(), to_toml(v)?)))
+            .collect::<Result<toml::Table, CauchemarError>>()?;
+        match toml::to_string(&table) {
+            Ok(text) => vm.push(CauchemarVMValue::String(text.into()))?,
+            Err(e) => return Err(CauchemarError::InvalidArgument(format!("unable to write TOML: {}", e))),
+        }
+        Ok(())
+    }));
+
+    routines.insert("CSV-PARSE".into(), CauchemarVMRoutine::Native(|vm| {
+        let text = vm.pop_string()?;
+        let mut reader = csv::ReaderBuilder::new().has_headers(false).flexible(true).from_reader(text.as_bytes());
+        let mut rows = Vec::new();
+        for record in reader.records() {
+            let record = record.map_err(invalid_csv)?;
+            rows.push(CauchemarVMValue::Array(
+                record.iter().map(|cell| CauchemarVMValue::String(cell.into())).collect(),
+            ));
+        }
+        vm.push(CauchemarVMValue::Array(rows))?;
+        Ok(())
+    }));
+
+    routines.insert("CSV-PARSE-WITH-HEADERS".into(), CauchemarVMRoutine::Native(|vm| {
+        let text = vm.pop_string()?;
+        let mut reader = csv::ReaderBuilder::new().from_reader(text.as_bytes());
+        let headers = reader.headers().map_err(invalid_csv)?.clone();
+        let mut rows = Vec::new();
+        for record in reader.records() {
+            let record = record.map_err(invalid_csv)?;
+            let entries = headers
+                .iter()
+                .zip(record.iter())
+                .map(|(header, cell)| (header.to_string(), CauchemarVMValue::String(cell.into())));
+            rows.push(CauchemarVMValue::Map(entries.collect()));
+        }
+        vm.push(CauchemarVMValue::Array(rows))?;
+        Ok(())
+    }));
+
+    routines.insert("CSV-STRINGIFY".into(), CauchemarVMRoutine::Native(|vm| {
+        let rows = vm.pop_array()?;
+        let mut writer = csv::WriterBuilder::new().flexible(true).from_writer(Vec::new());
+        for row in rows {
+            let cells = match row {
+                CauchemarVMValue::Array(cells) => cells,
+                value => return Err(CauchemarError::invalid_type("Array", &value)),
+            };
+            let cells: Vec<String> = cells.iter().map(|cell| cell.to_string()).collect();
+            writer.write_record(&cells).map_err(invalid_csv)?;
+        }
+        let bytes = match writer.into_inner() {
+            Ok(bytes) => bytes,
+            Err(e) => return Err(CauchemarError::InvalidArgument(format!("unable to write CSV: {}", e))),
+        };
+        vm.push(CauchemarVMValue::String(decoded_string(bytes)?.into()))?;
+        Ok(())
+    }));
+
+    routines.insert("EXIT".into(), CauchemarVMRoutine::Native(|vm| {
+        let status = vm.pop_number()?;
+        match u8::try_from(status) {
+            Ok(status) => vm.exit = Some(status),
+            Err(_) => {
+                return Err(CauchemarError::InvalidArgument(format!("exit status {} is not between 0 and 255", status)))
+            }
+        }
+        Ok(())
+    }));
+
+    routines.insert("ARG-COUNT".into(), CauchemarVMRoutine::Native(|vm| {
+        vm.push(CauchemarVMValue::Number(vm.args.len() as i32))?;
+        Ok(())
+    }));
+
+    routines.insert("ARG".into(), CauchemarVMRoutine::Native(|vm| {
+        let index = vm.pop_number()?;
+        if index < 0 || index as usize >= vm.args.len() {
+            return Err(CauchemarError::InvalidArgument(format!(
+                "argument {} out of bounds, {} given",
+                index,
+                vm.args.len(),
+            )));
+        }
+        vm.push(CauchemarVMValue::String(vm.args[index as usize].as_str().into()))?;
+        Ok(())
+    }));
+
+    routines.insert("ARGS".into(), CauchemarVMRoutine::Native(|vm| {
+        let args = vm.args.iter().map(|arg| CauchemarVMValue::String(arg.as_str().into())).collect();
+        vm.push(CauchemarVMValue::Array(args))?;
+        Ok(())
+    }));
+
+    fn read_line(vm: &mut CauchemarVM) -> Result<(), CauchemarError> {
+        let mut line = String::new();
+        match vm.input.reader.read_line(&mut line) {
+            Ok(0) => vm.push(CauchemarVMValue::Bool(false))?,
+            Ok(_) => {
+                let end = line.trim_end_matches(['\n', '\r']).len();
+                line.truncate(end);
+                vm.push(CauchemarVMValue::String(line.into()))?;
+            }
+            Err(e) => return Err(CauchemarError::FileAccess(PathBuf::from(STDIN_NAME), e)),
+        }
+        Ok(())
+    }
+
+    routines.insert("READ-LINE".into(), CauchemarVMRoutine::Native(read_line));
+
+    routines.insert("PROMPT".into(), CauchemarVMRoutine::Native(|vm| {
+        let prompt = vm.pop_string()?;
+        // Nobody would see it when the input is piped in.
+        if vm.input.terminal {
+            vm.print(&prompt)?;
+            if let Err(e) = vm.output.0.flush() {
+                return Err(CauchemarError::FileAccess(PathBuf::from(STDOUT_NAME), e));
+            }
+        }
+        read_line(vm)
+    }));
+
+    routines.insert("SYSTEM".into(), CauchemarVMRoutine::Native(|vm| {
+        vm.check_exec("SYSTEM")?;
+        let command = vm.pop_string()?;
+        let status = match shell(&command).status() {
+            Ok(status) => status,
+            Err(e) => return Err(CauchemarError::Spawn(command, e)),
+        };
+        vm.push(CauchemarVMValue::Number(status.code().unwrap_or(-1)))?;
+        Ok(())
+    }));
+
+    routines.insert("SYSTEM-OUTPUT".into(), CauchemarVMRoutine::Native(|vm| {
+        vm.check_exec("SYSTEM-OUTPUT")?;
+        let command = vm.pop_string()?;
+        let output = match shell(&command).stderr(Stdio::inherit()).output() {
+            Ok(output) => output,
+            Err(e) => return Err(CauchemarError::Spawn(command, e)),
+        };
+        vm.push(CauchemarVMValue::String(decoded_string(output.stdout)?.into()))?;
+        vm.push(CauchemarVMValue::Number(output.status.code().unwrap_or(-1)))?;
+        Ok(())
+    }));
+
+    routines.insert("HTTP-GET".into(), CauchemarVMRoutine::Native(|vm| {
+        let url = vm.pop_string()?;
+        let response = ureq::get(&url).call();
+        http_response(vm, url, response)
+    }));
+
+    routines.insert("HTTP-POST".into(), CauchemarVMRoutine::Native(|vm| {
+        let body = vm.pop_string()?;
+        let content_type = vm.pop_string()?;
+        let url = vm.pop_string()?;
+        let response = ureq::post(&url).set("Content-Type", &content_type).send_string(&body);
+        http_response(vm, url, response)
+    }));
+
+    routines.insert("HTTP-STATUS".into(), CauchemarVMRoutine::Native(|vm| {
+        match vm.http_status {
+            Some(status) => vm.push(CauchemarVMValue::Number(status.into()))?,
+            None => vm.push(CauchemarVMValue::Bool(false))?,
+        }
+        Ok(())
+    }));
+
+    routines.insert("SOCKET-CONNECT".into(), CauchemarVMRoutine::Native(|vm| {
+        let port = vm.pop_number()?;
+        let host = vm.pop_string()?;
+        let Ok(port) = u16::try_from(port) else {
+            return Err(CauchemarError::InvalidArgument(format!("port {} is not between 0 and 65535", port)));
+        };
+        let stream = match TcpStream::connect((host.as_str(), port)) {
+            Ok(stream) => stream,
+            Err(e) => return Err(CauchemarError::Socket(format!("{}:{}", host, port), e)),
+        };
+        let handle = vm.insert_handle(Box::new(stream));
+        vm.push(CauchemarVMValue::Handle(handle))?;
+        Ok(())
+    }));
+
+    routines.insert("SOCKET-SEND".into(), CauchemarVMRoutine::Native(|vm| {
+        let data = vm.pop_string()?;
+        let handle = vm.pop_handle()?;
+        if let Err(e) = socket(vm, handle)?.write_all(data.as_bytes()) {
+            return Err(CauchemarError::Socket(handle.to_string(), e));
+        }
+        Ok(())
+    }));
+
+    // Whatever has arrived so far, up to 4 KiB, and an empty string once the
+    // other end has closed the connection.
+    routines.insert("SOCKET-RECEIVE".into(), CauchemarVMRoutine::Native(|vm| {
+        let handle = vm.pop_handle()?;
+        let mut buffer = vec![0; 4096];
+        let read = match socket(vm, handle)?.read(&mut buffer) {
+            Ok(read) => read,
+            Err(e) => return Err(CauchemarError::Socket(handle.to_string(), e)),
+        };
+        buffer.truncate(read);
+        vm.push(CauchemarVMValue::String(decoded_string(buffer)?.into()))?;
+        Ok(())
+    }));
+
+    routines.insert("SOCKET-CLOSE".into(), CauchemarVMRoutine::Native(|vm| {
+        let handle = vm.pop_handle()?;
+        socket(vm, handle)?;
+        vm.handles.remove(&handle);
+        Ok(())
+    }));
+
+    routines.insert("PROCESS-SPAWN".into(), CauchemarVMRoutine::Native(|vm| {
+        vm.check_exec("PROCESS-SPAWN")?;
+        let argv = vm
+            .pop_array()?
+            .into_iter()
+            .map(|arg| match arg {
+                CauchemarVMValue::String(s) => Ok(s.to_string()),
+                value => Err(CauchemarError::invalid_type("String", &value)),
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        let Some((program, args)) = argv.split_first() else {
+            return Err(CauchemarError::InvalidArgument("cannot spawn an empty command".to_string()));
+        };
+        let child = process::Command::new(program)
+            .args(args)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .spawn();
+        let child = match child {
+            Ok(child) => child,
+            Err(e) => return Err(CauchemarError::Spawn(argv.join(" "), e)),
+        };
+        let handle = vm.insert_handle(Box::new(child));
+        vm.push(CauchemarVMValue::Handle(handle))?;
+        Ok(())
+    }));
+
+    routines.insert("PROCESS-INPUT".into(), CauchemarVMRoutine::Native(|vm| {
+        let data = vm.pop_string()?;
+        let handle = vm.pop_handle()?;
+        let Some(stdin) = process(vm, handle)?.stdin.as_mut() else {
+            return Err(CauchemarError::InvalidArgument(format!("input of process {} is closed", handle)));
+        };
+        if let Err(e) = stdin.write_all(data.as_bytes()) {
+            return Err(CauchemarError::Spawn(format!("process {}", handle), e));
+        }
+        Ok(())
+    }));
+
+    // Closes the input of the process first, so one waiting for the end of
+    // its input doesn't keep us waiting for its output.
+    routines.insert("PROCESS-OUTPUT".into(), CauchemarVMRoutine::Native(|vm| {
+        let handle = vm.pop_handle()?;
+        let child = process(vm, handle)?;
+        drop(child.stdin.take());
+        let mut output = Vec::new();
+        if let Some(mut stdout) = child.stdout.take() {
+            if let Err(e) = stdout.read_to_end(&mut output) {
+                return Err(CauchemarError::Spawn(format!("process {}", handle), e));
+            }
+        }
+        vm.push(CauchemarVMValue::String(decoded_string(output)?.into()))?;
+        Ok(())
+    }));
+
+    routines.insert("PROCESS-WAIT".into(), CauchemarVMRoutine::Native(|vm| {
+        let handle = vm.pop_handle()?;
+        let child = process(vm, handle)?;
+        drop(child.stdin.take());
+        let status = match child.wait() {
+            Ok(status) => status,
+            Err(e) => return Err(CauchemarError::Spawn(format!("process {}", handle), e)),
+        };
+        vm.handles.remove(&handle);
+        vm.push(CauchemarVMValue::Number(status.code().unwrap_or(-1)))?;
+        Ok(())
+    }));
+
+    routines.insert("THREAD-SPAWN".into(), CauchemarVMRoutine::Native(|vm| {
+        let name = vm.pop_string()?;
+        let Some((routine, _)) = vm.routines.get_key_value(name.as_str()) else {
+            return Err(CauchemarError::UnknownRoutine(name));
+        };
+        let mut forked = vm.fork(routine.clone());
+        let thread: CauchemarThread = thread::spawn(move || {
+            forked.run()?;
+            Ok(forked.stack)
+        });
+        let handle = vm.insert_handle(Box::new(thread));
+        vm.push(CauchemarVMValue::Handle(handle))?;
+        Ok(())
+    }));
+
+    // Errors in the thread are raised here, its stack is pushed as an Array.
+    routines.insert("THREAD-JOIN".into(), CauchemarVMRoutine::Native(|vm| {
+        let handle = vm.pop_handle()?;
+        resource::<CauchemarThread>(vm, handle, "a running thread")?;
+        let thread = vm.handles.remove(&handle).unwrap().downcast::<CauchemarThread>().unwrap();
+        match thread.join() {
+            Ok(stack) => vm.push(CauchemarVMValue::Array(stack?))?,
+            Err(payload) => panic::resume_unwind(payload),
+        }
+        Ok(())
+    }));
+
+    routines.insert("CHANNEL-NEW".into(), CauchemarVMRoutine::Native(|vm| {
+        let (sender, receiver) = mpsc::channel();
+        let channel = CauchemarChannel { sender: Mutex::new(sender), receiver: Mutex::new(receiver) };
+        let handle = vm.insert_handle(Box::new(Arc::new(channel)));
+        vm.push(CauchemarVMValue::Handle(handle))?;
+        Ok(())
+    }));
+
+    routines.insert("CHANNEL-SEND".into(), CauchemarVMRoutine::Native(|vm| {
+        let value = vm.pop()?;
+        let handle = vm.pop_handle()?;
+        let channel = resource::<Arc<CauchemarChannel>>(vm, handle, "a channel")?;
+        // The channel holds a receiver itself, so sending can't fail.
+        channel.sender.lock().unwrap().send(value).unwrap();
+        Ok(())
+    }));
+
+    // Blocks until a value is sent, by this thread or another.
+    routines.insert("CHANNEL-RECEIVE".into(), CauchemarVMRoutine::Native(|vm| {
+        let handle = vm.pop_handle()?;
+        let channel = resource::<Arc<CauchemarChannel>>(vm, handle, "a channel")?.clone();
+        let value = channel.receiver.lock().unwrap().recv().unwrap();
+        vm.push(value)?;
+        Ok(())
+    }));
+
+    routines.insert("MUTEX-NEW".into(), CauchemarVMRoutine::Native(|vm| {
+        let handle = vm.insert_handle(Box::new(Arc::new(CauchemarMutex::default())));
+        vm.push(CauchemarVMValue::Handle(handle))?;
+        Ok(())
+    }));
+
+    // Pushes a guard for MUTEX-UNLOCK and whether a thread failed while
+    // holding the lock before.
+    routines.insert("MUTEX-LOCK".into(), CauchemarVMRoutine::Native(|vm| {
+        let handle = vm.pop_handle()?;
+        let mutex = resource::<Arc<CauchemarMutex>>(vm, handle, "a mutex")?.clone();
+        let guard = mutex.lock();
+        let guard = vm.insert_handle(Box::new(guard));
+        vm.push(CauchemarVMValue::Handle(guard))?;
+        vm.push(CauchemarVMValue::Bool(mutex.poisoned.load(Ordering::Relaxed)))?;
+        Ok(())
+    }));
+
+    routines.insert("MUTEX-UNLOCK".into(), CauchemarVMRoutine::Native(|vm| {
+        let handle = vm.pop_handle()?;
+        resource::<CauchemarMutexGuard>(vm, handle, "a mutex guard")?;
+        vm.handles.remove(&handle).unwrap().downcast::<CauchemarMutexGuard>().unwrap().unlock();
+        Ok(())
+    }));
+
+    routines.insert("GETENV".into(), CauchemarVMRoutine::Native(|vm| {
+        let name = vm.pop_string()?;
+        match env::var(&name) {
+            Ok(value) => vm.push(CauchemarVMValue::String(value.into()))?,
+            Err(env::VarError::NotPresent) => vm.push(CauchemarVMValue::Bool(false))?,
+            Err(env::VarError::NotUnicode(_)) => {
+                return Err(CauchemarError::InvalidArgument(format!(
+                    "environment variable {} is not valid UTF-8",
+                    name,
+                )))
+            }
+        }
+        Ok(())
+    }));
+
+    routines.insert("REGEX-MATCH?".into(), CauchemarVMRoutine::Native(|vm| {
+        let pattern = vm.pop_string()?;
+        let subject = vm.pop_string()?;
+        let regex = vm.regex(format!("^(?:{})$", pattern))?;
+        vm.push(CauchemarVMValue::Bool(regex.is_match(&subject)))?;
+        Ok(())
+    }));
+
+    routines.insert("REGEX-FIND".into(), CauchemarVMRoutine::Native(|vm| {
+        let pattern = vm.pop_string()?;
+        let subject = vm.pop_string()?;
+        let regex = vm.regex(pattern)?;
+        let found = regex.find(&subject).map(|m| m.as_str()).unwrap_or("");
+        vm.push(CauchemarVMValue::String(found.into()))?;
+        Ok(())
+    }));
+
+    routines.insert("REGEX-FIND-ALL".into(), CauchemarVMRoutine::Native(|vm| {
+        let pattern = vm.pop_string()?;
+        let subject = vm.pop_string()?;
+        let regex = vm.regex(pattern)?;
+        let found = regex
+            .find_iter(&subject)
+            .map(|m| CauchemarVMValue::String(m.as_str().into()))
+            .collect();
+        vm.push(CauchemarVMValue::Array(found))?;
+        Ok(())
+    }));
+
+    routines.insert("REGEX-REPLACE".into(), CauchemarVMRoutine::Native(|vm| {
+        let replacement = vm.pop_string()?;
+        let pattern = vm.pop_string()?;
+        let subject = vm.pop_string()?;
+        let regex = vm.regex(pattern)?;
+        let replaced = regex.replace_all(&subject, replacement.as_str()).into_owned();
+        vm.push(CauchemarVMValue::String(replaced.into()))?;
+        Ok(())
+    }));
+
+    routines.insert("ASSERT".into(), CauchemarVMRoutine::Native(|vm| {
+        if !vm.pop_bool()? {
+            return Err(CauchemarError::AssertionFailed {
+                routine: vm.caller().to_string(),
+                detail: None,
+            });
+        }
+        Ok(())
+    }));
+
+    routines.insert("ASSERT-EQUALS".into(), CauchemarVMRoutine::Native(|vm| {
+        let actual = vm.pop()?;
+        let expected = vm.pop()?;
+
+        if !vm.equals(&expected, &actual)? {
+            return Err(CauchemarError::AssertionFailed {
+                routine: vm.caller().to_string(),
+                detail: Some(format!("expected {} but got {}", expected.repr(), actual.repr())),
+            });
+        }
+        Ok(())
+    }));
+
+    routines.insert("FORCE".into(), CauchemarVMRoutine::Native(|vm| {
+        let thunk = match vm.pop()? {
+            CauchemarVMValue::Thunk(instructions) => instructions,
+            value => return Err(CauchemarError::invalid_type("Thunk", &value)),
+        };
+
+        vm.check_depth()?;
+
+        // The frame on top belongs to FORCE itself and is dropped once this
+        // native returns, so the thunk has to be slotted in right below it.
+        let native_frame = vm.ip.len() - 1;
+        vm.ip.insert(native_frame, (CauchemarVMFrame::Thunk(Arc::new(thunk)), 0));
+        Ok(())
+    }));
+
+    routines.insert("CALL".into(), CauchemarVMRoutine::Native(|vm| {
+        let name = vm.pop_string()?;
+        if !vm.routines.contains_key(name.as_str()) {
+            return Err(CauchemarError::UnknownRoutine(name));
+        }
+        vm.check_depth()?;
+
+        // Same as FORCE, run the routine once this native's frame is gone.
+        let native_frame = vm.ip.len() - 1;
+        vm.ip.insert(native_frame, (CauchemarVMFrame::Routine(name.into()), 0));
+        Ok(())
+    }));
+
+    routines.insert("READ-FILE".into(), CauchemarVMRoutine::Native(|vm| {
+        vm.check_fs("READ-FILE")?;
+        let path = PathBuf::from(vm.pop_string()?);
+        match fs::read_to_string(&path) {
+            Ok(contents) => vm.push(CauchemarVMValue::String(contents.into()))?,
+            Err(e) => return Err(CauchemarError::FileAccess(path, e)),
+        }
+        Ok(())
+    }));
+
+    routines.insert("WRITE-FILE".into(), CauchemarVMRoutine::Native(|vm| {
+        vm.check_fs("WRITE-FILE")?;
+        let contents = vm.pop_string()?;
+        let path = PathBuf::from(vm.pop_string()?);
+        match fs::write(&path, contents) {
+            Ok(()) => Ok(()),
+            Err(e) => Err(CauchemarError::FileAccess(path, e)),
+        }
+    }));
+
+    routines.insert("APPEND-FILE".into(), CauchemarVMRoutine::Native(|vm| {
+        vm.check_fs("APPEND-FILE")?;
+        let contents = vm.pop_string()?;
+        let path = PathBuf::from(vm.pop_string()?);
+        let result = fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&path)
+            .and_then(|mut file| file.write_all(contents.as_bytes()));
+        match result {
+            Ok(()) => Ok(()),
+            Err(e) => Err(CauchemarError::FileAccess(path, e)),
+        }
+    }));
+
+    routines.insert("FILE-EXISTS?".into(), CauchemarVMRoutine::Native(|vm| {
+        vm.check_fs("FILE-EXISTS?")?;
+        let path = PathBuf::from(vm.pop_string()?);
+        vm.push(CauchemarVMValue::Bool(path.exists()))?;
+        Ok(())
+    }));
+
+    routines.insert("LOAD-FILE".into(), CauchemarVMRoutine::Native(|vm| {
+        vm.check_fs("LOAD-FILE")?;
+        let path = PathBuf::from(vm.pop_string()?);
+        let canonical = match fs::canonicalize(&path) {
+            Ok(canonical) => canonical,
+            Err(e) => return Err(CauchemarError::Io(path, e)),
+        };
+        if !vm.loaded_files.insert(canonical) {
+            return Ok(());
+        }
+
+        let source = match fs::read_to_string(&path) {
+            Ok(source) => source,
+            Err(e) => return Err(CauchemarError::Io(path, e)),
+        };
+        let program = match parse_cauchemar_file(&source) {
+            Ok(program) => program,
+            Err(e) => return Err(CauchemarError::Parse(e.with_path(&path.display().to_string()))),
+        };
+
+        let names = compile_user_routines(&mut vm.routines, program);
+        check_routines(&vm.routines, &names)
+    }));
+
+    routines.insert("EVAL".into(), CauchemarVMRoutine::Native(|vm| {
+        let code = vm.pop_string()?;
+        let mut program = match parse_cauchemar_snippet(&code) {
+            Ok(program) => program,
+            Err(e) => return Err(CauchemarError::Parse(e)),
+        };
+
+        // The snippet body runs as an anonymous frame rather than as PROGRAM
+        // so it doesn't replace the routine that is currently running.
+        let mut body = Vec::new();
+        if let Some(commands) = program.routines.remove("PROGRAM") {
+            compile_routine(&mut body, commands, &program.constants, &program.macros);
+        }
+        body.push(CauchemarVMInstruction::Return);
+
+        let names = compile_user_routines(&mut vm.routines, program);
+        check_routines(&vm.routines, &names)?;
+        check_calls(&vm.routines, &body)?;
+        vm.check_depth()?;
+
+        let native_frame = vm.ip.len() - 1;
+        vm.ip.insert(native_frame, (CauchemarVMFrame::Thunk(Arc::new(body)), 0));
+        Ok(())
+    }));
+}
+
+/// A Number when it fits, a BigInt otherwise.
+fn number_or_big_int(n: u64) -> CauchemarVMValue {
+    match i32::try_from(n) {
+        Ok(n) => CauchemarVMValue::Number(n),
+        Err(_) => CauchemarVMValue::BigInt(BigInt::from(n)),
+    }
+}
+
+fn hex_string(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// Convert parsed JSON, null becomes FALSE as there is no null value.
+fn from_json(json: serde_json::Value) -> Result<CauchemarVMValue, CauchemarError> {
+    Ok(match json {
+        serde_json::Value::Null => CauchemarVMValue::Bool(false),
+        serde_json::Value::Bool(b) => CauchemarVMValue::Bool(b),
+        serde_json::Value::Number(n) => match (n.as_i64(), n.as_u64()) {
+            (Some(i), _) => match i32::try_from(i) {
+                Ok(i) => CauchemarVMValue::Number(i),
+                Err(_) => CauchemarVMValue::BigInt(BigInt::from(i)),
+            },
+            (None, Some(u)) => CauchemarVMValue::BigInt(BigInt::from(u)),
+            (None, None) => {
+                return Err(CauchemarError::InvalidArgument(format!("JSON number {} is not an integer", n)))
+            }
+        },
+        serde_json::Value::String(s) => CauchemarVMValue::String(s.into()),
+        serde_json::Value::Array(values) => {
+            CauchemarVMValue::Array(values.into_iter().map(from_json).collect::<Result<_, _>>()?)
+        }
+        serde_json::Value::Object(entries) => CauchemarVMValue::Map(
+            entries
+                .into_iter()
+                .map(|(k, v)| Ok((k, from_json(v)?)))
+                .collect::<Result<_, CauchemarError>>()?,
+        ),
+    })
+}
+
+fn to_json(value: &CauchemarVMValue) -> Result<serde_json::Value, CauchemarError> {
+    Ok(match value {
+        CauchemarVMValue::Number(n) => (*n).into(),
+        CauchemarVMValue::BigInt(n) => match (i64::try_from(n), u64::try_from(n)) {
+            (Ok(i), _) => i.into(),
+            (_, Ok(u)) => u.into(),
+            _ => {
+                return Err(CauchemarError::InvalidArgument(format!("{} is too large for JSON", value.repr())))
+            }
+        },
+        CauchemarVMValue::Float(n) => match serde_json::Number::from_f64(*n) {
+            Some(n) => serde_json::Value::Number(n),
+            None => return Err(CauchemarError::InvalidArgument(format!("{} can't be written as JSON", n))),
+        },
+        CauchemarVMValue::Bool(b) => serde_json::Value::Bool(*b),
+        CauchemarVMValue::String(s) => serde_json::Value::String(s.to_string()),
+        CauchemarVMValue::Array(values) => {
+            serde_json::Value::Array(values.iter().map(to_json).collect::<Result<_, _>>()?)
+        }
+        CauchemarVMValue::Map(entries) => serde_json::Value::Object(
+            entries
+                .iter()
+                .map(|(k, v)| Ok((k.clone(), to_json(v)?)))
+                .collect::<Result<_, CauchemarError>>()?,
+        ),
+        CauchemarVMValue::Thunk(_) | CauchemarVMValue::Handle(_) => {
+            return Err(CauchemarError::invalid_type("value other than a Thunk or Handle", value))
+        }
+    })
+}
+
+/// Convert parsed TOML, dates and times are kept as they were written.
+fn from_toml(value: toml::Value) -> Result<CauchemarVMValue, CauchemarError> {
+    Ok(match value {
+        toml::Value::String(s) => CauchemarVMValue::String(s.into()),
+        toml::Value::Integer(i) => match i32::try_from(i) {
+            Ok(i) => CauchemarVMValue::Number(i),
+            Err(_) => CauchemarVMValue::BigInt(BigInt::from(i)),
+        },
+        toml::Value::Float(f) => CauchemarVMValue::Float(f),
+        toml::Value::Boolean(b) => CauchemarVMValue::Bool(b),
+        toml::Value::Datetime(d) => CauchemarVMValue::String(d.to_string().into()),
+        toml::Value::Array(values) => {
+            CauchemarVMValue::Array(values.into_iter().map(from_toml).collect::<Result<_, _>>()?)
+        }
+        toml::Value::Table(entries) => CauchemarVMValue::Map(
+            entries
+                .into_iter()
+                .map(|(k, v)| Ok((k, from_toml(v)?)))
+                .collect::<Result<_, CauchemarError>>()?,
+        ),
+    })
+}
+
+fn to_toml(value: &CauchemarVMValue) -> Result<toml::Value, CauchemarError> {
+    Ok(match value {
+        CauchemarVMValue::Number(n) => toml::Value::Integer((*n).into()),
+        CauchemarVMValue::BigInt(n) => match i64::try_from(n) {
+            Ok(i) => toml::Value::Integer(i),
+            Err(_) => {
+                return Err(CauchemarError::InvalidArgument(format!("{} is too large for TOML", value.repr())))
+            }
+        },
+        CauchemarVMValue::Float(n) => toml::Value::Float(*n),
+        CauchemarVMValue::Bool(b) => toml::Value::Boolean(*b),
+        CauchemarVMValue::String(s) => toml::Value::String(s.to_string()),
+        CauchemarVMValue::Array(values) => toml::Value::Array(values.iter().map(to_toml).collect::<Result<_, _>>()?),
+        CauchemarVMValue::Map(entries) => toml::Value::Table(
+            entries
+                .iter()
+                .map(|(k, v)| Ok((k.clone(), to_toml(v)?)))
+                .collect::<Result<_, CauchemarError>>()?,
+        ),
+        CauchemarVMValue::Thunk(_) | CauchemarVMValue::Handle(_) => {
+            return Err(CauchemarError::invalid_type("value other than a Thunk or Handle", value))
+        }
+    })
+}
+
+fn invalid_csv(e: csv::Error) -> CauchemarError {
+    CauchemarError::InvalidArgument(format!("invalid CSV: {}", e))
+}
+
+/// Print an SGR escape sequence, only when stdout is a terminal so piped
+/// output stays clean.
+fn ansi(vm: &mut CauchemarVM, code: &str) -> Result<(), CauchemarError> {
+    match vm.color {
+        true => vm.print(&format!("\x1b[{}m", code)),
+        false => Ok(()),
+    }
+}
+
+/// Pop a color of the 256 color palette.
+fn pop_color(vm: &mut CauchemarVM) -> Result<u8, CauchemarError> {
+    let color = vm.pop_number()?;
+    match u8::try_from(color) {
+        Ok(color) => Ok(color),
+        Err(_) => Err(CauchemarError::InvalidArgument(format!("color {} is not between 0 and 255", color))),
+    }
+}
+
+/// Push the body of a response, error statuses included so the program can
+/// look at them with HTTP-STATUS.
+fn http_response(
+    vm: &mut CauchemarVM,
+    url: String,
+    response: Result<ureq::Response, ureq::Error>,
+) -> Result<(), CauchemarError> {
+    let response = match response {
+        Ok(response) | Err(ureq::Error::Status(_, response)) => response,
+        Err(e) => return Err(CauchemarError::IoError(url, io::Error::other(e.to_string()))),
+    };
+    vm.http_status = Some(response.status());
+    match response.into_string() {
+        Ok(body) => vm.push(CauchemarVMValue::String(body.into()))?,
+        Err(e) => return Err(CauchemarError::IoError(url, e)),
+    }
+    Ok(())
+}
+
+/// The resource behind a handle, `kind` names what was expected in errors.
+fn resource<'vm, T: Any>(vm: &'vm mut CauchemarVM, handle: usize, kind: &str) -> Result<&'vm mut T, CauchemarError> {
+    match vm.handles.get_mut(&handle).and_then(|resource| resource.downcast_mut()) {
+        Some(resource) => Ok(resource),
+        None => Err(CauchemarError::InvalidArgument(format!("handle {} is not {}", handle, kind))),
+    }
+}
+
+fn socket(vm: &mut CauchemarVM, handle: usize) -> Result<&mut TcpStream, CauchemarError> {
+    resource(vm, handle, "an open socket")
+}
+
+fn process(vm: &mut CauchemarVM, handle: usize) -> Result<&mut Child, CauchemarError> {
+    resource(vm, handle, "a running process")
+}
+
+/// Run a command line through the platform shell. Commands killed by a
+/// signal have no exit code, SYSTEM reports -1 for those.
+fn shell(command: &str) -> process::Command {
+    let mut shell = if cfg!(windows) {
+        let mut shell = process::Command::new("cmd");
+        shell.arg("/C");
+        shell
+    } else {
+        let mut shell = process::Command::new("sh");
+        shell.arg("-c");
+        shell
+    };
+    shell.arg(command);
+    shell
+}
+
+/// Strings are UTF-8, so decoders refuse to produce anything else.
+fn decoded_string(bytes: Vec<u8>) -> Result<String, CauchemarError> {
+    match String::from_utf8(bytes) {
+        Ok(s) => Ok(s),
+        Err(_) => Err(CauchemarError::InvalidArgument("decoded bytes are not valid UTF-8".to_string())),
+    }
+}
+
+fn gcd(a: i32, b: i32) -> u32 {
+    let (mut a, mut b) = (a.unsigned_abs(), b.unsigned_abs());
+    while b != 0 {
+        (a, b) = (b, a % b);
+    }
+    a
+}
+
+fn unop<F>(vm: &mut CauchemarVM, f: F) -> Result<(), CauchemarError>
+where
+    F: Fn(i32) -> i32,
+{
+    let n = vm.pop_number()?;
+    vm.push(CauchemarVMValue::Number(f(n)))?;
+    Ok(())
+}
+
+fn predicate<F>(vm: &mut CauchemarVM, f: F) -> Result<(), CauchemarError>
+where
+    F: Fn(i32) -> bool,
+{
+    let n = vm.pop_number()?;
+    vm.push(CauchemarVMValue::Bool(f(n)))?;
+    Ok(())
+}
+
+/// Push a Number written by `f`, see TO-HEX-STR.
+fn number_string<F>(vm: &mut CauchemarVM, f: F) -> Result<(), CauchemarError>
+where
+    F: Fn(i32) -> String,
+{
+    let n = vm.pop_number()?;
+    vm.push(CauchemarVMValue::String(f(n).into()))?;
+    Ok(())
+}
+
+/// Print a Number written by `f`, see PRINT-HEX.
+fn print_number<F>(vm: &mut CauchemarVM, f: F) -> Result<(), CauchemarError>
+where
+    F: Fn(i32) -> String,
+{
+    let n = vm.pop_number()?;
+    vm.print(&format!("{}\n", f(n)))
+}
+
+fn binop<F>(vm: &mut CauchemarVM, f: F) -> Result<(), CauchemarError>
+where
+    F: Fn(i32, i32) -> Option<i32>,
+{
+    let b = vm.pop_number()?;
+    let a = vm.pop_number()?;
+
+    match f(a, b) {
+        Some(n) => vm.push(CauchemarVMValue::Number(n))?,
+        None => return Err(CauchemarError::IntegerOverflow),
+    }
+    Ok(())
+}
+
+/// Arithmetic over Number and BigInt, a Number on either side is promoted
+/// to a BigInt when the other one is.
+pub(crate) fn arithmetic<F, G>(vm: &mut CauchemarVM, f: F, g: G) -> Result<(), CauchemarError>
+where
+    F: Fn(i32, i32) -> Option<i32>,
+    G: Fn(BigInt, BigInt) -> BigInt,
+{
+    let b = vm.pop()?;
+    let a = vm.pop()?;
+
+    let result = match (a, b) {
+        (CauchemarVMValue::Number(a), CauchemarVMValue::Number(b)) => match f(a, b) {
+            Some(n) => CauchemarVMValue::Number(n),
+            None => return Err(CauchemarError::IntegerOverflow),
+        },
+        (CauchemarVMValue::BigInt(a), CauchemarVMValue::BigInt(b)) => CauchemarVMValue::BigInt(g(a, b)),
+        (CauchemarVMValue::Number(a), CauchemarVMValue::BigInt(b)) => CauchemarVMValue::BigInt(g(a.into(), b)),
+        (CauchemarVMValue::BigInt(a), CauchemarVMValue::Number(b)) => CauchemarVMValue::BigInt(g(a, b.into())),
+        (CauchemarVMValue::Number(_) | CauchemarVMValue::BigInt(_), b) => {
+            return Err(CauchemarError::invalid_type("Number", &b))
+        }
+        (a, _) => return Err(CauchemarError::invalid_type("Number", &a)),
+    };
+
+    vm.push(result)?;
+    Ok(())
+}
@@ -0,0 +1,411 @@
+//! Turning source files into a CauchemarProgram, following cauchemar.pest.
+
+use std::{
+    borrow::Cow,
+    collections::{HashMap, HashSet},
+};
+
+use num_bigint::BigInt;
+use pest::Parser;
+
+use crate::{CauchemarAST, CauchemarItem, CauchemarProgram, CauchemarSpan};
+
+#[derive(Parser)]
+#[grammar = "cauchemar.pest"]
+pub(crate) struct CauchemarParser;
+
+/// Decode the escape sequences of a string literal, the grammar only lets
+/// valid ones through.
+fn unescape_string(inner: &str) -> Cow<'_, str> {
+    if !inner.contains('\\') {
+        return Cow::Borrowed(inner);
+    }
+
+    let mut decoded = String::with_capacity(inner.len());
+    let mut chars = inner.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            decoded.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('n') => decoded.push('\n'),
+            Some('t') => decoded.push('\t'),
+            Some('r') => decoded.push('\r'),
+            Some('u') => {
+                let code: String = chars.by_ref().take(4).collect();
+                let code = u32::from_str_radix(&code, 16).unwrap();
+                decoded.push(char::from_u32(code).unwrap());
+            }
+            Some(c) => decoded.push(c),
+            None => unreachable!(),
+        }
+    }
+
+    Cow::Owned(decoded)
+}
+
+/// Lay out a triple-quoted string: a line break right after the opening
+/// quotes and the indentation before the closing ones are dropped, then the
+/// indentation common to every non-blank line is removed.
+fn dedent_string(inner: &str) -> Cow<'_, str> {
+    let inner = inner.strip_prefix('\n').unwrap_or(inner);
+    let inner = match inner.rfind('\n') {
+        Some(end) if inner[end + 1..].trim().is_empty() => &inner[..end + 1],
+        _ => inner,
+    };
+
+    let indent = inner
+        .lines()
+        .filter(|line| !line.trim().is_empty())
+        .map(|line| line.len() - line.trim_start_matches([' ', '\t']).len())
+        .min()
+        .unwrap_or(0);
+    if indent == 0 {
+        return Cow::Borrowed(inner);
+    }
+
+    let mut dedented = String::with_capacity(inner.len());
+    for line in inner.split_inclusive('\n') {
+        // Blank lines may be shorter than the common indentation.
+        let cut = indent.min(line.len() - line.trim_start_matches([' ', '\t']).len());
+        dedented.push_str(&line[cut..]);
+    }
+    Cow::Owned(dedented)
+}
+
+pub(crate) fn parse_cauchemar_file(file: &str) -> Result<CauchemarProgram<'_>, pest::error::Error<Rule>> {
+    parse_cauchemar(file, Rule::program)
+}
+
+/// Parse a snippet given on the command line, any commands before the first
+/// routine header make up an implicit PROGRAM routine.
+pub(crate) fn parse_cauchemar_snippet(code: &str) -> Result<CauchemarProgram<'_>, pest::error::Error<Rule>> {
+    parse_cauchemar(code, Rule::snippet)
+}
+
+fn parse_cauchemar(source: &str, rule: Rule) -> Result<CauchemarProgram<'_>, pest::error::Error<Rule>> {
+    let program = CauchemarParser::parse(rule, source)?.next().unwrap();
+
+    let mut routines = HashMap::new();
+    let mut constants = HashMap::new();
+    let mut macros = HashMap::new();
+    let mut macro_spans = Vec::new();
+    let mut layout = Vec::new();
+    let mut spans = HashMap::new();
+
+    use pest::iterators::Pair;
+
+    fn starts_line(pair: &Pair<Rule>) -> bool {
+        let start = pair.as_span().start_pos();
+        let (_, column) = start.line_col();
+        start.line_of().chars().take(column - 1).all(char::is_whitespace)
+    }
+
+    fn parse_command(pair: Pair<Rule>) -> CauchemarAST {
+        match pair.as_rule() {
+            Rule::COMMENT => CauchemarAST::Comment {
+                own_line: starts_line(&pair),
+                text: pair.as_str(),
+            },
+            Rule::number => CauchemarAST::Number(pair.as_str().parse().unwrap(), pair.as_str()),
+            Rule::char_literal => {
+                let code = match &pair.as_str()[2..] {
+                    "newline" => '\n' as i32,
+                    "space" => ' ' as i32,
+                    "tab" => '\t' as i32,
+                    c => c.chars().next().unwrap() as i32,
+                };
+                CauchemarAST::Number(code, pair.as_str())
+            }
+            Rule::radix_number => CauchemarAST::Number(parse_radix(pair.as_str()).unwrap(), pair.as_str()),
+            Rule::bigint => CauchemarAST::BigInt(pair.as_str().trim_end_matches("BI").parse().unwrap(), pair.as_str()),
+            Rule::radix_bigint => {
+                let (digits, radix) = radix_digits(pair.as_str().trim_end_matches("BI"));
+                CauchemarAST::BigInt(BigInt::parse_bytes(digits.as_bytes(), radix).unwrap(), pair.as_str())
+            }
+            Rule::string => CauchemarAST::String(unescape_string(pair.clone().into_inner().as_str()), pair.as_str()),
+            Rule::long_string => CauchemarAST::String(dedent_string(pair.clone().into_inner().as_str()), pair.as_str()),
+            Rule::identifier => CauchemarAST::Identifier(pair.as_str()),
+            Rule::params => CauchemarAST::Params(pair.into_inner().map(|name| name.as_str()).collect()),
+            Rule::local => CauchemarAST::Param(pair.as_str()),
+            // Anything too large to fit can't be returned anyway.
+            Rule::returns => CauchemarAST::Returns(pair.into_inner().as_str().parse().unwrap_or(usize::MAX)),
+            Rule::return_with => CauchemarAST::ReturnWith,
+            Rule::true_ => CauchemarAST::Bool(true),
+            Rule::false_ => CauchemarAST::Bool(false),
+            Rule::add => CauchemarAST::Add,
+            Rule::sub => CauchemarAST::Sub,
+            Rule::mul => CauchemarAST::Mul,
+            Rule::div => CauchemarAST::Div,
+            Rule::if_block => {
+                let mut then = Vec::new();
+                let mut otherwise = Vec::new();
+                let mut in_otherwise = false;
+
+                for inner in pair.into_inner() {
+                    match inner.as_rule() {
+                        Rule::if_true => then.extend(inner.into_inner().map(parse_command)),
+                        Rule::if_false => {
+                            in_otherwise = true;
+                            otherwise.extend(inner.into_inner().map(parse_command));
+                        }
+                        // Comments around the branches go with the branch before them.
+                        _ if in_otherwise => otherwise.push(parse_command(inner)),
+                        _ => then.push(parse_command(inner)),
+                    }
+                }
+
+                CauchemarAST::If(then, otherwise)
+            }
+            Rule::switch_block => {
+                let mut value = Vec::new();
+                let mut cases: Vec<(Vec<_>, Vec<_>)> = Vec::new();
+                let mut default = Vec::new();
+                let mut in_default = false;
+
+                for inner in pair.into_inner() {
+                    match inner.as_rule() {
+                        Rule::switch_value => value.extend(inner.into_inner().map(parse_command)),
+                        Rule::switch_case => {
+                            let mut case_value = Vec::new();
+                            let mut body = Vec::new();
+                            for part in inner.into_inner() {
+                                match part.as_rule() {
+                                    Rule::case_value => case_value.extend(part.into_inner().map(parse_command)),
+                                    Rule::case_body => body.extend(part.into_inner().map(parse_command)),
+                                    // Comments before DO stay with the value.
+                                    _ if body.is_empty() => case_value.push(parse_command(part)),
+                                    _ => body.push(parse_command(part)),
+                                }
+                            }
+                            cases.push((case_value, body));
+                        }
+                        Rule::switch_default => {
+                            in_default = true;
+                            default.extend(inner.into_inner().map(parse_command));
+                        }
+                        // Comments between the cases go with the one before them.
+                        _ if in_default => default.push(parse_command(inner)),
+                        _ => match cases.last_mut() {
+                            Some((_, body)) => body.push(parse_command(inner)),
+                            None => value.push(parse_command(inner)),
+                        },
+                    }
+                }
+
+                CauchemarAST::Switch(value, cases, default)
+            }
+            Rule::while_block => {
+                let body = pair.into_inner().map(parse_command).collect();
+                CauchemarAST::While(body)
+            }
+            Rule::thunk => {
+                let body = pair.into_inner().map(parse_command).collect();
+                CauchemarAST::Thunk(body)
+            }
+            _ => unreachable!(),
+        }
+    }
+
+    /// Spans of `pair` and the commands inside of it, in the order
+    /// parse_command puts them in.
+    fn command_spans(pair: Pair<Rule>, spans: &mut Vec<CauchemarSpan>) {
+        let grouping = matches!(
+            pair.as_rule(),
+            Rule::if_true | Rule::if_false | Rule::switch_value | Rule::switch_case | Rule::case_value
+                | Rule::case_body | Rule::switch_default
+        );
+        if !grouping {
+            let span = pair.as_span();
+            spans.push(CauchemarSpan { line: pair.line_col().0, start: span.start(), end: span.end() });
+        }
+        if grouping || matches!(pair.as_rule(), Rule::if_block | Rule::switch_block | Rule::while_block) {
+            for inner in pair.into_inner() {
+                command_spans(inner, spans);
+            }
+        }
+    }
+
+    // Done up front so parse_command doesn't have to fail.
+    for pair in program.clone().into_inner().flatten() {
+        let fits = match pair.as_rule() {
+            Rule::number => pair.as_str().parse::<i32>().is_ok(),
+            Rule::radix_number => parse_radix(pair.as_str()).is_some(),
+            _ => true,
+        };
+        if !fits {
+            let message = format!("{} does not fit in a 32-bit integer, use BI for larger values", pair.as_str());
+            return Err(pest::error::Error::new_from_span(
+                pest::error::ErrorVariant::CustomError { message },
+                pair.as_span(),
+            ));
+        }
+    }
+
+    for item in program.clone().into_inner() {
+        let params = match item.as_rule() {
+            Rule::routine => item.clone().into_inner().find(|pair| pair.as_rule() == Rule::params),
+            _ => None,
+        };
+        let mut names = Vec::new();
+        for name in params.into_iter().flat_map(|params| params.into_inner()) {
+            if names.contains(&name.as_str()) {
+                return Err(already_defined(name.as_str(), name.as_span()));
+            }
+            names.push(name.as_str());
+        }
+        check_params(item, &names, false)?;
+    }
+
+    for routine in program.into_inner() {
+        match routine.as_rule() {
+            Rule::routine => {
+                let mut routine_rules = routine.into_inner();
+                let routine_name = routine_rules.next().unwrap().as_str();
+                let mut routine_ast = Vec::new();
+                let mut routine_spans = Vec::new();
+
+                for command in routine_rules {
+                    command_spans(command.clone(), &mut routine_spans);
+                    routine_ast.push(parse_command(command));
+                }
+
+                routines.insert(routine_name, routine_ast);
+                spans.insert(routine_name, routine_spans);
+                layout.push(CauchemarItem::Routine(routine_name));
+            }
+            Rule::definition => {
+                let span = routine.as_span();
+                let mut definition_rules = routine.into_inner();
+                let name = definition_rules.next().unwrap().as_str();
+                let value = parse_command(definition_rules.next().unwrap());
+
+                if macros.contains_key(name) || constants.insert(name, value).is_some() {
+                    return Err(already_defined(name, span));
+                }
+                layout.push(CauchemarItem::Define(name));
+            }
+            Rule::macro_definition => {
+                let span = routine.as_span();
+                let mut macro_rules = routine.into_inner();
+                let name = macro_rules.next().unwrap().as_str();
+                let body: Vec<_> = macro_rules.map(parse_command).collect();
+
+                if constants.contains_key(name) || macros.insert(name, body).is_some() {
+                    return Err(already_defined(name, span));
+                }
+                macro_spans.push((name, span));
+                layout.push(CauchemarItem::Macro(name));
+            }
+            Rule::COMMENT => layout.push(CauchemarItem::Comment {
+                own_line: starts_line(&routine),
+                text: routine.as_str(),
+            }),
+            Rule::snippet_body => {
+                let mut routine_spans = Vec::new();
+                for command in routine.clone().into_inner() {
+                    command_spans(command, &mut routine_spans);
+                }
+                let routine_ast: Vec<_> = routine.into_inner().map(parse_command).collect();
+                if !routine_ast.is_empty() {
+                    routines.insert("PROGRAM", routine_ast);
+                    spans.insert("PROGRAM", routine_spans);
+                    layout.push(CauchemarItem::Routine("PROGRAM"));
+                }
+            }
+            Rule::EOI => (),
+            _ => unreachable!(),
+        }
+    }
+
+    for (name, span) in macro_spans {
+        if expands_to(name, &macros[name], &macros, &mut HashSet::new()) {
+            let message = format!("MACRO {} expands to itself", name);
+            return Err(pest::error::Error::new_from_span(
+                pest::error::ErrorVariant::CustomError { message },
+                span,
+            ));
+        }
+    }
+
+    Ok(CauchemarProgram { routines, constants, macros, layout, spans })
+}
+
+/// Split a `0b`, `0o` or `0x` literal into its signed digits and radix.
+fn radix_digits(literal: &str) -> (String, u32) {
+    let (sign, unsigned) = match literal.strip_prefix('-') {
+        Some(unsigned) => ("-", unsigned),
+        None => ("", literal),
+    };
+    let radix = match &unsigned[..2] {
+        "0b" => 2,
+        "0o" => 8,
+        _ => 16,
+    };
+    (format!("{}{}", sign, &unsigned[2..]), radix)
+}
+
+/// Value of a `0b`, `0o` or `0x` literal, `None` when it overflows.
+fn parse_radix(literal: &str) -> Option<i32> {
+    let (digits, radix) = radix_digits(literal);
+    i32::from_str_radix(&digits, radix).ok()
+}
+
+/// Parameters are locals of the routine's own frame, they can't be used
+/// anywhere else, including thunks which may run from another routine.
+fn check_params(pair: pest::iterators::Pair<Rule>, params: &[&str], in_thunk: bool) -> Result<(), pest::error::Error<Rule>> {
+    for inner in pair.into_inner() {
+        let message = match inner.as_rule() {
+            Rule::params => continue,
+            Rule::thunk => {
+                check_params(inner, params, true)?;
+                continue;
+            }
+            Rule::local if !params.contains(&inner.as_str()) => format!("Unknown parameter {}", inner.as_str()),
+            Rule::local if in_thunk => format!("Parameter {} can't be used inside a thunk", inner.as_str()),
+            _ => {
+                check_params(inner, params, in_thunk)?;
+                continue;
+            }
+        };
+        return Err(pest::error::Error::new_from_span(
+            pest::error::ErrorVariant::CustomError { message },
+            inner.as_span(),
+        ));
+    }
+    Ok(())
+}
+
+fn already_defined(name: &str, span: pest::Span) -> pest::error::Error<Rule> {
+    let message = format!("{} is already defined", name);
+    pest::error::Error::new_from_span(pest::error::ErrorVariant::CustomError { message }, span)
+}
+
+/// Whether expanding `body` ends up expanding the macro `name` again.
+fn expands_to<'a>(
+    name: &str,
+    body: &'a [CauchemarAST<'a>],
+    macros: &'a HashMap<&'a str, Vec<CauchemarAST<'a>>>,
+    seen: &mut HashSet<&'a str>,
+) -> bool {
+    body.iter().any(|command| match command {
+        CauchemarAST::Identifier(s) if *s == name => true,
+        CauchemarAST::Identifier(s) => match macros.get(s) {
+            Some(inner) => seen.insert(s) && expands_to(name, inner, macros, seen),
+            None => false,
+        },
+        CauchemarAST::If(then, otherwise) => {
+            expands_to(name, then, macros, seen) || expands_to(name, otherwise, macros, seen)
+        }
+        CauchemarAST::Switch(value, cases, default) => {
+            expands_to(name, value, macros, seen)
+                || cases.iter().any(|(case_value, body)| {
+                    expands_to(name, case_value, macros, seen) || expands_to(name, body, macros, seen)
+                })
+                || expands_to(name, default, macros, seen)
+        }
+        CauchemarAST::While(body) | CauchemarAST::Thunk(body) => expands_to(name, body, macros, seen),
+        _ => false,
+    })
+}
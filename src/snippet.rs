@@ -0,0 +1,32 @@
+//! Runtime errors along with the line they happened on, the command that
+//! failed underlined as rustc does.
+
+use codespan_reporting::{
+    diagnostic::{Diagnostic, Label},
+    files::SimpleFile,
+    term::{
+        self,
+        termcolor::{ColorChoice, StandardStream},
+        Chars, Config,
+    },
+};
+
+use crate::{style, CauchemarError, CauchemarSpan};
+
+/// Print `error` to stderr with the line of `source` that `span` is on,
+/// `name` being where the source came from.
+pub fn emit(error: &CauchemarError, name: &str, source: &str, span: CauchemarSpan) {
+    let file = SimpleFile::new(name, source);
+    let diagnostic = Diagnostic::error()
+        .with_message(error.to_string())
+        .with_labels(vec![Label::primary((), span.start..span.end)]);
+    let config = Config { chars: Chars::ascii(), ..Config::default() };
+    let color = match style::stderr_colored() {
+        true => ColorChoice::Always,
+        false => ColorChoice::Never,
+    };
+    let stderr = StandardStream::stderr(color);
+    if term::emit(&mut stderr.lock(), &config, &file, &diagnostic).is_err() {
+        eprintln!("{}", style::error(error));
+    }
+}
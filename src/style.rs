@@ -0,0 +1,69 @@
+//! Colors for what the interpreter itself prints, as opposed to the program.
+//! Diagnostics go to stderr and listings to stdout, each stream is colored
+//! only when `init` said so.
+
+use std::{
+    fmt::Display,
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+static STDOUT: AtomicBool = AtomicBool::new(false);
+static STDERR: AtomicBool = AtomicBool::new(false);
+
+const RED: &str = "1;31";
+const CYAN: &str = "36";
+const BOLD: &str = "1";
+const DIM: &str = "2";
+
+pub fn init(stdout: bool, stderr: bool) {
+    STDOUT.store(stdout, Ordering::Relaxed);
+    STDERR.store(stderr, Ordering::Relaxed);
+}
+
+fn paint(stream: &AtomicBool, code: &str, text: impl Display) -> String {
+    match stream.load(Ordering::Relaxed) {
+        true => format!("\x1b[{}m{}\x1b[0m", code, text),
+        false => text.to_string(),
+    }
+}
+
+/// Whether stderr is colored, for what other crates print there.
+pub fn stderr_colored() -> bool {
+    STDERR.load(Ordering::Relaxed)
+}
+
+/// An error message for stderr. The first line is the message itself and is
+/// red, the ` --> file:line:column` lines of parse errors are cyan.
+pub fn error(error: impl Display) -> String {
+    let text = error.to_string();
+    let lines: Vec<String> = text
+        .lines()
+        .enumerate()
+        .map(|(i, line)| {
+            if i == 0 {
+                paint(&STDERR, RED, line)
+            } else if line.trim_start().starts_with("-->") {
+                paint(&STDERR, CYAN, line)
+            } else {
+                line.to_string()
+            }
+        })
+        .collect();
+    lines.join("\n")
+}
+
+/// Where something happened, a frame of a backtrace or an instruction index.
+pub fn position(text: impl Display) -> String {
+    paint(&STDERR, CYAN, text)
+}
+
+/// Details on stderr that are only there for the curious, like the stack
+/// dumps of --trace.
+pub fn dim(text: impl Display) -> String {
+    paint(&STDERR, DIM, text)
+}
+
+/// Routine names and jump labels of listings printed to stdout.
+pub fn label(text: impl Display) -> String {
+    paint(&STDOUT, BOLD, text)
+}
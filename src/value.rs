@@ -0,0 +1,91 @@
+//! Values the VM works with.
+
+use std::{collections::BTreeMap, fmt, sync::Arc};
+
+use num_bigint::BigInt;
+use serde::{Deserialize, Serialize};
+
+use crate::CauchemarVMInstruction;
+
+/// A value on the stack.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum CauchemarVMValue {
+    Number(i32),
+    BigInt(BigInt),
+    /// Only read from files such as TOML, arithmetic is on integers alone.
+    Float(f64),
+    Bool(bool),
+    /// Shared so duplicating a string doesn't copy it.
+    String(Arc<str>),
+    Thunk(Vec<CauchemarVMInstruction>),
+    Array(Vec<CauchemarVMValue>),
+    /// Kept sorted so printing a map always gives the same output.
+    Map(BTreeMap<String, CauchemarVMValue>),
+    /// Refers to a resource held by the VM, such as a socket.
+    Handle(usize),
+}
+
+impl fmt::Display for CauchemarVMValue {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CauchemarVMValue::Number(n) => write!(f, "{}", n),
+            CauchemarVMValue::BigInt(n) => write!(f, "{}", n),
+            CauchemarVMValue::Float(n) => write!(f, "{:?}", n),
+            CauchemarVMValue::Bool(true) => write!(f, "TRUE"),
+            CauchemarVMValue::Bool(false) => write!(f, "FALSE"),
+            CauchemarVMValue::String(s) => write!(f, "{}", s),
+            CauchemarVMValue::Thunk(instructions) => {
+                write!(f, "{{ ")?;
+                for i in instructions {
+                    write!(f, "{}; ", i)?;
+                }
+                write!(f, "}}")
+            }
+            CauchemarVMValue::Array(values) => {
+                write!(f, "[")?;
+                for (i, v) in values.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ", ")?;
+                    }
+                    write!(f, "{}", v.repr())?;
+                }
+                write!(f, "]")
+            }
+            CauchemarVMValue::Map(entries) => {
+                write!(f, "{{")?;
+                for (i, (k, v)) in entries.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ", ")?;
+                    }
+                    write!(f, "{:?}: {}", k, v.repr())?;
+                }
+                write!(f, "}}")
+            }
+            CauchemarVMValue::Handle(handle) => write!(f, "<handle {}>", handle),
+        }
+    }
+}
+
+impl CauchemarVMValue {
+    pub(crate) fn type_name(&self) -> &'static str {
+        match self {
+            CauchemarVMValue::Number(_) => "Number",
+            CauchemarVMValue::BigInt(_) => "BigInt",
+            CauchemarVMValue::Float(_) => "Float",
+            CauchemarVMValue::Bool(_) => "Bool",
+            CauchemarVMValue::String(_) => "String",
+            CauchemarVMValue::Thunk(_) => "Thunk",
+            CauchemarVMValue::Array(_) => "Array",
+            CauchemarVMValue::Map(_) => "Map",
+            CauchemarVMValue::Handle(_) => "Handle",
+        }
+    }
+
+    pub(crate) fn repr(&self) -> String {
+        match self {
+            CauchemarVMValue::String(s) => format!("{:?}", s),
+            CauchemarVMValue::BigInt(n) => format!("{}BI", n),
+            _ => self.to_string(),
+        }
+    }
+}
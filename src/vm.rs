@@ -0,0 +1,1401 @@
+//! The virtual machine running compiled routines and the builder setting
+//! it up.
+
+use std::{
+    any::Any,
+    collections::{BTreeMap, HashMap, HashSet},
+    fmt, fs,
+    io::{self, BufRead, BufReader, IsTerminal, Write},
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        mpsc::{Receiver, Sender},
+        Arc, Condvar, Mutex,
+    },
+    thread::JoinHandle,
+};
+
+use num_bigint::BigInt;
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    arithmetic, check_routines, closest_name, compile_user_routines, register_natives, style, unknown_calls,
+    CauchemarError, CauchemarProgram, CauchemarVMValue, STDERR_NAME, STDOUT_NAME,
+};
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum CauchemarVMInstruction {
+    Push(CauchemarVMValue),
+    Call(Arc<str>),
+    /// Pop a value into a local of the current frame.
+    Store(Arc<str>),
+    /// Push a copy of a local of the current frame.
+    Load(Arc<str>),
+    /// Start keeping track of what the current frame leaves on the stack.
+    Returns(usize),
+    /// Return early with the given number of values.
+    ReturnWith,
+    Jump(usize),
+    JumpIfFalse(usize),
+    Add,
+    Sub,
+    Mul,
+    Div,
+    Return,
+    Nop,
+}
+
+impl fmt::Display for CauchemarVMInstruction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CauchemarVMInstruction::Push(v) => write!(f, "PUSH {}", v),
+            CauchemarVMInstruction::Call(r) => write!(f, "CALL {}", r),
+            CauchemarVMInstruction::Store(name) => write!(f, "STORE {}", name),
+            CauchemarVMInstruction::Load(name) => write!(f, "LOAD {}", name),
+            CauchemarVMInstruction::Returns(count) => write!(f, "RETURNS {}", count),
+            CauchemarVMInstruction::ReturnWith => write!(f, "RETURN_WITH"),
+            CauchemarVMInstruction::Jump(i) => write!(f, "JUMP {}", i),
+            CauchemarVMInstruction::JumpIfFalse(i) => write!(f, "JUMP_IF_FALSE {}", i),
+            CauchemarVMInstruction::Add => write!(f, "ADD"),
+            CauchemarVMInstruction::Sub => write!(f, "SUB"),
+            CauchemarVMInstruction::Mul => write!(f, "MUL"),
+            CauchemarVMInstruction::Div => write!(f, "DIV"),
+            CauchemarVMInstruction::Return => write!(f, "RETURN"),
+            CauchemarVMInstruction::Nop => write!(f, "NOP"),
+        }
+    }
+}
+
+type CauchemarNative = fn(&mut CauchemarVM) -> Result<(), CauchemarError>;
+
+type CauchemarHostFn = dyn FnMut(&mut CauchemarVM) -> Result<(), CauchemarError> + Send;
+
+/// A native registered by the host, which unlike the built-in ones can keep
+/// state between calls. The VMs of THREAD-SPAWN take turns calling it.
+#[derive(Clone)]
+pub(crate) struct CauchemarHostNative(Arc<Mutex<Box<CauchemarHostFn>>>);
+
+impl fmt::Debug for CauchemarHostNative {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "CauchemarHostNative")
+    }
+}
+
+impl CauchemarHostNative {
+    fn call(&self, vm: &mut CauchemarVM) -> Result<(), CauchemarError> {
+        let mut native = self.0.lock().unwrap();
+        native(vm)
+    }
+}
+
+#[derive(Clone)]
+pub(crate) enum CauchemarVMRoutine {
+    Native(CauchemarNative),
+    /// Registered with CauchemarVMBuilder::register_native.
+    Host(CauchemarHostNative),
+    User(Arc<Vec<CauchemarVMInstruction>>),
+}
+
+impl fmt::Debug for CauchemarVMRoutine {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            CauchemarVMRoutine::Native(_) => write!(f, "Native"),
+            CauchemarVMRoutine::Host(_) => write!(f, "Host"),
+            CauchemarVMRoutine::User(instructions) => {
+                write!(f, "User({:?})", instructions)
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) enum CauchemarVMFrame {
+    Routine(Arc<str>),
+    Thunk(Arc<Vec<CauchemarVMInstruction>>),
+}
+
+impl fmt::Display for CauchemarVMFrame {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CauchemarVMFrame::Routine(name) => write!(f, "{}", name),
+            CauchemarVMFrame::Thunk(_) => write!(f, "<thunk>"),
+        }
+    }
+}
+
+/// A compiled program along with everything it needs to run, see
+/// [`crate::VmBuilder`].
+#[derive(Debug)]
+pub struct CauchemarVM {
+    pub(crate) ip: Vec<(CauchemarVMFrame, usize)>,
+    pub(crate) stack: Vec<CauchemarVMValue>,
+    pub(crate) routines: HashMap<Arc<str>, CauchemarVMRoutine>,
+    max_depth: usize,
+    /// Largest allowed stack, zero means unlimited.
+    max_stack: usize,
+    /// Number of instructions executed so far.
+    pub(crate) steps: u64,
+    /// Instruction budget, zero means unlimited.
+    max_steps: u64,
+    /// Comparing values of different types is an error rather than FALSE.
+    strict_equality: bool,
+    /// Routines have to leave as many values as RETURNS says.
+    strict_returns: bool,
+    /// Print every instruction to stderr before running it.
+    trace: bool,
+    regex_cache: HashMap<String, Regex>,
+    /// Files already brought in by LOAD-FILE.
+    pub(crate) loaded_files: HashSet<PathBuf>,
+    /// Arguments given to the program, read by ARG and ARGS.
+    pub(crate) args: Vec<String>,
+    /// Whether natives may touch the file system, see --no-fs.
+    filesystem: bool,
+    /// Whether natives may run other programs, see --no-exec.
+    exec: bool,
+    /// Whether SLEEP actually waits, see --no-sleep.
+    pub(crate) sleep: bool,
+    /// Whether NOW and CLOCK-MS always give 0, see --sandbox.
+    pub(crate) fixed_clock: bool,
+    /// Values set by GLOBAL-SET, untouched by calls and returns.
+    pub(crate) globals: HashMap<String, CauchemarVMValue>,
+    /// RETURNS of the routines being run, innermost last.
+    returns: Vec<CauchemarReturnCheck>,
+    /// Parameters of the routines being run, along with the depth of the
+    /// frame they belong to. Only frames with parameters have an entry.
+    pub(crate) locals: Vec<(usize, HashMap<Arc<str>, CauchemarVMValue>)>,
+    /// Whether the color natives print anything, stdout being a terminal.
+    pub(crate) color: bool,
+    /// Status code of the last HTTP-GET or HTTP-POST.
+    pub(crate) http_status: Option<u16>,
+    /// Resources behind Handle values, such as sockets and threads.
+    pub(crate) handles: HashMap<usize, Box<dyn Any + Send>>,
+    /// Status given to EXIT, the VM stops as soon as it is set.
+    pub(crate) exit: Option<u8>,
+    pub(crate) input: CauchemarInput,
+    pub(crate) output: CauchemarOutput,
+    pub(crate) error_output: CauchemarOutput,
+}
+
+/// Queue behind CHANNEL-NEW, any thread holding it can both send and receive.
+#[derive(Debug)]
+pub(crate) struct CauchemarChannel {
+    pub(crate) sender: Mutex<Sender<CauchemarVMValue>>,
+    pub(crate) receiver: Mutex<Receiver<CauchemarVMValue>>,
+}
+
+/// Lock behind MUTEX-NEW. Unlike std's, it is released by a different call
+/// than the one taking it, so the guard can be held as a handle.
+#[derive(Debug, Default)]
+pub(crate) struct CauchemarMutex {
+    locked: Mutex<bool>,
+    released: Condvar,
+    /// Set when a guard is dropped without MUTEX-UNLOCK, which happens when
+    /// the thread holding it fails.
+    pub(crate) poisoned: AtomicBool,
+}
+
+impl CauchemarMutex {
+    pub(crate) fn lock(self: &Arc<Self>) -> CauchemarMutexGuard {
+        let mut locked = self.locked.lock().unwrap();
+        while *locked {
+            locked = self.released.wait(locked).unwrap();
+        }
+        *locked = true;
+        CauchemarMutexGuard(Some(self.clone()))
+    }
+
+    fn release(&self) {
+        *self.locked.lock().unwrap() = false;
+        self.released.notify_one();
+    }
+}
+
+#[derive(Debug)]
+pub(crate) struct CauchemarMutexGuard(Option<Arc<CauchemarMutex>>);
+
+impl CauchemarMutexGuard {
+    pub(crate) fn unlock(mut self) {
+        if let Some(mutex) = self.0.take() {
+            mutex.release();
+        }
+    }
+}
+
+impl Drop for CauchemarMutexGuard {
+    fn drop(&mut self) {
+        if let Some(mutex) = self.0.take() {
+            mutex.poisoned.store(true, Ordering::Relaxed);
+            mutex.release();
+        }
+    }
+}
+
+/// What THREAD-JOIN gets back, the stack left by the thread.
+pub(crate) type CauchemarThread = JoinHandle<Result<Vec<CauchemarVMValue>, CauchemarError>>;
+
+/// Where READ-LINE and PROMPT read from, standard input unless the embedder
+/// swaps it.
+pub(crate) struct CauchemarInput {
+    pub(crate) reader: Box<dyn BufRead + Send>,
+    /// Whether someone is typing the input, PROMPT only shows its prompt then.
+    pub(crate) terminal: bool,
+}
+
+impl Default for CauchemarInput {
+    fn default() -> Self {
+        CauchemarInput { reader: Box::new(BufReader::new(io::stdin())), terminal: io::stdin().is_terminal() }
+    }
+}
+
+impl fmt::Debug for CauchemarInput {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "CauchemarInput")
+    }
+}
+
+/// Where PRINT and the other natives printing to stdout write, standard
+/// output unless the embedder swaps it. EPRINT has one of its own, standard
+/// error by default.
+pub(crate) struct CauchemarOutput(pub(crate) Box<dyn Write + Send>);
+
+impl Default for CauchemarOutput {
+    fn default() -> Self {
+        CauchemarOutput(Box::new(io::stdout()))
+    }
+}
+
+impl CauchemarOutput {
+    fn stderr() -> CauchemarOutput {
+        CauchemarOutput(Box::new(io::stderr()))
+    }
+}
+
+impl fmt::Debug for CauchemarOutput {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "CauchemarOutput")
+    }
+}
+
+pub(crate) const DEFAULT_MAX_DEPTH: usize = 10_000;
+
+/// Enough for any reasonable program, low enough to stop a runaway loop well
+/// before it eats all the memory.
+pub(crate) const DEFAULT_MAX_STACK: usize = 1_000_000;
+
+/// Which built-in natives a program may call. Natives left out are never
+/// registered, so calling them is an unknown routine like any other.
+#[derive(Debug, Clone, Default)]
+pub enum SandboxPolicy {
+    /// Every one of them.
+    #[default]
+    Permissive,
+    /// Only the natives that can't touch anything outside of the VM
+    /// besides printing.
+    Restricted,
+    /// Only the natives named.
+    Custom(HashSet<&'static str>),
+}
+
+/// Natives that can't touch anything outside of the VM besides printing.
+/// New natives are left out until someone decides they are safe.
+const RESTRICTED_NATIVES: &[&str] = &[
+    "PRINT", "EPRINT", "EPRINT-REPR", "DROP",
+    "PRINT-BINARY", "PRINT-OCTAL", "PRINT-HEX", "PRINT-HEX-UPPER", "TO-BINARY-STR", "TO-OCTAL-STR", "TO-HEX-STR",
+    "COLOR-RED", "COLOR-GREEN", "COLOR-YELLOW", "COLOR-BLUE", "COLOR-RESET",
+    "COLOR-SET-FG", "COLOR-SET-BG", "BOLD", "ITALIC", "UNDERLINE", "DUP", "SWAP", "ROT", "OVER",
+    "ROT-", "OVER2", "UNDER", "TUCK2", "2DUP", "2DROP", "2SWAP", "2OVER", "PICK-SWAP",
+    "EQUALS", "NOT-EQUALS", "NOT", "OR", "AND",
+    "GREATER-THAN", "GREATER-EQUAL", "LESS-THAN", "LESS-EQUAL",
+    "CHECKED-ADD", "CHECKED-SUB", "CHECKED-MUL", "CHECKED-DIV",
+    "GCD", "LCM", "POPCOUNT", "LEADING-ZEROS", "TRAILING-ZEROS", "BIT-LENGTH",
+    "ZERO?", "POSITIVE?", "NEGATIVE?", "EVEN?", "ODD?", "EMPTY?",
+    "BIG-INT", "BIG-INT-TO-NUMBER", "BIG-INT-STR",
+    "BASE64-ENCODE", "BASE64-DECODE", "HEX-ENCODE", "HEX-DECODE", "URL-ENCODE", "URL-DECODE",
+    "SHA256", "SHA512", "MD5", "HMAC-SHA256",
+    "ARRAY", "ARRAY-LENGTH", "ARRAY-GET", "PRINT-TABLE", "MAP-GET",
+    "JSON-PARSE", "JSON-STRINGIFY", "JSON-STRINGIFY-PRETTY", "TOML-PARSE", "TOML-STRINGIFY",
+    "CSV-PARSE", "CSV-PARSE-WITH-HEADERS", "CSV-STRINGIFY",
+    "REGEX-MATCH?", "REGEX-FIND", "REGEX-FIND-ALL", "REGEX-REPLACE",
+    "ASSERT", "ASSERT-EQUALS", "FORCE",
+    "SHOW-IP", "SHOW-STACK", "STACK-FRAME-DEPTH",
+    "ARG-COUNT", "ARG", "ARGS", "SLEEP", "NOW", "CLOCK-MS",
+    "GLOBAL-SET", "GLOBAL-GET", "GLOBAL-EXISTS?", "GLOBAL-DELETE", "GLOBAL-KEYS",
+];
+
+impl SandboxPolicy {
+    fn allows(&self, name: &str) -> bool {
+        match self {
+            SandboxPolicy::Permissive => true,
+            SandboxPolicy::Restricted => RESTRICTED_NATIVES.contains(&name),
+            SandboxPolicy::Custom(allowed) => allowed.contains(name),
+        }
+    }
+}
+
+impl CauchemarVM {
+    /// Push a value, failing once the stack would grow past its limit so a
+    /// native pushing many values is stopped as it does it.
+    pub(crate) fn push(&mut self, value: CauchemarVMValue) -> Result<(), CauchemarError> {
+        if self.max_stack != 0 && self.stack.len() >= self.max_stack {
+            const SHOWN: usize = 5;
+            const WIDTH: usize = 32;
+
+            let top = self.stack.iter().rev().take(SHOWN).map(|value| {
+                let repr = value.repr();
+                match repr.char_indices().nth(WIDTH) {
+                    Some((end, _)) => format!("{}...", &repr[..end]),
+                    None => repr,
+                }
+            });
+            return Err(CauchemarError::StackOverflow {
+                max: self.max_stack,
+                routine: self.ip.last().map(|(frame, _)| frame.to_string()).unwrap_or_default(),
+                top: top.collect(),
+            });
+        }
+        self.stack.push(value);
+        Ok(())
+    }
+
+    pub(crate) fn pop(&mut self) -> Result<CauchemarVMValue, CauchemarError> {
+        let value = self.stack.pop().ok_or(CauchemarError::StackUnderflow)?;
+        self.lower_water();
+        Ok(value)
+    }
+
+    /// Pop the top `count` values, the top one last.
+    pub(crate) fn take(&mut self, count: i32) -> Result<Vec<CauchemarVMValue>, CauchemarError> {
+        if count < 0 || count as usize > self.stack.len() {
+            return Err(CauchemarError::InvalidArgument(format!(
+                "cannot take {} values from a stack of {}",
+                count,
+                self.stack.len(),
+            )));
+        }
+        let values = self.stack.split_off(self.stack.len() - count as usize);
+        self.lower_water();
+        Ok(values)
+    }
+
+    /// Swap the top value with the one `depth` below it, a depth of 1 being
+    /// SWAP and 0 leaving the stack alone.
+    pub(crate) fn swap_top(&mut self, depth: i32) -> Result<(), CauchemarError> {
+        let len = self.stack.len();
+        if depth < 0 || depth as usize >= len {
+            return Err(CauchemarError::InvalidArgument(format!(
+                "cannot swap with the value {} deep in a stack of {}",
+                depth, len,
+            )));
+        }
+        let index = len - 1 - depth as usize;
+        self.stack.swap(index, len - 1);
+        // The same as taking the values down to `index` and putting them back.
+        if let Some(check) = self.returns.last_mut() {
+            check.low_water = check.low_water.min(index);
+        }
+        Ok(())
+    }
+
+    /// Values taken off the stack may have been left by the caller, which
+    /// RETURNS has to know about.
+    fn lower_water(&mut self) {
+        if let Some(check) = self.returns.last_mut() {
+            check.low_water = check.low_water.min(self.stack.len());
+        }
+    }
+
+    /// Pop a Number, failing on anything else.
+    pub fn pop_number(&mut self) -> Result<i32, CauchemarError> {
+        match self.pop()? {
+            CauchemarVMValue::Number(n) => Ok(n),
+            value => Err(CauchemarError::invalid_type("Number", &value)),
+        }
+    }
+
+    pub(crate) fn pop_bigint(&mut self) -> Result<BigInt, CauchemarError> {
+        match self.pop()? {
+            CauchemarVMValue::BigInt(n) => Ok(n),
+            value => Err(CauchemarError::invalid_type("BigInt", &value)),
+        }
+    }
+
+    /// Pop a String, failing on anything else.
+    pub fn pop_string(&mut self) -> Result<String, CauchemarError> {
+        match self.pop()? {
+            CauchemarVMValue::String(s) => Ok(s.to_string()),
+            value => Err(CauchemarError::invalid_type("String", &value)),
+        }
+    }
+
+    pub(crate) fn pop_array(&mut self) -> Result<Vec<CauchemarVMValue>, CauchemarError> {
+        match self.pop()? {
+            CauchemarVMValue::Array(values) => Ok(values),
+            value => Err(CauchemarError::invalid_type("Array", &value)),
+        }
+    }
+
+    pub(crate) fn pop_map(&mut self) -> Result<BTreeMap<String, CauchemarVMValue>, CauchemarError> {
+        match self.pop()? {
+            CauchemarVMValue::Map(entries) => Ok(entries),
+            value => Err(CauchemarError::invalid_type("Map", &value)),
+        }
+    }
+
+    pub(crate) fn pop_handle(&mut self) -> Result<usize, CauchemarError> {
+        match self.pop()? {
+            CauchemarVMValue::Handle(handle) => Ok(handle),
+            value => Err(CauchemarError::invalid_type("Handle", &value)),
+        }
+    }
+
+    /// Pop a Bool, failing on anything else.
+    pub fn pop_bool(&mut self) -> Result<bool, CauchemarError> {
+        match self.pop()? {
+            CauchemarVMValue::Bool(b) => Ok(b),
+            value => Err(CauchemarError::invalid_type("Bool", &value)),
+        }
+    }
+
+    /// Push a Number, as a native returning one would, failing once the
+    /// stack is full.
+    pub fn push_number(&mut self, n: i32) -> Result<(), CauchemarError> {
+        self.push(CauchemarVMValue::Number(n))
+    }
+
+    /// Push a String.
+    pub fn push_string(&mut self, s: &str) -> Result<(), CauchemarError> {
+        self.push(CauchemarVMValue::String(s.into()))
+    }
+
+    /// Push a Bool.
+    pub fn push_bool(&mut self, b: bool) -> Result<(), CauchemarError> {
+        self.push(CauchemarVMValue::Bool(b))
+    }
+
+    /// The values on the stack, bottom first, written the way they would be
+    /// in a source file.
+    pub fn stack(&self) -> Vec<String> {
+        self.stack.iter().map(CauchemarVMValue::repr).collect()
+    }
+
+    /// The frame that called the currently running native.
+    pub(crate) fn caller(&self) -> &CauchemarVMFrame {
+        &self.ip[self.ip.len() - 2].0
+    }
+
+    pub(crate) fn equals(&self, a: &CauchemarVMValue, b: &CauchemarVMValue) -> Result<bool, CauchemarError> {
+        if self.strict_equality && a.type_name() != b.type_name() {
+            return Err(CauchemarError::MismatchedTypes {
+                left: format!("{} {}", a.type_name(), a.repr()),
+                right: format!("{} {}", b.type_name(), b.repr()),
+            });
+        }
+        Ok(a == b)
+    }
+
+    /// Compile a regular expression, reusing the result for later calls.
+    pub(crate) fn regex(&mut self, pattern: String) -> Result<Regex, CauchemarError> {
+        if let Some(regex) = self.regex_cache.get(&pattern) {
+            return Ok(regex.clone());
+        }
+
+        let regex = match Regex::new(&pattern) {
+            Ok(regex) => regex,
+            Err(e) => return Err(CauchemarError::InvalidArgument(format!("invalid regex: {}", e))),
+        };
+        self.regex_cache.insert(pattern, regex.clone());
+        Ok(regex)
+    }
+
+    /// Write `text` where the program's output goes, standard output unless
+    /// the embedder said otherwise.
+    pub(crate) fn print(&mut self, text: &str) -> Result<(), CauchemarError> {
+        match self.output.0.write_all(text.as_bytes()) {
+            Ok(()) => Ok(()),
+            Err(e) => Err(CauchemarError::FileAccess(PathBuf::from(STDOUT_NAME), e)),
+        }
+    }
+
+    /// Write `text` where EPRINT and EPRINT-REPR go, standard error unless
+    /// the embedder said otherwise.
+    pub(crate) fn eprint(&mut self, text: &str) -> Result<(), CauchemarError> {
+        match self.error_output.0.write_all(text.as_bytes()) {
+            Ok(()) => Ok(()),
+            Err(e) => Err(CauchemarError::FileAccess(PathBuf::from(STDERR_NAME), e)),
+        }
+    }
+
+    pub(crate) fn check_fs(&self, routine: &'static str) -> Result<(), CauchemarError> {
+        match self.filesystem {
+            true => Ok(()),
+            false => Err(CauchemarError::Disabled { routine, flag: "--no-fs" }),
+        }
+    }
+
+    pub(crate) fn check_exec(&self, routine: &'static str) -> Result<(), CauchemarError> {
+        match self.exec {
+            true => Ok(()),
+            false => Err(CauchemarError::Disabled { routine, flag: "--no-exec" }),
+        }
+    }
+
+    /// Hold on to a resource for the program, handles are unique across
+    /// threads so they can be passed between them.
+    pub(crate) fn insert_handle(&mut self, resource: Box<dyn Any + Send>) -> usize {
+        static NEXT_HANDLE: AtomicUsize = AtomicUsize::new(1);
+
+        let handle = NEXT_HANDLE.fetch_add(1, Ordering::Relaxed);
+        self.handles.insert(handle, resource);
+        handle
+    }
+
+    /// A VM for THREAD-SPAWN running `routine` on a copy of the stack and
+    /// globals. It shares the routines, channels and mutexes, but none of the
+    /// other resources.
+    pub(crate) fn fork(&self, routine: Arc<str>) -> CauchemarVM {
+        let shared = self
+            .handles
+            .iter()
+            .filter_map(|(handle, resource)| {
+                let resource: Box<dyn Any + Send> = match resource.downcast_ref::<Arc<CauchemarChannel>>() {
+                    Some(channel) => Box::new(channel.clone()),
+                    None => Box::new(resource.downcast_ref::<Arc<CauchemarMutex>>()?.clone()),
+                };
+                Some((*handle, resource))
+            })
+            .collect();
+
+        CauchemarVM {
+            ip: vec![(CauchemarVMFrame::Routine(routine), 0)],
+            stack: self.stack.clone(),
+            routines: self.routines.clone(),
+            max_depth: self.max_depth,
+            max_stack: self.max_stack,
+            steps: 0,
+            max_steps: self.max_steps,
+            strict_equality: self.strict_equality,
+            strict_returns: self.strict_returns,
+            trace: self.trace,
+            regex_cache: HashMap::new(),
+            loaded_files: self.loaded_files.clone(),
+            args: self.args.clone(),
+            filesystem: self.filesystem,
+            exec: self.exec,
+            sleep: self.sleep,
+            fixed_clock: self.fixed_clock,
+            globals: self.globals.clone(),
+            locals: Vec::new(),
+            returns: Vec::new(),
+            color: self.color,
+            http_status: None,
+            handles: shared,
+            exit: None,
+            input: CauchemarInput::default(),
+            output: CauchemarOutput::default(),
+            error_output: CauchemarOutput::stderr(),
+        }
+    }
+
+    pub(crate) fn check_depth(&self) -> Result<(), CauchemarError> {
+        if self.ip.len() >= self.max_depth {
+            return Err(CauchemarError::CallDepthExceeded(self.max_depth));
+        }
+        Ok(())
+    }
+
+    /// Call frames from innermost to outermost, pointing at the instruction
+    /// being executed in each. Natives are left out, the frame calling one
+    /// already points at the call.
+    pub(crate) fn backtrace(&self) -> Vec<String> {
+        self.ip
+            .iter()
+            .rev()
+            .filter(|(frame, _)| match frame {
+                CauchemarVMFrame::Routine(name) => {
+                    !matches!(self.routines.get(name), Some(CauchemarVMRoutine::Native(_) | CauchemarVMRoutine::Host(_)))
+                }
+                CauchemarVMFrame::Thunk(_) => true,
+            })
+            .map(|(frame, ip)| format!("{} [{}]", frame, ip.saturating_sub(1)))
+            .collect()
+    }
+
+    /// Run a single instruction, or a whole native, giving the exit status
+    /// once the entry routine returns or EXIT is called.
+    pub(crate) fn step(&mut self) -> Result<Option<u8>, CauchemarError> {
+        let (frame, ip) = match self.ip.pop() {
+            Some(top) => top,
+            None => return Ok(Some(self.exit.unwrap_or(0))),
+        };
+
+        self.ip.push((frame.clone(), ip + 1));
+
+        self.steps += 1;
+        if self.max_steps != 0 && self.steps > self.max_steps {
+            return Err(CauchemarError::StepLimitExceeded(self.max_steps));
+        }
+        let instructions = match &frame {
+            CauchemarVMFrame::Routine(routine_name) => match self.routines.get(routine_name) {
+                Some(CauchemarVMRoutine::Native(native)) => {
+                    native(self)?;
+                    self.ip.pop();
+                    return Ok(self.exit);
+                }
+                Some(CauchemarVMRoutine::Host(native)) => {
+                    native.clone().call(self)?;
+                    self.ip.pop();
+                    return Ok(self.exit);
+                }
+                Some(CauchemarVMRoutine::User(instructions)) => instructions.as_ref(),
+                None => return Err(CauchemarError::UnknownRoutine(routine_name.to_string())),
+            },
+            CauchemarVMFrame::Thunk(instructions) => instructions.as_ref(),
+        };
+
+        let instruction = &instructions[ip];
+
+        if self.trace {
+            eprintln!("{} {}", style::position(format_args!("[{:>5}]", ip)), instruction);
+            eprintln!("{}", style::dim(format_args!("        STACK: {:?}", self.stack)));
+            eprintln!("{}", style::dim(format_args!("        ROUTINE: {:?}", frame)));
+            eprintln!("{}", style::dim(format_args!("        FRAMES: {:?}", self.ip)));
+        }
+
+        match instruction {
+            CauchemarVMInstruction::Push(n) => self.push(n.clone())?,
+            CauchemarVMInstruction::Add => arithmetic(self, |a, b| a.checked_add(b), |a, b| a + b)?,
+            CauchemarVMInstruction::Sub => arithmetic(self, |a, b| a.checked_sub(b), |a, b| a - b)?,
+            CauchemarVMInstruction::Mul => arithmetic(self, |a, b| a.checked_mul(b), |a, b| a * b)?,
+            CauchemarVMInstruction::Div => {
+                match self.stack.last() {
+                    Some(CauchemarVMValue::Number(0)) => return Err(CauchemarError::DivisionByZero),
+                    Some(CauchemarVMValue::BigInt(n)) if *n == BigInt::from(0) => {
+                        return Err(CauchemarError::DivisionByZero)
+                    }
+                    _ => {}
+                }
+                arithmetic(self, |a, b| a.checked_div(b), |a, b| a / b)?
+            }
+            CauchemarVMInstruction::Jump(pos) => {
+                self.ip.pop();
+                self.ip.push((frame.clone(), *pos));
+            }
+            CauchemarVMInstruction::JumpIfFalse(pos) => {
+                let pos = *pos;
+                if !self.pop_bool()? {
+                    self.ip.pop();
+                    self.ip.push((frame.clone(), pos));
+                }
+            }
+            CauchemarVMInstruction::Call(routine_name) => {
+                self.check_depth()?;
+                self.ip.push((CauchemarVMFrame::Routine(routine_name.clone()), 0));
+            }
+            CauchemarVMInstruction::Store(name) => {
+                let name = name.clone();
+                let value = self.pop()?;
+                let depth = self.ip.len();
+                match self.locals.last_mut() {
+                    Some((frame, locals)) if *frame == depth => {
+                        locals.insert(name, value);
+                    }
+                    _ => self.locals.push((depth, HashMap::from([(name, value)]))),
+                }
+            }
+            CauchemarVMInstruction::Load(name) => {
+                let value = match self.locals.last() {
+                    Some((frame, locals)) if *frame == self.ip.len() => locals.get(name).cloned(),
+                    _ => None,
+                };
+                match value {
+                    Some(value) => self.push(value)?,
+                    None => return Err(CauchemarError::InvalidArgument(format!("no {} parameter", name))),
+                }
+            }
+            CauchemarVMInstruction::Returns(expected) => {
+                let check = CauchemarReturnCheck {
+                    depth: self.ip.len(),
+                    expected: *expected,
+                    low_water: self.stack.len(),
+                };
+                self.returns.push(check);
+            }
+            CauchemarVMInstruction::ReturnWith => {
+                let count = self.pop_number()?;
+                let values = self.take(count)?;
+                // Whatever else the routine left is dropped, when it is known.
+                if let Some(check) = self.returns.last().filter(|check| check.depth == self.ip.len()) {
+                    self.stack.truncate(check.low_water);
+                }
+                self.stack.extend(values);
+                return self.leave_frame();
+            }
+            CauchemarVMInstruction::Nop => {},
+            CauchemarVMInstruction::Return => return self.leave_frame(),
+        }
+
+        Ok(None)
+    }
+
+    /// Step until the program is over, leaving the stack as it is, and give
+    /// the exit status.
+    pub fn run(&mut self) -> Result<u8, CauchemarError> {
+        loop {
+            if let Some(status) = self.step()? {
+                return Ok(status);
+            }
+        }
+    }
+
+    /// Pop the current frame along with its locals, making sure it left
+    /// what RETURNS said it would under --strict-returns.
+    fn leave_frame(&mut self) -> Result<Option<u8>, CauchemarError> {
+        if let Some(check) = self.returns.pop_if(|check| check.depth == self.ip.len()) {
+            if let Some(parent) = self.returns.last_mut() {
+                parent.low_water = parent.low_water.min(check.low_water);
+            }
+            let found = self.stack.len() - check.low_water;
+            if self.strict_returns && found != check.expected {
+                return Err(CauchemarError::ReturnCount {
+                    routine: self.ip.last().map(|(frame, _)| frame.to_string()).unwrap_or_default(),
+                    expected: check.expected,
+                    found,
+                });
+            }
+        }
+
+        self.ip.pop();
+        while self.locals.last().is_some_and(|(frame, _)| *frame > self.ip.len()) {
+            self.locals.pop();
+        }
+        match self.ip.is_empty() {
+            true => Ok(Some(0)),
+            false => Ok(None),
+        }
+    }
+
+    /// The instruction the next step runs, None when it is a native.
+    pub(crate) fn next_instruction(&self) -> Option<&CauchemarVMInstruction> {
+        let (frame, ip) = self.ip.last()?;
+        match frame {
+            CauchemarVMFrame::Routine(routine_name) => match self.routines.get(routine_name)? {
+                CauchemarVMRoutine::User(instructions) => instructions.get(*ip),
+                CauchemarVMRoutine::Native(_) | CauchemarVMRoutine::Host(_) => None,
+            },
+            CauchemarVMFrame::Thunk(instructions) => instructions.get(*ip),
+        }
+    }
+}
+
+/// Everything a running program can change about the VM. Compiled routines
+/// are shared, so taking a snapshot doesn't copy any bytecode. Natives can't
+/// be saved and are taken from the VM being restored instead.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CauchemarVMSnapshot {
+    ip: Vec<(CauchemarVMFrame, usize)>,
+    stack: Vec<CauchemarVMValue>,
+    routines: HashMap<Arc<str>, Arc<Vec<CauchemarVMInstruction>>>,
+    steps: u64,
+    loaded_files: HashSet<PathBuf>,
+    #[serde(default)]
+    globals: HashMap<String, CauchemarVMValue>,
+    #[serde(default)]
+    locals: Vec<(usize, HashMap<Arc<str>, CauchemarVMValue>)>,
+    #[serde(default)]
+    returns: Vec<CauchemarReturnCheck>,
+}
+
+/// What RETURNS said about a frame that is still running. `low_water` is the
+/// smallest the stack has been since, so values taken from the caller count.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct CauchemarReturnCheck {
+    depth: usize,
+    expected: usize,
+    low_water: usize,
+}
+
+impl CauchemarVM {
+    /// The instructions of every user routine, sorted by name, with jump
+    /// targets replaced by labels, as printed by --disassemble.
+    pub fn disassemble(&self) -> String {
+        let mut out = String::new();
+        let mut routines: Vec<_> = self
+            .routines
+            .iter()
+            .filter_map(|(name, routine)| match routine {
+                CauchemarVMRoutine::User(instructions) => Some((name, instructions)),
+                CauchemarVMRoutine::Native(_) | CauchemarVMRoutine::Host(_) => None,
+            })
+            .collect();
+        routines.sort_by_key(|(name, _)| *name);
+
+        for (routine_name, instructions) in routines {
+            let mut targets: Vec<usize> = instructions
+                .iter()
+                .filter_map(|instruction| match instruction {
+                    CauchemarVMInstruction::Jump(target) | CauchemarVMInstruction::JumpIfFalse(target) => Some(*target),
+                    _ => None,
+                })
+                .collect();
+            targets.sort_unstable();
+            targets.dedup();
+            let label = |target: usize| format!(".L{}", targets.binary_search(&target).unwrap() + 1);
+
+            out.push_str(&format!("{}:\n", style::label(routine_name)));
+            for (i, instruction) in instructions.iter().enumerate() {
+                if targets.binary_search(&i).is_ok() {
+                    out.push_str(&format!("{}:\n", style::label(label(i))));
+                }
+                match instruction {
+                    CauchemarVMInstruction::Push(value) => out.push_str(&format!("    PUSH {}\n", value.repr())),
+                    CauchemarVMInstruction::Jump(target) => out.push_str(&format!("    JUMP {}\n", label(*target))),
+                    CauchemarVMInstruction::JumpIfFalse(target) => {
+                        out.push_str(&format!("    JUMP_IF_FALSE {}\n", label(*target)))
+                    }
+                    instruction => out.push_str(&format!("    {}\n", instruction)),
+                }
+            }
+            out.push('\n');
+        }
+        out
+    }
+
+    /// Everything the program can change, to go back to with restore.
+    pub fn snapshot(&self) -> CauchemarVMSnapshot {
+        CauchemarVMSnapshot {
+            ip: self.ip.clone(),
+            stack: self.stack.clone(),
+            routines: self
+                .routines
+                .iter()
+                .filter_map(|(name, routine)| match routine {
+                    CauchemarVMRoutine::User(instructions) => Some((name.clone(), instructions.clone())),
+                    CauchemarVMRoutine::Native(_) | CauchemarVMRoutine::Host(_) => None,
+                })
+                .collect(),
+            steps: self.steps,
+            loaded_files: self.loaded_files.clone(),
+            globals: self.globals.clone(),
+            locals: self.locals.clone(),
+            returns: self.returns.clone(),
+        }
+    }
+
+    /// Roll back to a snapshot, dropping routines defined since by LOAD-FILE
+    /// or EVAL. The limits and other settings are left alone. Handles still
+    /// on the restored stack, globals or parameters are kept open, every
+    /// other one is closed.
+    pub fn restore(&mut self, snapshot: CauchemarVMSnapshot) {
+        self.ip = snapshot.ip;
+        self.stack = snapshot.stack;
+        self.routines.retain(|_, routine| !matches!(routine, CauchemarVMRoutine::User(_)));
+        for (name, instructions) in snapshot.routines {
+            self.routines.insert(name, CauchemarVMRoutine::User(instructions));
+        }
+        self.steps = snapshot.steps;
+        self.loaded_files = snapshot.loaded_files;
+        self.globals = snapshot.globals;
+        self.locals = snapshot.locals;
+        self.returns = snapshot.returns;
+        let mut kept = HashSet::new();
+        let locals = self.locals.iter().flat_map(|(_, locals)| locals.values());
+        for value in self.stack.iter().chain(self.globals.values()).chain(locals) {
+            referenced_handles(value, &mut kept);
+        }
+        let closed: Vec<usize> = self.handles.keys().filter(|handle| !kept.contains(*handle)).copied().collect();
+        // Dropping a guard would poison its mutex, which other threads may
+        // still share, though nothing went wrong while holding it.
+        for handle in closed {
+            if let Ok(guard) = self.handles.remove(&handle).unwrap().downcast::<CauchemarMutexGuard>() {
+                guard.unlock();
+            }
+        }
+        self.exit = None;
+    }
+}
+
+/// Add every handle in `value`, including those inside of arrays, maps and
+/// thunks, to `handles`.
+fn referenced_handles(value: &CauchemarVMValue, handles: &mut HashSet<usize>) {
+    match value {
+        CauchemarVMValue::Handle(handle) => {
+            handles.insert(*handle);
+        }
+        CauchemarVMValue::Array(values) => values.iter().for_each(|value| referenced_handles(value, handles)),
+        CauchemarVMValue::Map(entries) => entries.values().for_each(|value| referenced_handles(value, handles)),
+        CauchemarVMValue::Thunk(instructions) => {
+            for instruction in instructions {
+                if let CauchemarVMInstruction::Push(value) = instruction {
+                    referenced_handles(value, handles);
+                }
+            }
+        }
+        _ => {}
+    }
+}
+
+/// Write a snapshot to `path` as JSON.
+pub fn save_snapshot(path: &Path, snapshot: &CauchemarVMSnapshot) -> Result<(), CauchemarError> {
+    let json = match serde_json::to_string(snapshot) {
+        Ok(json) => json,
+        Err(e) => return Err(CauchemarError::InvalidSnapshot(path.to_path_buf(), e)),
+    };
+    match fs::write(path, json) {
+        Ok(()) => Ok(()),
+        Err(e) => Err(CauchemarError::Io(path.to_path_buf(), e)),
+    }
+}
+
+/// Read a snapshot written by save_snapshot.
+pub fn load_snapshot(path: &Path) -> Result<CauchemarVMSnapshot, CauchemarError> {
+    let json = match fs::read_to_string(path) {
+        Ok(json) => json,
+        Err(e) => return Err(CauchemarError::Io(path.to_path_buf(), e)),
+    };
+    match serde_json::from_str(&json) {
+        Ok(snapshot) => Ok(snapshot),
+        Err(e) => Err(CauchemarError::InvalidSnapshot(path.to_path_buf(), e)),
+    }
+}
+
+/// Configures and compiles a program into a ready to run VM.
+#[derive(Debug)]
+pub(crate) struct CauchemarVMBuilder {
+    entry: Arc<str>,
+    max_stack: usize,
+    max_depth: Option<usize>,
+    max_steps: u64,
+    strict_equality: bool,
+    strict_returns: bool,
+    trace: bool,
+    policy: SandboxPolicy,
+    natives: Vec<(Arc<str>, CauchemarHostNative)>,
+    stack: Vec<CauchemarVMValue>,
+    args: Vec<String>,
+    filesystem: bool,
+    exec: bool,
+    sleep: bool,
+    fixed_clock: bool,
+    color: Option<bool>,
+    input: Option<CauchemarInput>,
+    output: Option<CauchemarOutput>,
+    error_output: Option<CauchemarOutput>,
+}
+
+impl CauchemarVMBuilder {
+    pub(crate) fn new() -> CauchemarVMBuilder {
+        CauchemarVMBuilder {
+            entry: "PROGRAM".into(),
+            max_stack: DEFAULT_MAX_STACK,
+            max_depth: None,
+            max_steps: 0,
+            strict_equality: false,
+            strict_returns: false,
+            trace: false,
+            policy: SandboxPolicy::default(),
+            natives: Vec::new(),
+            stack: Vec::new(),
+            args: Vec::new(),
+            filesystem: true,
+            exec: true,
+            sleep: true,
+            fixed_clock: false,
+            color: None,
+            input: None,
+            output: None,
+            error_output: None,
+        }
+    }
+
+    /// Largest number of values on the stack, DEFAULT_MAX_STACK by default
+    /// and zero for unlimited.
+    pub(crate) fn max_stack_depth(mut self, n: usize) -> CauchemarVMBuilder {
+        self.max_stack = n;
+        self
+    }
+
+    /// Maximum number of nested frames, DEFAULT_MAX_DEPTH by default.
+    pub(crate) fn max_call_depth(mut self, n: usize) -> CauchemarVMBuilder {
+        self.max_depth = Some(n);
+        self
+    }
+
+    /// Maximum number of instructions to execute, zero means unlimited.
+    pub(crate) fn instruction_budget(mut self, n: u64) -> CauchemarVMBuilder {
+        self.max_steps = n;
+        self
+    }
+
+    /// Routine to start running from, PROGRAM by default.
+    pub(crate) fn entry(mut self, name: &str) -> CauchemarVMBuilder {
+        self.entry = name.into();
+        self
+    }
+
+    pub(crate) fn strict_equality(mut self, strict: bool) -> CauchemarVMBuilder {
+        self.strict_equality = strict;
+        self
+    }
+
+    pub(crate) fn strict_returns(mut self, strict: bool) -> CauchemarVMBuilder {
+        self.strict_returns = strict;
+        self
+    }
+
+    pub(crate) fn trace(mut self, trace: bool) -> CauchemarVMBuilder {
+        self.trace = trace;
+        self
+    }
+
+    pub(crate) fn sandbox(mut self, policy: SandboxPolicy) -> CauchemarVMBuilder {
+        self.policy = policy;
+        self
+    }
+
+    /// Add a native of the host's own, which the sandbox policy leaves
+    /// alone. Building fails when a built-in native or a routine of the
+    /// program has the same name.
+    pub(crate) fn register_native<F>(mut self, name: &str, native: F) -> CauchemarVMBuilder
+    where
+        F: FnMut(&mut CauchemarVM) -> Result<(), CauchemarError> + Send + 'static,
+    {
+        self.natives.push((name.into(), CauchemarHostNative(Arc::new(Mutex::new(Box::new(native))))));
+        self
+    }
+
+    /// Values the program starts with, the last one being the top.
+    pub(crate) fn initial_stack(mut self, stack: Vec<CauchemarVMValue>) -> CauchemarVMBuilder {
+        self.stack = stack;
+        self
+    }
+
+    fn routines(&self, program: CauchemarProgram) -> Result<HashMap<Arc<str>, CauchemarVMRoutine>, CauchemarError> {
+        let mut routines = HashMap::new();
+
+        compile_user_routines(&mut routines, program);
+        self.check_natives(&routines, false)?;
+        register_natives(&mut routines);
+        self.check_natives(&routines, true)?;
+
+        routines.retain(|name, routine| matches!(routine, CauchemarVMRoutine::User(_)) || self.policy.allows(name));
+        for (name, native) in &self.natives {
+            routines.insert(name.clone(), CauchemarVMRoutine::Host(native.clone()));
+        }
+
+        Ok(routines)
+    }
+
+    /// Make sure no host native has the name of one of `routines`, the
+    /// built-in natives or the program's own.
+    fn check_natives(
+        &self,
+        routines: &HashMap<Arc<str>, CauchemarVMRoutine>,
+        builtin: bool,
+    ) -> Result<(), CauchemarError> {
+        match self.natives.iter().find(|(name, _)| routines.contains_key(name)) {
+            Some((name, _)) => Err(CauchemarError::NativeCollision { name: name.to_string(), builtin }),
+            None => Ok(()),
+        }
+    }
+
+    /// Compile the program without running it and return every problem
+    /// found, rather than stopping at the first one like build does.
+    pub(crate) fn check(&self, program: CauchemarProgram) -> Vec<CauchemarError> {
+        let mut errors = Vec::new();
+        if let Err(e) = self.check_entry(&program) {
+            errors.push(e);
+        }
+
+        let routines = match self.routines(program) {
+            Ok(routines) => routines,
+            Err(e) => {
+                errors.push(e);
+                return errors;
+            }
+        };
+        let mut names: Vec<_> = routines.keys().collect();
+        names.sort();
+
+        let mut unknown = Vec::new();
+        for name in names {
+            if let CauchemarVMRoutine::User(instructions) = &routines[name] {
+                unknown_calls(&routines, instructions, &mut unknown);
+            }
+        }
+        let mut reported = HashSet::new();
+        for name in unknown {
+            if reported.insert(name.clone()) {
+                errors.push(CauchemarError::UnknownRoutine(name.to_string()));
+            }
+        }
+
+        errors
+    }
+
+    fn check_entry(&self, program: &CauchemarProgram) -> Result<(), CauchemarError> {
+        if program.routines.contains_key(&*self.entry) {
+            return Ok(());
+        }
+
+        let names = program.routines.keys().copied();
+        Err(CauchemarError::MissingRoutine {
+            name: self.entry.to_string(),
+            suggestion: closest_name(&self.entry, names).map(str::to_string),
+        })
+    }
+
+    /// Arguments for ARG, ARG-COUNT and ARGS.
+    pub(crate) fn args(mut self, args: Vec<String>) -> CauchemarVMBuilder {
+        self.args = args;
+        self
+    }
+
+    /// Allow natives to touch the file system, on by default.
+    pub(crate) fn filesystem(mut self, allowed: bool) -> CauchemarVMBuilder {
+        self.filesystem = allowed;
+        self
+    }
+
+    /// Allow natives to run other programs, on by default.
+    pub(crate) fn exec(mut self, allowed: bool) -> CauchemarVMBuilder {
+        self.exec = allowed;
+        self
+    }
+
+    /// Let SLEEP wait, on by default. Otherwise it returns right away so
+    /// tests don't take longer than they need to.
+    pub(crate) fn sleep(mut self, enabled: bool) -> CauchemarVMBuilder {
+        self.sleep = enabled;
+        self
+    }
+
+    /// Make NOW and CLOCK-MS always give 0, so the output doesn't depend on
+    /// when the program runs.
+    pub(crate) fn fixed_clock(mut self, fixed: bool) -> CauchemarVMBuilder {
+        self.fixed_clock = fixed;
+        self
+    }
+
+    /// Force the color natives on or off instead of checking whether stdout
+    /// is a terminal.
+    pub(crate) fn color(mut self, enabled: bool) -> CauchemarVMBuilder {
+        self.color = Some(enabled);
+        self
+    }
+
+    /// Send what PRINT and the like write somewhere other than standard
+    /// output.
+    pub(crate) fn output(mut self, output: Box<dyn Write + Send>) -> CauchemarVMBuilder {
+        self.output = Some(CauchemarOutput(output));
+        self
+    }
+
+    /// Send what EPRINT and EPRINT-REPR write somewhere other than standard
+    /// error.
+    pub(crate) fn error_output(mut self, output: Box<dyn Write + Send>) -> CauchemarVMBuilder {
+        self.error_output = Some(CauchemarOutput(output));
+        self
+    }
+
+    /// Read READ-LINE input from somewhere other than standard input.
+    pub(crate) fn input(mut self, input: Box<dyn BufRead + Send>) -> CauchemarVMBuilder {
+        self.input = Some(CauchemarInput { reader: input, terminal: false });
+        self
+    }
+
+    pub(crate) fn build(self, program: CauchemarProgram) -> Result<CauchemarVM, CauchemarError> {
+        self.check_entry(&program)?;
+        let routines = self.routines(program)?;
+
+        let names: Vec<_> = routines.keys().cloned().collect();
+        check_routines(&routines, &names)?;
+
+        Ok(CauchemarVM {
+            ip: vec![(CauchemarVMFrame::Routine(self.entry), 0)],
+            stack: self.stack,
+            routines,
+            max_depth: self.max_depth.unwrap_or(DEFAULT_MAX_DEPTH),
+            max_stack: self.max_stack,
+            steps: 0,
+            max_steps: self.max_steps,
+            strict_equality: self.strict_equality,
+            strict_returns: self.strict_returns,
+            trace: self.trace,
+            regex_cache: HashMap::new(),
+            loaded_files: HashSet::new(),
+            args: self.args,
+            filesystem: self.filesystem,
+            exec: self.exec,
+            sleep: self.sleep,
+            fixed_clock: self.fixed_clock,
+            globals: HashMap::new(),
+            locals: Vec::new(),
+            returns: Vec::new(),
+            color: self.color.unwrap_or_else(|| io::stdout().is_terminal()),
+            http_status: None,
+            handles: HashMap::new(),
+            exit: None,
+            input: self.input.unwrap_or_default(),
+            output: self.output.unwrap_or_default(),
+            error_output: self.error_output.unwrap_or_else(CauchemarOutput::stderr),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parse_cauchemar_file;
+
+    fn build(source: &str, builder: CauchemarVMBuilder) -> Result<CauchemarVM, CauchemarError> {
+        builder.build(parse_cauchemar_file(source).unwrap())
+    }
+
+    fn restricted() -> CauchemarVMBuilder {
+        CauchemarVMBuilder::new().sandbox(SandboxPolicy::Restricted)
+    }
+
+    #[test]
+    fn restricted_sandbox_leaves_out_read_file() {
+        let result = build("PROGRAM:\n  \"/etc/passwd\" READ-FILE\n", restricted());
+        assert!(matches!(result, Err(CauchemarError::UnknownRoutine(name)) if name == "READ-FILE"));
+    }
+
+    #[test]
+    fn restricted_sandbox_leaves_out_exit() {
+        let result = build("PROGRAM:\n  1 EXIT\n", restricted());
+        assert!(matches!(result, Err(CauchemarError::UnknownRoutine(name)) if name == "EXIT"));
+    }
+
+    #[test]
+    fn restricted_sandbox_leaves_out_eval_and_call() {
+        for native in ["EVAL", "CALL"] {
+            let source = format!("PROGRAM:\n  \"1\" {}\n", native);
+            let result = build(&source, restricted());
+            assert!(matches!(result, Err(CauchemarError::UnknownRoutine(name)) if name == native));
+        }
+    }
+
+    #[test]
+    fn restricted_sandbox_keeps_safe_natives() {
+        let mut vm = build("PROGRAM:\n  2 DUP * 4 ASSERT-EQUALS\n", restricted()).unwrap();
+        vm.run().unwrap();
+    }
+
+    #[test]
+    fn restore_releases_held_mutexes_without_poisoning_them() {
+        let source = "PROGRAM:\n  MUTEX-NEW DUP MUTEX-LOCK DROP\n  1 0 /\n";
+        let mut vm = build(source, CauchemarVMBuilder::new()).unwrap();
+        let initial = vm.snapshot();
+        assert!(matches!(vm.run(), Err(CauchemarError::DivisionByZero)));
+
+        let mutex = vm.handles.values().find_map(|resource| resource.downcast_ref::<Arc<CauchemarMutex>>());
+        let mutex = mutex.unwrap().clone();
+        vm.restore(initial);
+        assert!(vm.handles.is_empty());
+        assert!(!mutex.poisoned.load(Ordering::Relaxed));
+        // Would block forever had the guard not released it.
+        mutex.lock().unlock();
+    }
+
+    #[test]
+    fn restore_keeps_handles_the_restored_stack_refers_to() {
+        let source = "PROGRAM:\n  MUTEX-NEW\n  CHANNEL-NEW DROP\n";
+        let mut vm = build(source, CauchemarVMBuilder::new()).unwrap();
+        while vm.stack.is_empty() {
+            vm.step().unwrap();
+        }
+        let snapshot = vm.snapshot();
+        let CauchemarVMValue::Handle(mutex) = vm.stack[0] else { panic!("MUTEX-NEW pushes a handle") };
+        vm.run().unwrap();
+        assert_eq!(vm.handles.len(), 2);
+
+        vm.restore(snapshot);
+        assert_eq!(vm.stack, vec![CauchemarVMValue::Handle(mutex)]);
+        assert_eq!(vm.handles.keys().collect::<Vec<_>>(), vec![&mutex]);
+        vm.run().unwrap();
+    }
+
+    #[test]
+    fn snapshot_roundtrips_every_value() {
+        let values = vec![
+            CauchemarVMValue::Number(-42),
+            CauchemarVMValue::BigInt(BigInt::from(i64::MAX) * 3),
+            CauchemarVMValue::Float(0.1),
+            CauchemarVMValue::Bool(true),
+            CauchemarVMValue::String("caf\u{e9} \"quoted\"".into()),
+            CauchemarVMValue::Thunk(vec![
+                CauchemarVMInstruction::Push(CauchemarVMValue::Number(1)),
+                CauchemarVMInstruction::Call("DUP".into()),
+                CauchemarVMInstruction::JumpIfFalse(0),
+            ]),
+            CauchemarVMValue::Array(vec![CauchemarVMValue::Number(1), CauchemarVMValue::String("two".into())]),
+            CauchemarVMValue::Map(BTreeMap::from([("key".to_string(), CauchemarVMValue::Bool(false))])),
+            CauchemarVMValue::Handle(7),
+        ];
+        let builder = CauchemarVMBuilder::new().initial_stack(values.clone());
+        let mut vm = build("PROGRAM:\n  1 \"GLOBAL\" GLOBAL-SET\n", builder).unwrap();
+        vm.run().unwrap();
+        let snapshot = vm.snapshot();
+
+        let path = std::env::temp_dir().join(format!("cauchemar-snapshot-{}.json", std::process::id()));
+        save_snapshot(&path, &snapshot).unwrap();
+        let loaded = load_snapshot(&path);
+        fs::remove_file(&path).unwrap();
+        let loaded = loaded.unwrap();
+
+        assert_eq!(loaded.stack, values);
+        assert_eq!(loaded.globals, snapshot.globals);
+        assert_eq!(loaded.steps, snapshot.steps);
+        assert_eq!(loaded.routines, snapshot.routines);
+    }
+
+    #[test]
+    fn toml_parse_reads_nested_tables_arrays_and_floats() {
+        let config = "[server]\\nratio = 0.5\\nports = [8000, 8001]\\n[server.limits]\\nscales = [1.5, 2]\\n";
+        let mut vm = build(&format!("PROGRAM:\n  \"{}\" TOML-PARSE\n", config), CauchemarVMBuilder::new()).unwrap();
+        vm.run().unwrap();
+
+        let limits = BTreeMap::from([(
+            "scales".to_string(),
+            CauchemarVMValue::Array(vec![CauchemarVMValue::Float(1.5), CauchemarVMValue::Number(2)]),
+        )]);
+        let server = BTreeMap::from([
+            ("ratio".to_string(), CauchemarVMValue::Float(0.5)),
+            (
+                "ports".to_string(),
+                CauchemarVMValue::Array(vec![CauchemarVMValue::Number(8000), CauchemarVMValue::Number(8001)]),
+            ),
+            ("limits".to_string(), CauchemarVMValue::Map(limits)),
+        ]);
+        let expected = CauchemarVMValue::Map(BTreeMap::from([("server".to_string(), CauchemarVMValue::Map(server))]));
+        assert_eq!(vm.stack, vec![expected]);
+    }
+
+    #[derive(Clone, Default)]
+    struct Captured(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for Captured {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn eprint_goes_to_the_error_output() {
+        let (output, error_output) = (Captured::default(), Captured::default());
+        let builder = CauchemarVMBuilder::new()
+            .output(Box::new(output.clone()))
+            .error_output(Box::new(error_output.clone()));
+        let mut vm = build("PROGRAM:\n  \"out\" PRINT\n  \"err\" EPRINT\n  \"err\" EPRINT-REPR\n", builder).unwrap();
+        vm.run().unwrap();
+
+        assert_eq!(*output.0.lock().unwrap(), b"out\n");
+        assert_eq!(*error_output.0.lock().unwrap(), b"err\n\"err\"\n");
+    }
+}
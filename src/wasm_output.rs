@@ -0,0 +1,429 @@
+//! WebAssembly backend for --output-wasm.
+//!
+//! Every user routine becomes a function taking and returning nothing, the
+//! Cauchemar stack lives in the exported memory with `sp` pointing past its
+//! top. Numbers and booleans are `i32`s, anything else traps when reached,
+//! as do natives that aren't either imported or written out below.
+
+use std::{borrow::Cow, collections::HashMap, sync::Arc};
+
+use wasm_encoder::{
+    BlockType, CodeSection, ConstExpr, EntityType, ExportKind, ExportSection, Function, FunctionSection,
+    GlobalSection, GlobalType, ImportSection, Instruction, MemArg, MemorySection, MemoryType, Module,
+    TypeSection, ValType,
+};
+
+use crate::{CauchemarVMInstruction, CauchemarVMRoutine, CauchemarVMValue};
+
+/// Natives provided by the host, all taking the value on top of the stack.
+const IMPORTS: [(&str, &str); 2] = [("PRINT", "print"), ("EPRINT", "eprint")];
+
+/// 1MiB, room for a quarter million values.
+const MEMORY_PAGES: u64 = 16;
+
+const EMPTY_TYPE: u32 = 0;
+const PUSH_TYPE: u32 = 1;
+const POP_TYPE: u32 = 2;
+
+const PUSH: u32 = IMPORTS.len() as u32;
+const POP: u32 = PUSH + 1;
+const STACK_POINTER: u32 = 0;
+
+const VALUE: MemArg = MemArg { offset: 0, align: 2, memory_index: 0 };
+
+/// Locals every function has, before the ones of its PARAMS.
+const PC: u32 = 0;
+const A: u32 = 1;
+const B: u32 = 2;
+const C: u32 = 3;
+const D: u32 = 4;
+
+/// Compile the user routines to a WebAssembly module, each exported under
+/// its own name along with the memory.
+pub fn wasm(routines: &HashMap<Arc<str>, CauchemarVMRoutine>) -> Vec<u8> {
+    let mut user: Vec<_> = routines
+        .iter()
+        .filter_map(|(name, routine)| match routine {
+            CauchemarVMRoutine::User(instructions) => Some((name.clone(), instructions)),
+            CauchemarVMRoutine::Native(_) | CauchemarVMRoutine::Host(_) => None,
+        })
+        .collect();
+    user.sort_by(|(a, _), (b, _)| a.cmp(b));
+    let indices: HashMap<Arc<str>, u32> =
+        user.iter().enumerate().map(|(i, (name, _))| (name.clone(), POP + 1 + i as u32)).collect();
+
+    let mut types = TypeSection::new();
+    types.function([], []);
+    types.function([ValType::I32], []);
+    types.function([], [ValType::I32]);
+
+    let mut imports = ImportSection::new();
+    for (_, field) in IMPORTS {
+        imports.import("env", field, EntityType::Function(PUSH_TYPE));
+    }
+
+    let mut functions = FunctionSection::new();
+    functions.function(PUSH_TYPE);
+    functions.function(POP_TYPE);
+    for _ in &user {
+        functions.function(EMPTY_TYPE);
+    }
+
+    let mut memories = MemorySection::new();
+    memories.memory(MemoryType { minimum: MEMORY_PAGES, maximum: None, memory64: false, shared: false });
+
+    let mut globals = GlobalSection::new();
+    globals.global(GlobalType { val_type: ValType::I32, mutable: true }, &ConstExpr::i32_const(0));
+
+    let mut exports = ExportSection::new();
+    exports.export("memory", ExportKind::Memory, 0);
+    for (name, _) in &user {
+        exports.export(name, ExportKind::Func, indices[name]);
+    }
+
+    let mut code = CodeSection::new();
+    code.function(&push());
+    code.function(&pop());
+    for (_, instructions) in &user {
+        code.function(&routine(instructions, &indices));
+    }
+
+    let mut module = Module::new();
+    module
+        .section(&types)
+        .section(&imports)
+        .section(&functions)
+        .section(&memories)
+        .section(&globals)
+        .section(&exports)
+        .section(&code);
+    let bytes = module.finish();
+    if let Err(e) = wasmparser::validate(&bytes) {
+        panic!("emitted an invalid WebAssembly module: {}", e);
+    }
+    bytes
+}
+
+fn push() -> Function {
+    let mut function = Function::new([]);
+    for instruction in [
+        Instruction::GlobalGet(STACK_POINTER),
+        Instruction::LocalGet(0),
+        Instruction::I32Store(VALUE),
+        Instruction::GlobalGet(STACK_POINTER),
+        Instruction::I32Const(4),
+        Instruction::I32Add,
+        Instruction::GlobalSet(STACK_POINTER),
+        Instruction::End,
+    ] {
+        function.instruction(&instruction);
+    }
+    function
+}
+
+fn pop() -> Function {
+    let mut function = Function::new([]);
+    for instruction in [
+        Instruction::GlobalGet(STACK_POINTER),
+        Instruction::I32Const(4),
+        Instruction::I32Sub,
+        Instruction::GlobalSet(STACK_POINTER),
+        Instruction::GlobalGet(STACK_POINTER),
+        Instruction::I32Load(VALUE),
+        Instruction::End,
+    ] {
+        function.instruction(&instruction);
+    }
+    function
+}
+
+/// Jumps can go anywhere in a routine, so the body is a loop around one
+/// block per instruction and a `br_table` on the index of the next one:
+///
+/// ```text
+/// loop
+///   block ... block
+///     local.get $pc
+///     br_table 0 1 ... n-1
+///   end
+///   instruction 0
+///   ...
+///   end
+///   instruction n-1
+/// end
+/// ```
+///
+/// Jumping sets $pc and branches back to the loop.
+fn routine(instructions: &[CauchemarVMInstruction], indices: &HashMap<Arc<str>, u32>) -> Function {
+    let mut locals: HashMap<&str, u32> = HashMap::new();
+    for instruction in instructions {
+        if let CauchemarVMInstruction::Store(name) | CauchemarVMInstruction::Load(name) = instruction {
+            let next = D + 1 + locals.len() as u32;
+            locals.entry(name).or_insert(next);
+        }
+    }
+
+    let mut function = Function::new([(D + 1 + locals.len() as u32, ValType::I32)]);
+    let mut emit = |instructions: &[Instruction]| {
+        for instruction in instructions {
+            function.instruction(instruction);
+        }
+    };
+
+    let count = instructions.len() as u32;
+    emit(&[Instruction::Loop(BlockType::Empty)]);
+    for _ in 0..count {
+        emit(&[Instruction::Block(BlockType::Empty)]);
+    }
+    let targets: Vec<u32> = (0..count).collect();
+    emit(&[Instruction::LocalGet(PC), Instruction::BrTable(Cow::Owned(targets), count - 1), Instruction::End]);
+
+    for (i, instruction) in instructions.iter().enumerate() {
+        // Blocks left between this instruction and the loop.
+        let to_loop = count - 1 - i as u32;
+        match instruction {
+            CauchemarVMInstruction::Push(value) => match value {
+                CauchemarVMValue::Number(n) => emit(&[Instruction::I32Const(*n), Instruction::Call(PUSH)]),
+                CauchemarVMValue::Bool(b) => emit(&[Instruction::I32Const(*b as i32), Instruction::Call(PUSH)]),
+                _ => emit(&[Instruction::Unreachable]),
+            },
+            CauchemarVMInstruction::Call(name) => call(&mut emit, name, indices),
+            CauchemarVMInstruction::Store(name) => {
+                emit(&[Instruction::Call(POP), Instruction::LocalSet(locals[&**name])])
+            }
+            CauchemarVMInstruction::Load(name) => {
+                emit(&[Instruction::LocalGet(locals[&**name]), Instruction::Call(PUSH)])
+            }
+            CauchemarVMInstruction::Jump(target) => emit(&[
+                Instruction::I32Const(*target as i32),
+                Instruction::LocalSet(PC),
+                Instruction::Br(to_loop),
+            ]),
+            CauchemarVMInstruction::JumpIfFalse(target) => emit(&[
+                Instruction::Call(POP),
+                Instruction::I32Eqz,
+                Instruction::If(BlockType::Empty),
+                Instruction::I32Const(*target as i32),
+                Instruction::LocalSet(PC),
+                Instruction::Br(to_loop + 1),
+                Instruction::End,
+            ]),
+            CauchemarVMInstruction::Add => binary(&mut emit, Instruction::I32Add),
+            CauchemarVMInstruction::Sub => binary(&mut emit, Instruction::I32Sub),
+            CauchemarVMInstruction::Mul => binary(&mut emit, Instruction::I32Mul),
+            CauchemarVMInstruction::Div => binary(&mut emit, Instruction::I32DivS),
+            CauchemarVMInstruction::Return => emit(&[Instruction::Return]),
+            // Which values to keep depends on how many RETURNS said, which
+            // isn't known here.
+            CauchemarVMInstruction::ReturnWith => emit(&[Instruction::Unreachable]),
+            CauchemarVMInstruction::Returns(_) | CauchemarVMInstruction::Nop => {}
+        }
+        if i as u32 + 1 < count {
+            emit(&[Instruction::End]);
+        }
+    }
+
+    emit(&[Instruction::End, Instruction::End]);
+    function
+}
+
+/// Pop the two values on top into $a and $b, the one on top being $b, and
+/// push the result of `operation` on them.
+fn binary(emit: &mut impl FnMut(&[Instruction]), operation: Instruction) {
+    emit(&[
+        Instruction::Call(POP),
+        Instruction::LocalSet(B),
+        Instruction::Call(POP),
+        Instruction::LocalGet(B),
+        operation,
+        Instruction::Call(PUSH),
+    ]);
+}
+
+fn call(emit: &mut impl FnMut(&[Instruction]), name: &str, indices: &HashMap<Arc<str>, u32>) {
+    if let Some(index) = indices.get(name) {
+        return emit(&[Instruction::Call(*index)]);
+    }
+    if let Some(import) = IMPORTS.iter().position(|(routine, _)| *routine == name) {
+        return emit(&[Instruction::Call(POP), Instruction::Call(import as u32)]);
+    }
+
+    match name {
+        "DROP" => emit(&[Instruction::Call(POP), Instruction::Drop]),
+        "DUP" => emit(&[
+            Instruction::Call(POP),
+            Instruction::LocalTee(A),
+            Instruction::Call(PUSH),
+            Instruction::LocalGet(A),
+            Instruction::Call(PUSH),
+        ]),
+        "SWAP" => emit(&[
+            Instruction::Call(POP),
+            Instruction::LocalSet(B),
+            Instruction::Call(POP),
+            Instruction::LocalSet(A),
+            Instruction::LocalGet(B),
+            Instruction::Call(PUSH),
+            Instruction::LocalGet(A),
+            Instruction::Call(PUSH),
+        ]),
+        "OVER" => emit(&[
+            Instruction::Call(POP),
+            Instruction::LocalSet(B),
+            Instruction::Call(POP),
+            Instruction::LocalTee(A),
+            Instruction::Call(PUSH),
+            Instruction::LocalGet(B),
+            Instruction::Call(PUSH),
+            Instruction::LocalGet(A),
+            Instruction::Call(PUSH),
+        ]),
+        "ROT" => emit(&[
+            Instruction::Call(POP),
+            Instruction::LocalSet(C),
+            Instruction::Call(POP),
+            Instruction::LocalSet(B),
+            Instruction::Call(POP),
+            Instruction::LocalSet(A),
+            Instruction::LocalGet(B),
+            Instruction::Call(PUSH),
+            Instruction::LocalGet(C),
+            Instruction::Call(PUSH),
+            Instruction::LocalGet(A),
+            Instruction::Call(PUSH),
+        ]),
+        "ROT-" => emit(&[
+            Instruction::Call(POP),
+            Instruction::LocalSet(C),
+            Instruction::Call(POP),
+            Instruction::LocalSet(B),
+            Instruction::Call(POP),
+            Instruction::LocalSet(A),
+            Instruction::LocalGet(C),
+            Instruction::Call(PUSH),
+            Instruction::LocalGet(A),
+            Instruction::Call(PUSH),
+            Instruction::LocalGet(B),
+            Instruction::Call(PUSH),
+        ]),
+        "OVER2" => emit(&[
+            Instruction::Call(POP),
+            Instruction::LocalSet(C),
+            Instruction::Call(POP),
+            Instruction::LocalSet(B),
+            Instruction::Call(POP),
+            Instruction::LocalSet(A),
+            Instruction::LocalGet(A),
+            Instruction::Call(PUSH),
+            Instruction::LocalGet(B),
+            Instruction::Call(PUSH),
+            Instruction::LocalGet(C),
+            Instruction::Call(PUSH),
+            Instruction::LocalGet(A),
+            Instruction::Call(PUSH),
+        ]),
+        "UNDER" => emit(&[
+            Instruction::Call(POP),
+            Instruction::LocalSet(C),
+            Instruction::Call(POP),
+            Instruction::LocalSet(B),
+            Instruction::Call(POP),
+            Instruction::LocalSet(A),
+            Instruction::LocalGet(C),
+            Instruction::Call(PUSH),
+            Instruction::LocalGet(A),
+            Instruction::Call(PUSH),
+            Instruction::LocalGet(B),
+            Instruction::Call(PUSH),
+            Instruction::LocalGet(C),
+            Instruction::Call(PUSH),
+        ]),
+        "TUCK2" => emit(&[
+            Instruction::Call(POP),
+            Instruction::LocalSet(D),
+            Instruction::Call(POP),
+            Instruction::LocalSet(C),
+            Instruction::Call(POP),
+            Instruction::LocalSet(B),
+            Instruction::Call(POP),
+            Instruction::LocalSet(A),
+            Instruction::LocalGet(C),
+            Instruction::Call(PUSH),
+            Instruction::LocalGet(D),
+            Instruction::Call(PUSH),
+            Instruction::LocalGet(A),
+            Instruction::Call(PUSH),
+            Instruction::LocalGet(B),
+            Instruction::Call(PUSH),
+            Instruction::LocalGet(C),
+            Instruction::Call(PUSH),
+            Instruction::LocalGet(D),
+            Instruction::Call(PUSH),
+        ]),
+        "2DUP" => emit(&[
+            Instruction::Call(POP),
+            Instruction::LocalSet(B),
+            Instruction::Call(POP),
+            Instruction::LocalSet(A),
+            Instruction::LocalGet(A),
+            Instruction::Call(PUSH),
+            Instruction::LocalGet(B),
+            Instruction::Call(PUSH),
+            Instruction::LocalGet(A),
+            Instruction::Call(PUSH),
+            Instruction::LocalGet(B),
+            Instruction::Call(PUSH),
+        ]),
+        "2DROP" => emit(&[Instruction::Call(POP), Instruction::Drop, Instruction::Call(POP), Instruction::Drop]),
+        "2SWAP" => emit(&[
+            Instruction::Call(POP),
+            Instruction::LocalSet(D),
+            Instruction::Call(POP),
+            Instruction::LocalSet(C),
+            Instruction::Call(POP),
+            Instruction::LocalSet(B),
+            Instruction::Call(POP),
+            Instruction::LocalSet(A),
+            Instruction::LocalGet(C),
+            Instruction::Call(PUSH),
+            Instruction::LocalGet(D),
+            Instruction::Call(PUSH),
+            Instruction::LocalGet(A),
+            Instruction::Call(PUSH),
+            Instruction::LocalGet(B),
+            Instruction::Call(PUSH),
+        ]),
+        "2OVER" => emit(&[
+            Instruction::Call(POP),
+            Instruction::LocalSet(D),
+            Instruction::Call(POP),
+            Instruction::LocalSet(C),
+            Instruction::Call(POP),
+            Instruction::LocalSet(B),
+            Instruction::Call(POP),
+            Instruction::LocalSet(A),
+            Instruction::LocalGet(A),
+            Instruction::Call(PUSH),
+            Instruction::LocalGet(B),
+            Instruction::Call(PUSH),
+            Instruction::LocalGet(C),
+            Instruction::Call(PUSH),
+            Instruction::LocalGet(D),
+            Instruction::Call(PUSH),
+            Instruction::LocalGet(A),
+            Instruction::Call(PUSH),
+            Instruction::LocalGet(B),
+            Instruction::Call(PUSH),
+        ]),
+        "NOT" => emit(&[Instruction::Call(POP), Instruction::I32Eqz, Instruction::Call(PUSH)]),
+        "AND" => binary(emit, Instruction::I32And),
+        "OR" => binary(emit, Instruction::I32Or),
+        "EQUALS" => binary(emit, Instruction::I32Eq),
+        "NOT-EQUALS" => binary(emit, Instruction::I32Ne),
+        "GREATER-THAN" => binary(emit, Instruction::I32GtS),
+        "GREATER-EQUAL" => binary(emit, Instruction::I32GeS),
+        "LESS-THAN" => binary(emit, Instruction::I32LtS),
+        "LESS-EQUAL" => binary(emit, Instruction::I32LeS),
+        _ => emit(&[Instruction::Unreachable]),
+    }
+}
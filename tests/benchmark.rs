@@ -0,0 +1,36 @@
+//! --benchmark-json reports on stdout, where scripts can read it.
+
+use assert_cmd::Command;
+use serde_json::Value;
+
+#[test]
+fn benchmark_json_goes_to_stdout() {
+    let output = Command::cargo_bin("cauchemar")
+        .unwrap()
+        .args(["--quiet", "--benchmark", "3", "--benchmark-json", "-e", "1 2 +"])
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+    assert!(!String::from_utf8_lossy(&output.stderr).contains("runs"));
+
+    // The last line, the debug feature prints the compiled routines first.
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    let report: Value = serde_json::from_str(stdout.lines().last().unwrap()).unwrap();
+    assert_eq!(report["runs"], 3);
+    assert_eq!(report["instructions"], 4);
+    for field in ["min_ms", "max_ms", "mean_ms", "median_ms", "stddev_ms"] {
+        assert!(report[field].is_f64(), "{} is missing", field);
+    }
+}
+
+#[test]
+fn benchmark_text_stays_on_stderr() {
+    let output = Command::cargo_bin("cauchemar")
+        .unwrap()
+        .args(["--quiet", "--benchmark", "2", "-e", "1 2 +"])
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+    assert!(!String::from_utf8_lossy(&output.stdout).contains("runs"));
+    assert!(String::from_utf8_lossy(&output.stderr).contains("runs:         2\n"));
+}
@@ -0,0 +1,71 @@
+//! --output-c compiled with the system C compiler, skipped without one.
+
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    process,
+};
+
+use assert_cmd::Command;
+
+fn has_cc() -> bool {
+    process::Command::new("cc").arg("--version").output().is_ok_and(|output| output.status.success())
+}
+
+/// Compile `example` to C then to an executable in a directory of its own,
+/// next to the runtime header written along with it.
+fn build(example: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("cauchemar-c-{}-{}", process::id(), example));
+    fs::create_dir_all(&dir).unwrap();
+    let source = dir.join(format!("{}.c", example));
+    Command::cargo_bin("cauchemar")
+        .unwrap()
+        .arg("--output-c")
+        .arg(&source)
+        .arg(Path::new("examples").join(format!("{}.cauchemar", example)))
+        .assert()
+        .success();
+    assert!(dir.join("cauchemar_runtime.h").exists());
+
+    let executable = dir.join(example);
+    let status = process::Command::new("cc").arg("-Wall").arg("-o").arg(&executable).arg(&source).status();
+    assert!(status.unwrap().success(), "{} failed to compile", source.display());
+    executable
+}
+
+#[test]
+fn prints_what_the_example_expects() {
+    if !has_cc() {
+        eprintln!("skipped, no cc");
+        return;
+    }
+
+    let executable = build("fib");
+    let compiled = process::Command::new(&executable).output().unwrap();
+    fs::remove_dir_all(executable.parent().unwrap()).unwrap();
+
+    // The `#=>` comments, what the interpreter prints.
+    let expected: String = fs::read_to_string("examples/fib.cauchemar")
+        .unwrap()
+        .lines()
+        .filter_map(|line| line.strip_prefix("#=> "))
+        .map(|line| format!("{}\n", line))
+        .collect();
+    assert!(compiled.status.success());
+    assert_eq!(String::from_utf8_lossy(&compiled.stdout), expected);
+}
+
+#[test]
+fn exit_status_is_kept() {
+    if !has_cc() {
+        eprintln!("skipped, no cc");
+        return;
+    }
+
+    let executable = build("exit");
+    let compiled = process::Command::new(&executable).output().unwrap();
+    fs::remove_dir_all(executable.parent().unwrap()).unwrap();
+
+    assert_eq!(compiled.status.code(), Some(3));
+    assert_eq!(compiled.stdout, b"Leaving\n");
+}
@@ -0,0 +1,122 @@
+//! `cauchemar dap` spoken to over the Debug Adapter Protocol, as an editor
+//! would.
+use std::{
+    io::{BufRead, BufReader, Read, Write},
+    process::{ChildStdin, Command, Stdio},
+    sync::mpsc,
+    thread,
+    time::Duration,
+};
+
+use serde_json::{json, Value};
+
+fn send(stdin: &mut ChildStdin, seq: i64, command: &str, arguments: Value) {
+    let body = json!({"seq": seq, "type": "request", "command": command, "arguments": arguments}).to_string();
+    write!(stdin, "Content-Length: {}\r\n\r\n{}", body.len(), body).unwrap();
+    stdin.flush().unwrap();
+}
+
+/// Read messages framed by Content-Length headers until the adapter closes
+/// its output.
+fn receive(stdout: impl Read + Send + 'static) -> mpsc::Receiver<Value> {
+    let (sender, receiver) = mpsc::channel();
+    thread::spawn(move || {
+        let mut stdout = BufReader::new(stdout);
+        loop {
+            let mut length = 0;
+            loop {
+                let mut header = String::new();
+                if stdout.read_line(&mut header).unwrap_or(0) == 0 {
+                    return;
+                }
+                let header = header.trim_end();
+                if header.is_empty() {
+                    break;
+                }
+                if let Some(value) = header.strip_prefix("Content-Length: ") {
+                    length = value.parse().unwrap();
+                }
+            }
+            let mut body = vec![0; length];
+            stdout.read_exact(&mut body).unwrap();
+            if sender.send(serde_json::from_slice(&body).unwrap()).is_err() {
+                return;
+            }
+        }
+    });
+    receiver
+}
+
+/// The next message `accept` returns true for, skipping the others.
+fn wait_for(messages: &mpsc::Receiver<Value>, accept: impl Fn(&Value) -> bool) -> Value {
+    loop {
+        let message = messages.recv_timeout(Duration::from_secs(10)).expect("no reply from the adapter");
+        if accept(&message) {
+            return message;
+        }
+    }
+}
+
+/// The successful response to the request numbered `seq`.
+fn response(messages: &mpsc::Receiver<Value>, seq: i64) -> Value {
+    let response = wait_for(messages, |message| message["type"] == "response" && message["request_seq"] == seq);
+    assert_eq!(response["success"], true, "{}", response);
+    response
+}
+
+#[test]
+fn stops_at_a_breakpoint() {
+    let mut adapter = Command::new(env!("CARGO_BIN_EXE_cauchemar"))
+        .arg("dap")
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()
+        .unwrap();
+    let mut stdin = adapter.stdin.take().unwrap();
+    let messages = receive(adapter.stdout.take().unwrap());
+
+    send(&mut stdin, 1, "initialize", json!({"adapterID": "cauchemar"}));
+    let initialized = response(&messages, 1);
+    assert_eq!(initialized["body"]["supportsConfigurationDoneRequest"], true);
+    wait_for(&messages, |message| message["event"] == "initialized");
+
+    send(&mut stdin, 2, "launch", json!({"program": "examples/fib.cauchemar"}));
+    response(&messages, 2);
+    send(&mut stdin, 3, "setBreakpoints", json!({"source": {"path": "examples/fib.cauchemar"}, "breakpoints": [{"line": 4}]}));
+    let breakpoints = response(&messages, 3);
+    assert_eq!(breakpoints["body"]["breakpoints"][0]["line"], 4);
+    send(&mut stdin, 4, "configurationDone", json!({}));
+    response(&messages, 4);
+    let stopped = wait_for(&messages, |message| message["event"] == "stopped");
+    assert_eq!(stopped["body"]["reason"], "breakpoint");
+
+    send(&mut stdin, 5, "stackTrace", json!({"threadId": 1}));
+    let trace = response(&messages, 5);
+    let frames = trace["body"]["stackFrames"].as_array().unwrap();
+    assert_eq!(frames.len(), 1);
+    assert_eq!(frames[0]["name"], "PROGRAM");
+    assert_eq!(frames[0]["line"], 4);
+
+    send(&mut stdin, 6, "scopes", json!({"frameId": frames[0]["id"]}));
+    let scopes = response(&messages, 6);
+    let stack = scopes["body"]["scopes"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .find(|scope| scope["name"] == "Stack")
+        .unwrap()["variablesReference"]
+        .clone();
+    send(&mut stdin, 7, "variables", json!({"variablesReference": stack}));
+    let variables = response(&messages, 7);
+    assert_eq!(variables["body"]["variables"], json!([{"name": "0", "value": "0", "type": "Number", "variablesReference": 0}]));
+
+    // 0 stands for "nothing to expand", it is never a scope.
+    send(&mut stdin, 8, "variables", json!({"variablesReference": 0}));
+    let variables = response(&messages, 8);
+    assert_eq!(variables["body"]["variables"], json!([]));
+
+    send(&mut stdin, 9, "disconnect", json!({}));
+    response(&messages, 9);
+    drop(stdin);
+    assert!(adapter.wait().unwrap().success());
+}
@@ -0,0 +1,62 @@
+//! `cauchemar debug`, driven through its prompt as someone at the terminal
+//! would.
+
+use std::{fs, process};
+
+use assert_cmd::Command;
+
+/// Everything the debugger printed while running `source` with `commands`
+/// typed in, `name` telling the files of each test apart.
+fn debug(name: &str, source: &str, commands: &str) -> String {
+    let file = std::env::temp_dir().join(format!("cauchemar-debug-{}-{}.cauchemar", process::id(), name));
+    fs::write(&file, source).unwrap();
+    let output = Command::cargo_bin("cauchemar")
+        .unwrap()
+        .arg("debug")
+        .arg(&file)
+        .write_stdin(commands)
+        .output()
+        .unwrap();
+    fs::remove_file(&file).unwrap();
+    String::from_utf8(output.stderr).unwrap()
+}
+
+const COUNTDOWN: &str = "PROGRAM:\n  3 COUNTDOWN DROP\n\nCOUNTDOWN:\n  DO 1 - DUP 0 GREATER-THAN WHILE\n";
+
+#[test]
+fn step_runs_one_instruction() {
+    let stderr = debug("step", COUNTDOWN, "s\ns\nstack\n");
+    assert!(stderr.contains("(debug) PROGRAM [1] CALL COUNTDOWN\n"), "{}", stderr);
+    assert!(stderr.contains("(debug) COUNTDOWN [0] PUSH 1\n(debug)     3\n"), "{}", stderr);
+}
+
+#[test]
+fn routine_breakpoint_stops_once_per_call() {
+    let stderr = debug("routine", COUNTDOWN, "b COUNTDOWN\nc\nc\n");
+    assert!(stderr.contains("Breakpoint 1 at COUNTDOWN, hit 1 times\nCOUNTDOWN [0] PUSH 1\n"), "{}", stderr);
+    assert!(!stderr.contains("hit 2 times"), "{}", stderr);
+    assert!(stderr.contains("Program exited with status 0"), "{}", stderr);
+}
+
+#[test]
+fn instruction_breakpoint_stops_every_time_it_is_reached() {
+    let stderr = debug("instruction", COUNTDOWN, "b COUNTDOWN:0\nc\nc\nc\nc\n");
+    assert!(stderr.contains("Breakpoint 1 at COUNTDOWN:0, hit 3 times\n"), "{}", stderr);
+    assert!(stderr.contains("Program exited with status 0"), "{}", stderr);
+}
+
+/// The session described at the top of examples/debug-step.cauchemar.
+#[test]
+fn debug_step_matches_the_documented_example() {
+    let output = Command::cargo_bin("cauchemar")
+        .unwrap()
+        .args(["--debug-step", "examples/debug-step.cauchemar"])
+        .write_stdin("\ns\nc\n")
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+    assert_eq!(
+        String::from_utf8(output.stderr).unwrap(),
+        "PROGRAM [0] PUSH 1\nPROGRAM [1] PUSH two\n    1\nPROGRAM [1] PUSH two\n-- stack: 1 \"two\"\n"
+    );
+}
@@ -0,0 +1,18 @@
+//! --dump-ast --format json, the way external tools see it.
+
+use assert_cmd::Command;
+
+#[test]
+fn json_matches_the_documented_example() {
+    let output = Command::cargo_bin("cauchemar")
+        .unwrap()
+        .args(["--dump-ast", "--format", "json", "examples/library.cauchemar"])
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+
+    let actual: serde_json::Value = serde_json::from_slice(&output.stdout).unwrap();
+    let expected: serde_json::Value =
+        serde_json::from_str(&std::fs::read_to_string("examples/library.ast.json").unwrap()).unwrap();
+    assert_eq!(actual, expected);
+}
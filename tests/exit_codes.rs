@@ -0,0 +1,58 @@
+//! The exit codes documented in the README, one example per code.
+
+use assert_cmd::Command;
+
+fn cauchemar() -> Command {
+    Command::cargo_bin("cauchemar").unwrap()
+}
+
+#[test]
+fn missing_file_exits_with_1() {
+    cauchemar()
+        .arg("examples/does-not-exist.cauchemar")
+        .assert()
+        .code(1);
+}
+
+#[test]
+fn parse_error_exits_with_2() {
+    cauchemar()
+        .arg("examples/invalid-escape.cauchemar")
+        .assert()
+        .code(2);
+}
+
+#[test]
+fn literal_too_large_exits_with_2() {
+    cauchemar().arg("--eval=99999999999 PRINT").assert().code(2);
+    cauchemar().arg("examples/radix-overflow.cauchemar").assert().code(2);
+}
+
+#[test]
+fn missing_program_exits_with_3() {
+    cauchemar()
+        .arg("examples/library.cauchemar")
+        .assert()
+        .code(3);
+}
+
+#[test]
+fn runtime_error_exits_with_4() {
+    cauchemar()
+        .arg("examples/type-error.cauchemar")
+        .assert()
+        .code(4);
+}
+
+#[test]
+fn failed_assertion_exits_with_5() {
+    cauchemar().arg("examples/args.cauchemar").assert().code(5);
+}
+
+#[test]
+fn success_exits_with_0() {
+    cauchemar()
+        .args(["examples/args.cauchemar", "--", "Alice", "3"])
+        .assert()
+        .success();
+}
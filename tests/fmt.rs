@@ -0,0 +1,60 @@
+//! `cauchemar fmt`, reprinting source in the canonical style.
+
+use std::{fs, process};
+
+use assert_cmd::Command;
+
+fn cauchemar_fmt(args: &[&str]) -> Command {
+    let mut command = Command::cargo_bin("cauchemar").unwrap();
+    command.arg("fmt").args(args);
+    command
+}
+
+/// `source` formatted, `name` telling the files of each test apart.
+fn fmt(name: &str, source: &str) -> String {
+    let file = std::env::temp_dir().join(format!("cauchemar-fmt-{}-{}.cauchemar", process::id(), name));
+    fs::write(&file, source).unwrap();
+    let output = cauchemar_fmt(&[file.to_str().unwrap()]).output().unwrap();
+    fs::remove_file(&file).unwrap();
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    String::from_utf8(output.stdout).unwrap()
+}
+
+#[test]
+fn formats_the_documented_example() {
+    let expected = fs::read_to_string("examples/formatted.cauchemar").unwrap();
+    cauchemar_fmt(&["examples/unformatted.cauchemar"]).assert().success().stdout(expected);
+}
+
+#[test]
+fn formatting_twice_changes_nothing() {
+    let formatted = fs::read_to_string("examples/formatted.cauchemar").unwrap();
+    assert_eq!(fmt("idempotent", &formatted), formatted);
+    let example = fs::read_to_string("examples/test.cauchemar").unwrap();
+    let once = fmt("once", &example);
+    assert_eq!(fmt("twice", &once), once);
+}
+
+#[test]
+fn check_exits_with_1_when_unformatted() {
+    cauchemar_fmt(&["--check", "examples/unformatted.cauchemar"]).assert().code(1).stdout("");
+    cauchemar_fmt(&["--check", "examples/formatted.cauchemar"]).assert().success().stdout("");
+}
+
+#[test]
+fn keeps_long_strings_as_written() {
+    let source = "PROGRAM:\n  \"\"\"\n    Hello\n      \"world\"\n    \"\"\" PRINT \"a\\tb\" PRINT\n";
+    assert_eq!(fmt("long-strings", source), source);
+}
+
+#[test]
+fn keeps_character_literals_as_written() {
+    let source = "PROGRAM:\n  #\\A PRINT #\\space PRINT #\\newline PRINT\n";
+    assert_eq!(fmt("characters", source), source);
+}
+
+#[test]
+fn keeps_radix_literals_as_written() {
+    let source = "DEFINE MASK 0xFF\n\nPROGRAM:\n  0b11BI PRINT -0o17 PRINT MASK PRINT\n";
+    assert_eq!(fmt("radix", source), source);
+}
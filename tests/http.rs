@@ -0,0 +1,69 @@
+//! HTTP-GET, HTTP-POST and HTTP-STATUS against a local mock server.
+
+use std::net::TcpListener;
+
+use cauchemar::{CauchemarError, Program, Vm};
+
+fn run(source: &str) -> Result<Vm, CauchemarError> {
+    let mut vm = Program::parse(source)?.compile()?;
+    vm.run()?;
+    Ok(vm)
+}
+
+#[test]
+fn get_pushes_the_body() {
+    let mut server = mockito::Server::new();
+    let mock = server.mock("GET", "/greeting").with_body("hello").create();
+
+    let source = format!("PROGRAM:\n  \"{}/greeting\" HTTP-GET HTTP-STATUS\n", server.url());
+    let vm = run(&source).unwrap();
+    assert_eq!(vm.stack(), ["\"hello\"", "200"]);
+    mock.assert();
+}
+
+#[test]
+fn error_statuses_are_not_errors() {
+    let mut server = mockito::Server::new();
+    let mock = server.mock("GET", "/missing").with_status(404).with_body("not found").create();
+
+    let source = format!("PROGRAM:\n  \"{}/missing\" HTTP-GET HTTP-STATUS\n", server.url());
+    let vm = run(&source).unwrap();
+    assert_eq!(vm.stack(), ["\"not found\"", "404"]);
+    mock.assert();
+}
+
+#[test]
+fn post_pops_the_body_then_the_content_type_then_the_url() {
+    let mut server = mockito::Server::new();
+    let mock = server
+        .mock("POST", "/items")
+        .match_header("content-type", "application/json")
+        .match_body(r#"{"name":"x"}"#)
+        .with_status(201)
+        .with_body("created")
+        .create();
+
+    let source = format!(
+        "PROGRAM:\n  \"{}/items\" \"application/json\" \"{{\\\"name\\\":\\\"x\\\"}}\" HTTP-POST HTTP-STATUS\n",
+        server.url()
+    );
+    let vm = run(&source).unwrap();
+    assert_eq!(vm.stack(), ["\"created\"", "201"]);
+    mock.assert();
+}
+
+#[test]
+fn status_is_false_before_any_request() {
+    let vm = run("PROGRAM:\n  HTTP-STATUS\n").unwrap();
+    assert_eq!(vm.stack(), ["FALSE"]);
+}
+
+#[test]
+fn network_failures_are_io_errors() {
+    // Nothing listens on a port that was just released.
+    let port = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port();
+    let url = format!("http://127.0.0.1:{}/", port);
+
+    let result = run(&format!("PROGRAM:\n  \"{}\" HTTP-GET\n", url));
+    assert!(matches!(result, Err(CauchemarError::IoError(failed, _)) if failed == url));
+}
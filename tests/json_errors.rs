@@ -0,0 +1,60 @@
+//! --json-errors, whose field names tools rely on.
+
+use assert_cmd::Command;
+
+fn json_errors(args: &[&str]) -> String {
+    let output = Command::cargo_bin("cauchemar").unwrap().arg("--json-errors").args(args).output().unwrap();
+    String::from_utf8(output.stderr).unwrap()
+}
+
+/// The keys of a JSON object in the order they were written.
+fn keys(line: &str) -> Vec<String> {
+    let object: serde_json::Map<String, serde_json::Value> = serde_json::from_str(line).unwrap();
+    let mut keys: Vec<(usize, String)> = object
+        .into_iter()
+        .map(|(key, _)| (line.find(&format!("\"{}\":", key)).unwrap(), key))
+        .collect();
+    keys.sort();
+    keys.into_iter().map(|(_, key)| key).collect()
+}
+
+#[test]
+fn runtime_error_matches_the_documented_example() {
+    let expected = std::fs::read_to_string("examples/json-errors.jsonl").unwrap();
+    assert_eq!(json_errors(&["examples/json-errors.cauchemar"]), expected);
+}
+
+#[test]
+fn runtime_error_fields() {
+    let line = json_errors(&["examples/json-errors.cauchemar"]);
+    assert_eq!(keys(&line), ["severity", "code", "message", "file", "line", "column", "trace"]);
+}
+
+#[test]
+fn runtime_error_points_at_the_failing_command() {
+    let line = json_errors(&["-e", "1 2 +\n\"a\" 1 +"]);
+    let diagnostic: serde_json::Value = serde_json::from_str(&line).unwrap();
+    assert_eq!(diagnostic["code"], "invalid-type");
+    assert_eq!(diagnostic["file"], "<eval>");
+    assert_eq!(diagnostic["line"], 2);
+    assert_eq!(diagnostic["column"], 7);
+}
+
+#[test]
+fn runtime_error_points_into_the_file_it_happened_in() {
+    let line = json_errors(&["--load", "examples/library.cauchemar", "-e", "\"a\" SQUARE"]);
+    let diagnostic: serde_json::Value = serde_json::from_str(&line).unwrap();
+    assert_eq!(diagnostic["file"], "examples/library.cauchemar");
+    assert_eq!(diagnostic["line"], 4);
+    assert_eq!(diagnostic["column"], 7);
+}
+
+#[test]
+fn parse_error_fields() {
+    let line = json_errors(&["examples/invalid-escape.cauchemar"]);
+    assert_eq!(keys(&line), ["severity", "code", "message", "file", "line", "column"]);
+
+    let diagnostic: serde_json::Value = serde_json::from_str(&line).unwrap();
+    assert_eq!(diagnostic["code"], "parse");
+    assert_eq!(diagnostic["file"], "examples/invalid-escape.cauchemar");
+}
@@ -0,0 +1,170 @@
+//! cauchemar-lsp spoken to over JSON-RPC, as an editor would.
+#![cfg(feature = "lsp")]
+
+use std::{
+    io::{BufRead, BufReader, Read, Write},
+    process::{Child, ChildStdin, Command, Stdio},
+    sync::mpsc,
+    thread,
+    time::Duration,
+};
+
+use serde_json::{json, Value};
+
+fn send(stdin: &mut ChildStdin, message: Value) {
+    let body = message.to_string();
+    write!(stdin, "Content-Length: {}\r\n\r\n{}", body.len(), body).unwrap();
+    stdin.flush().unwrap();
+}
+
+/// Read messages framed by Content-Length headers until the server closes
+/// its output.
+fn receive(stdout: impl Read + Send + 'static) -> mpsc::Receiver<Value> {
+    let (sender, receiver) = mpsc::channel();
+    thread::spawn(move || {
+        let mut stdout = BufReader::new(stdout);
+        loop {
+            let mut length = 0;
+            loop {
+                let mut header = String::new();
+                if stdout.read_line(&mut header).unwrap_or(0) == 0 {
+                    return;
+                }
+                let header = header.trim_end();
+                if header.is_empty() {
+                    break;
+                }
+                if let Some(value) = header.strip_prefix("Content-Length: ") {
+                    length = value.parse().unwrap();
+                }
+            }
+            let mut body = vec![0; length];
+            stdout.read_exact(&mut body).unwrap();
+            if sender.send(serde_json::from_slice(&body).unwrap()).is_err() {
+                return;
+            }
+        }
+    });
+    receiver
+}
+
+/// The next message `accept` returns true for, skipping the others.
+fn wait_for(messages: &mpsc::Receiver<Value>, accept: impl Fn(&Value) -> bool) -> Value {
+    loop {
+        let message = messages.recv_timeout(Duration::from_secs(10)).expect("no reply from the server");
+        if accept(&message) {
+            return message;
+        }
+    }
+}
+
+/// A server that has been initialized, along with its input and what it
+/// sends back.
+fn start() -> (Child, ChildStdin, mpsc::Receiver<Value>) {
+    let mut server = Command::new(env!("CARGO_BIN_EXE_cauchemar-lsp"))
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()
+        .unwrap();
+    let mut stdin = server.stdin.take().unwrap();
+    let messages = receive(server.stdout.take().unwrap());
+
+    send(&mut stdin, json!({"jsonrpc": "2.0", "id": 1, "method": "initialize", "params": {"capabilities": {}}}));
+    let initialized = wait_for(&messages, |message| message["id"] == 1);
+    assert_eq!(initialized["result"]["serverInfo"]["name"], "cauchemar");
+    assert_eq!(initialized["result"]["capabilities"]["hoverProvider"], true);
+    send(&mut stdin, json!({"jsonrpc": "2.0", "method": "initialized", "params": {}}));
+    (server, stdin, messages)
+}
+
+fn did_open(stdin: &mut ChildStdin, uri: &str, text: &str) {
+    send(
+        stdin,
+        json!({
+            "jsonrpc": "2.0",
+            "method": "textDocument/didOpen",
+            "params": {
+                "textDocument": {
+                    "uri": uri,
+                    "languageId": "cauchemar",
+                    "version": 1,
+                    "text": text
+                }
+            }
+        }),
+    );
+}
+
+fn shut_down(mut server: Child, mut stdin: ChildStdin, messages: &mpsc::Receiver<Value>) {
+    send(&mut stdin, json!({"jsonrpc": "2.0", "id": 99, "method": "shutdown"}));
+    wait_for(messages, |message| message["id"] == 99);
+    send(&mut stdin, json!({"jsonrpc": "2.0", "method": "exit"}));
+    drop(stdin);
+    server.wait().unwrap();
+}
+
+#[test]
+fn did_open_publishes_diagnostics() {
+    let (server, mut stdin, messages) = start();
+
+    let uri = "file:///tmp/broken.cauchemar";
+    did_open(&mut stdin, uri, "PROGRAM:\n  1 MISSING-ROUTINE\n");
+    let published = wait_for(&messages, |message| message["method"] == "textDocument/publishDiagnostics");
+    let params = &published["params"];
+    assert_eq!(params["uri"], uri);
+    assert_eq!(params["version"], 1);
+
+    let diagnostics = params["diagnostics"].as_array().unwrap();
+    assert_eq!(diagnostics.len(), 1);
+    assert_eq!(diagnostics[0]["severity"], 1);
+    assert_eq!(diagnostics[0]["source"], "cauchemar");
+    assert!(diagnostics[0]["message"].as_str().unwrap().contains("MISSING-ROUTINE"));
+    assert_eq!(diagnostics[0]["range"]["start"], json!({"line": 1, "character": 4}));
+
+    shut_down(server, stdin, &messages);
+}
+
+const SQUARES: &str = "/* Multiplies the top value by itself */\nSQUARE: DUP *\n\nPROGRAM:\n  3 SQUARE PRINT\n";
+
+#[test]
+fn hover_shows_the_signature_and_documentation() {
+    let (server, mut stdin, messages) = start();
+    let uri = "file:///tmp/squares.cauchemar";
+    did_open(&mut stdin, uri, SQUARES);
+
+    let position = |line, character| {
+        json!({"textDocument": {"uri": uri}, "position": {"line": line, "character": character}})
+    };
+    send(&mut stdin, json!({"jsonrpc": "2.0", "id": 2, "method": "textDocument/hover", "params": position(4, 5)}));
+    let hover = wait_for(&messages, |message| message["id"] == 2);
+    let contents = &hover["result"]["contents"];
+    assert_eq!(contents["kind"], "markdown");
+    assert_eq!(contents["value"], "```cauchemar\nSQUARE:\n```\n\nMultiplies the top value by itself");
+
+    send(&mut stdin, json!({"jsonrpc": "2.0", "id": 3, "method": "textDocument/hover", "params": position(4, 12)}));
+    let hover = wait_for(&messages, |message| message["id"] == 3);
+    assert_eq!(hover["result"]["contents"]["value"], "```cauchemar\nPRINT\n```\n\nNative routine");
+
+    shut_down(server, stdin, &messages);
+}
+
+#[test]
+fn completion_offers_routines_and_natives() {
+    let (server, mut stdin, messages) = start();
+    let uri = "file:///tmp/squares.cauchemar";
+    did_open(&mut stdin, uri, SQUARES);
+
+    let params = json!({"textDocument": {"uri": uri}, "position": {"line": 4, "character": 2}});
+    send(&mut stdin, json!({"jsonrpc": "2.0", "id": 2, "method": "textDocument/completion", "params": params}));
+    let completion = wait_for(&messages, |message| message["id"] == 2);
+    let items = completion["result"].as_array().unwrap();
+    let item = |label: &str| items.iter().find(|item| item["label"] == label).cloned();
+
+    let square = item("SQUARE").expect("SQUARE is offered");
+    assert_eq!(square["kind"], 3);
+    assert_eq!(square["detail"], "SQUARE:");
+    assert_eq!(item("DUP").expect("natives are offered")["detail"], "native");
+    assert!(item("PROGRAM").is_some());
+
+    shut_down(server, stdin, &messages);
+}
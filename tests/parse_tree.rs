@@ -0,0 +1,33 @@
+//! --print-parse-tree, the pairs the grammar made and the rules they are for.
+
+use assert_cmd::Command;
+
+fn parse_tree(source: &str) -> String {
+    let output = Command::cargo_bin("cauchemar")
+        .unwrap()
+        .args(["--print-parse-tree", "-"])
+        .write_stdin(source)
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+    String::from_utf8(output.stdout).unwrap()
+}
+
+#[test]
+fn matches_the_documented_example() {
+    Command::cargo_bin("cauchemar")
+        .unwrap()
+        .args(["--print-parse-tree", "examples/parse-tree.cauchemar"])
+        .assert()
+        .success()
+        .stdout(std::fs::read_to_string("examples/parse-tree.txt").unwrap());
+}
+
+#[test]
+fn names_the_rule_of_every_pair() {
+    let rules: Vec<String> = parse_tree("PROGRAM: 0xFF \"hi\" PRINT\n")
+        .lines()
+        .map(|line| line.split_whitespace().next().unwrap().to_string())
+        .collect();
+    assert_eq!(rules, ["program", "routine", "identifier", "radix_number", "string", "inner", "identifier", "EOI"]);
+}
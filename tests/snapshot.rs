@@ -0,0 +1,40 @@
+//! Going back to a snapshot after a program fails.
+
+use cauchemar::{CauchemarError, Program, Value, VmBuilder};
+
+#[test]
+fn restore_after_an_error_starts_over() {
+    let source = "PROGRAM:\n  1 2 3\n  \"oops\" NOT\n";
+    let mut vm = VmBuilder::new(Program::parse(source).unwrap()).build().unwrap();
+    let initial = vm.snapshot();
+
+    assert!(matches!(vm.run(), Err(CauchemarError::InvalidType { .. })));
+    assert!(!vm.stack().is_empty());
+
+    vm.restore(initial);
+    assert!(vm.stack().is_empty());
+    assert!(matches!(vm.run(), Err(CauchemarError::InvalidType { .. })));
+}
+
+#[test]
+fn restore_brings_back_the_stack_of_the_snapshot() {
+    let program = Program::parse("PROGRAM:\n  DUP 0 /\n").unwrap();
+    let mut vm = VmBuilder::new(program).initial_stack(vec![Value::Number(7)]).build().unwrap();
+    let initial = vm.snapshot();
+
+    assert!(matches!(vm.run(), Err(CauchemarError::DivisionByZero)));
+    vm.restore(initial);
+    assert_eq!(vm.stack(), ["7"]);
+}
+
+#[test]
+fn restore_forgets_globals_set_since() {
+    let source = "PROGRAM:\n  \"SEEN\" GLOBAL-EXISTS? NOT ASSERT\n  TRUE \"SEEN\" GLOBAL-SET\n  1 0 /\n";
+    let mut vm = VmBuilder::new(Program::parse(source).unwrap()).build().unwrap();
+    let initial = vm.snapshot();
+
+    assert!(matches!(vm.run(), Err(CauchemarError::DivisionByZero)));
+    vm.restore(initial);
+    // Fails the assertion instead if SEEN is still set.
+    assert!(matches!(vm.run(), Err(CauchemarError::DivisionByZero)));
+}
@@ -0,0 +1,53 @@
+//! Runtime errors shown with the line they happened on, compared with the
+//! expected output next to each example.
+
+use std::fs;
+
+use assert_cmd::Command;
+
+fn assert_reported(example: &str) {
+    let output = Command::cargo_bin("cauchemar")
+        .unwrap()
+        .args(["--color", "never", &format!("examples/{}.cauchemar", example)])
+        .output()
+        .unwrap();
+    assert_eq!(output.status.code(), Some(4));
+
+    let expected = fs::read_to_string(format!("examples/{}.txt", example)).unwrap();
+    assert_eq!(String::from_utf8_lossy(&output.stderr), expected);
+}
+
+#[test]
+fn type_error() {
+    assert_reported("type-error");
+}
+
+#[test]
+fn stack_underflow() {
+    assert_reported("stack-underflow");
+}
+
+#[test]
+fn error_in_a_native_points_at_the_call() {
+    let output = Command::cargo_bin("cauchemar")
+        .unwrap()
+        .args(["--color", "never", "-e", "1 2\n\"a\" 3 GCD"])
+        .output()
+        .unwrap();
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("  --> <eval>:2:7\n"), "{}", stderr);
+    assert!(stderr.contains("    at PROGRAM [4]\n"), "{}", stderr);
+    assert!(!stderr.contains("at GCD"), "{}", stderr);
+}
+
+#[test]
+fn error_in_a_loaded_file_points_into_it() {
+    let output = Command::cargo_bin("cauchemar")
+        .unwrap()
+        .args(["--color", "never", "--load", "examples/library.cauchemar", "-e", "\"a\" SQUARE"])
+        .output()
+        .unwrap();
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("  --> examples/library.cauchemar:4:7\n"), "{}", stderr);
+    assert!(stderr.contains("4 |   DUP *\n"), "{}", stderr);
+}
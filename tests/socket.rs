@@ -0,0 +1,69 @@
+//! The SOCKET- natives against a local echo server.
+
+use std::{
+    io::{Read, Write},
+    net::{SocketAddr, TcpListener},
+    thread,
+};
+
+use cauchemar::{CauchemarError, Program, Vm};
+
+fn run(source: &str) -> Result<Vm, CauchemarError> {
+    let mut vm = Program::parse(source)?.compile()?;
+    vm.run()?;
+    Ok(vm)
+}
+
+/// Echo everything back on the first connection until the client closes it.
+fn echo_server() -> SocketAddr {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let address = listener.local_addr().unwrap();
+    thread::spawn(move || {
+        let (mut stream, _) = listener.accept().unwrap();
+        let mut buffer = [0; 4096];
+        loop {
+            match stream.read(&mut buffer) {
+                Ok(0) | Err(_) => break,
+                Ok(read) => stream.write_all(&buffer[..read]).unwrap(),
+            }
+        }
+    });
+    address
+}
+
+#[test]
+fn send_and_receive_through_an_echo_server() {
+    let address = echo_server();
+    let source = format!(
+        "PROGRAM:\n  \"{}\" {} SOCKET-CONNECT\n  DUP \"ping\\n\" SOCKET-SEND\n  DUP SOCKET-RECEIVE\n  SWAP SOCKET-CLOSE\n",
+        address.ip(),
+        address.port()
+    );
+    let vm = run(&source).unwrap();
+    assert_eq!(vm.stack(), ["\"ping\\n\""]);
+}
+
+#[test]
+fn receive_is_empty_once_the_other_end_closes() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let address = listener.local_addr().unwrap();
+    let server = thread::spawn(move || drop(listener.accept().unwrap()));
+
+    let source = format!(
+        "PROGRAM:\n  \"{}\" {} SOCKET-CONNECT\n  SOCKET-RECEIVE\n",
+        address.ip(),
+        address.port()
+    );
+    let vm = run(&source).unwrap();
+    server.join().unwrap();
+    assert_eq!(vm.stack(), ["\"\""]);
+}
+
+#[test]
+fn connecting_to_a_closed_port_is_a_socket_error() {
+    // Nothing listens on a port that was just released.
+    let port = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port();
+
+    let result = run(&format!("PROGRAM:\n  \"127.0.0.1\" {} SOCKET-CONNECT\n", port));
+    assert!(matches!(result, Err(CauchemarError::Socket(socket, _)) if socket == format!("127.0.0.1:{}", port)));
+}
@@ -0,0 +1,28 @@
+//! How `cauchemar test` decides whether a TEST- routine passed.
+
+use assert_cmd::Command;
+
+#[test]
+fn non_zero_exit_is_a_failure() {
+    let output = Command::cargo_bin("cauchemar")
+        .unwrap()
+        .args(["test", "examples/exit-tests.cauchemar"])
+        .output()
+        .unwrap();
+    assert_eq!(output.status.code(), Some(5));
+
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    assert!(stdout.contains("TEST-EXIT-NON-ZERO ... FAILED\n"), "{}", stdout);
+    assert!(stdout.contains("TEST-EXIT-ZERO ... ok\n"), "{}", stdout);
+    assert!(stdout.contains("Exited with status 2\n"), "{}", stdout);
+    assert!(stdout.contains("test result: FAILED. 1 passed; 1 failed\n"), "{}", stdout);
+}
+
+#[test]
+fn passing_tests_exit_with_0() {
+    Command::cargo_bin("cauchemar")
+        .unwrap()
+        .args(["test", "examples/unit-tests.cauchemar"])
+        .assert()
+        .success();
+}
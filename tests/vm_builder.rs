@@ -0,0 +1,112 @@
+//! Each option of VmBuilder, through the public API.
+
+use std::{
+    collections::HashSet,
+    sync::{Arc, Mutex},
+};
+
+use cauchemar::{CauchemarError, Program, SandboxPolicy, Value, Vm, VmBuilder};
+
+fn build(source: &str, options: impl FnOnce(VmBuilder) -> VmBuilder) -> Result<Vm, CauchemarError> {
+    options(VmBuilder::new(Program::parse(source)?)).build()
+}
+
+#[test]
+fn entry() {
+    let source = "PROGRAM:\n  1\n\nOTHER:\n  2\n";
+    let mut vm = build(source, |b| b.entry("OTHER")).unwrap();
+    vm.run().unwrap();
+    assert_eq!(vm.stack(), ["2"]);
+
+    let result = build(source, |b| b.entry("MISSING"));
+    assert!(matches!(result, Err(CauchemarError::MissingRoutine { name, .. }) if name == "MISSING"));
+}
+
+#[test]
+fn max_stack_depth() {
+    let source = "PROGRAM:\n  1 2 3\n";
+    let mut vm = build(source, |b| b.max_stack_depth(2)).unwrap();
+    assert!(matches!(vm.run(), Err(CauchemarError::StackOverflow { max: 2, .. })));
+
+    let mut vm = build(source, |b| b.max_stack_depth(3)).unwrap();
+    vm.run().unwrap();
+    assert_eq!(vm.stack(), ["1", "2", "3"]);
+}
+
+#[test]
+fn max_stack_depth_stops_natives_as_they_push() {
+    let pushed = Arc::new(Mutex::new(0));
+    let counter = pushed.clone();
+    let source = "PROGRAM:\n  FLOOD\n";
+    let mut vm = build(source, |b| {
+        b.max_stack_depth(10).register_native("FLOOD", move |vm| {
+            for n in 0..1000 {
+                vm.push_number(n)?;
+                *counter.lock().unwrap() += 1;
+            }
+            Ok(())
+        })
+    })
+    .unwrap();
+    let result = vm.run();
+    assert!(matches!(result, Err(CauchemarError::StackOverflow { max: 10, routine, .. }) if routine == "FLOOD"));
+    assert_eq!(*pushed.lock().unwrap(), 10);
+}
+
+#[test]
+fn max_call_depth() {
+    let source = "PROGRAM:\n  LOOP\n\nLOOP:\n  LOOP\n";
+    let mut vm = build(source, |b| b.max_call_depth(10)).unwrap();
+    assert!(matches!(vm.run(), Err(CauchemarError::CallDepthExceeded(10))));
+}
+
+#[test]
+fn instruction_budget() {
+    let source = "PROGRAM:\n  1 2 3 4 5\n";
+    let mut vm = build(source, |b| b.instruction_budget(3)).unwrap();
+    assert!(matches!(vm.run(), Err(CauchemarError::StepLimitExceeded(3))));
+
+    let mut vm = build(source, |b| b.instruction_budget(0)).unwrap();
+    vm.run().unwrap();
+}
+
+#[test]
+fn sandbox() {
+    let source = "PROGRAM:\n  \"notes.txt\" READ-FILE\n";
+    let result = build(source, |b| b.sandbox(SandboxPolicy::Restricted));
+    assert!(matches!(result, Err(CauchemarError::UnknownRoutine(name)) if name == "READ-FILE"));
+
+    let source = "PROGRAM:\n  1 DUP\n";
+    let allowed = HashSet::from(["DROP"]);
+    let result = build(source, |b| b.sandbox(SandboxPolicy::Custom(allowed)));
+    assert!(matches!(result, Err(CauchemarError::UnknownRoutine(name)) if name == "DUP"));
+}
+
+#[test]
+fn register_native() {
+    let pixels = Arc::new(Mutex::new(Vec::new()));
+    let recorded = pixels.clone();
+    let source = "PROGRAM:\n  1 2 SET-PIXEL\n  3 4 SET-PIXEL\n";
+    let mut vm = build(source, |b| {
+        b.register_native("SET-PIXEL", move |vm| {
+            let y = vm.pop_number()?;
+            let x = vm.pop_number()?;
+            recorded.lock().unwrap().push((x, y));
+            Ok(())
+        })
+    })
+    .unwrap();
+    vm.run().unwrap();
+    assert_eq!(*pixels.lock().unwrap(), [(1, 2), (3, 4)]);
+
+    let result = build("PROGRAM:\n  1 DUP\n", |b| b.register_native("DUP", |_| Ok(())));
+    assert!(matches!(result, Err(CauchemarError::NativeCollision { builtin: true, .. })));
+}
+
+#[test]
+fn initial_stack() {
+    let stack = vec![Value::Number(2), Value::String("x".into())];
+    let mut vm = build("PROGRAM:\n  DROP 3 *\n", |b| b.initial_stack(stack)).unwrap();
+    vm.run().unwrap();
+    assert_eq!(vm.stack(), ["6"]);
+}
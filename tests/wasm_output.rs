@@ -0,0 +1,49 @@
+//! --output-wasm emits modules any WebAssembly runtime accepts.
+
+use std::{fs, path::PathBuf};
+
+use assert_cmd::Command;
+use wasmparser::{Parser, Payload};
+
+/// The module emitted for `example`, written to a temporary file first.
+fn output_wasm(example: &str) -> Vec<u8> {
+    let output = std::env::temp_dir().join(format!("cauchemar-{}-{}.wasm", std::process::id(), example));
+    Command::cargo_bin("cauchemar")
+        .unwrap()
+        .arg("--output-wasm")
+        .arg(&output)
+        .arg(PathBuf::from("examples").join(format!("{}.cauchemar", example)))
+        .assert()
+        .success();
+    let bytes = fs::read(&output).unwrap();
+    fs::remove_file(&output).unwrap();
+    bytes
+}
+
+fn exports(bytes: &[u8]) -> Vec<String> {
+    let mut names = Vec::new();
+    for payload in Parser::new(0).parse_all(bytes) {
+        if let Payload::ExportSection(reader) = payload.unwrap() {
+            for export in reader {
+                names.push(export.unwrap().name.to_string());
+            }
+        }
+    }
+    names
+}
+
+#[test]
+fn modules_validate() {
+    for example in ["fib", "stack-order", "strict-equality", "over-return", "type-error", "colors"] {
+        let bytes = output_wasm(example);
+        if let Err(e) = wasmparser::validate(&bytes) {
+            panic!("{} compiled to an invalid module: {}", example, e);
+        }
+    }
+}
+
+#[test]
+fn routines_are_exported_along_with_the_memory() {
+    let bytes = output_wasm("fib");
+    assert_eq!(exports(&bytes), ["memory", "FIB", "PROGRAM"]);
+}
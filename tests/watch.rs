@@ -0,0 +1,36 @@
+//! --watch, rerunning the program whenever its files change.
+
+use std::{
+    fs,
+    io::{BufRead, BufReader},
+    iter,
+    process::{self, Command, Stdio},
+    sync::mpsc,
+    thread,
+    time::Duration,
+};
+
+#[test]
+fn runs_a_missing_file_once_it_is_created() {
+    let dir = std::env::temp_dir().join(format!("cauchemar-watch-{}", process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    let mut watcher = Command::new(env!("CARGO_BIN_EXE_cauchemar"))
+        .args(["--watch", "missing.cauchemar"])
+        .current_dir(&dir)
+        .stdout(Stdio::piped())
+        .stderr(Stdio::null())
+        .spawn()
+        .unwrap();
+    let (sender, lines) = mpsc::channel();
+    let stdout = BufReader::new(watcher.stdout.take().unwrap());
+    thread::spawn(move || stdout.lines().map_while(Result::ok).try_for_each(|line| sender.send(line)));
+
+    // The first run fails, the file is only written once it is being watched.
+    thread::sleep(Duration::from_millis(500));
+    fs::write(dir.join("missing.cauchemar"), "PROGRAM:\n  \"created\" PRINT\n").unwrap();
+    let ran = iter::from_fn(|| lines.recv_timeout(Duration::from_secs(10)).ok()).any(|line| line == "created");
+    watcher.kill().unwrap();
+    watcher.wait().unwrap();
+    fs::remove_dir_all(&dir).unwrap();
+    assert!(ran);
+}